@@ -1,7 +1,7 @@
 #![no_std]
 use soroban_sdk::{
     contract, contracterror, contractimpl, contractclient, contracttype, panic_with_error,
-    Address, Env, Symbol, symbol_short, Vec, Map, String,
+    xdr::ToXdr, Address, BytesN, Env, Symbol, symbol_short, Vec, Map, String,
 };
 use sep_41_token::TokenClient;
 
@@ -10,12 +10,170 @@ const PRICE: Symbol = symbol_short!("PRICE");
 const ASSET: Symbol = symbol_short!("ASST");
 const LEVERAGE: Symbol = symbol_short!("LEV");
 const PUSD: Symbol = symbol_short!("PUSD");
-const ORACLES: Symbol = symbol_short!("ORCL");
+const ORACLE_ROUTES: Symbol = symbol_short!("OROUTES");
+const MARKET: Symbol = symbol_short!("MARKET");
 const LONG_POS: Symbol = symbol_short!("LONG");
 const SHORT_POS: Symbol = symbol_short!("SHT");
 const MARGIN_REQ: Symbol = symbol_short!("MREQ");
 const POSITIONS: Symbol = symbol_short!("PTNS");
 const TRADE_HISTORY: Symbol = symbol_short!("HIST");
+const ADMIN: Symbol = symbol_short!("ADMIN");
+const MARGIN_TIERS: Symbol = symbol_short!("MTIER");
+const INSURANCE: Symbol = symbol_short!("INS");
+const LIQ_BID: Symbol = symbol_short!("LBID");
+const PRICE_LEDGER: Symbol = symbol_short!("PLDG");
+const PRICE_TIME: Symbol = symbol_short!("PTIME");
+const ORACLE_REPORTS: Symbol = symbol_short!("OREPORT");
+const ORACLE_QUORUM: Symbol = symbol_short!("QUORUM");
+const MAX_PRICE_AGE: Symbol = symbol_short!("MAXAGE");
+const ORACLE_MARK: Symbol = symbol_short!("OMARK");
+const TWAP: Symbol = symbol_short!("TWAP");
+const MAX_PRICE_JUMP: Symbol = symbol_short!("MAXJUMP");
+const ORACLE_KEYS: Symbol = symbol_short!("OKEYS");
+const ORACLE_STATS: Symbol = symbol_short!("OSTATS");
+const ORACLE_THRESHOLD: Symbol = symbol_short!("OTHRESH");
+const FAILOVER: Symbol = symbol_short!("FAILOVER");
+const FAILOVER_ON: Symbol = symbol_short!("FAILOVRON");
+const SKEW_FEE: Symbol = symbol_short!("SKEWFEE");
+const SKEW_FEE_CHANGED: Symbol = symbol_short!("SKEWFEECH");
+const SPREAD_CHANGED: Symbol = symbol_short!("SPREADCHG");
+const PREMIUM_CHANGED: Symbol = symbol_short!("MARKPRCHG");
+const FRESH_CLOSE: Symbol = symbol_short!("FRESH");
+const SETTLE_PRICE: Symbol = symbol_short!("SETTLE");
+const PENDING_ASSET: Symbol = symbol_short!("PASST");
+const CONTRACT_BOOK: Symbol = symbol_short!("CONTRACTS");
+const PENDING_CONTRACT: Symbol = symbol_short!("PCONTRACT");
+const FEE_ON_NOTIONAL: Symbol = symbol_short!("FEENTL");
+const MARGIN_CALL: Symbol = symbol_short!("MCALL");
+const INCENTIVES: Symbol = symbol_short!("INCENT");
+#[cfg(feature = "rewards")]
+const REBATE_DUE: Symbol = symbol_short!("REBATEDUE");
+const TOKEN_DECIMALS: Symbol = symbol_short!("TDEC");
+const MARK_PREMIUM: Symbol = symbol_short!("MARKPREM");
+const SWAP_ROUTER: Symbol = symbol_short!("ROUTER");
+const PAYOUT_ASSETS: Symbol = symbol_short!("PAYASSETS");
+const SUSPENSION: Symbol = symbol_short!("ACCTSUSP");
+const CONFIDENCE: Symbol = symbol_short!("CONF");
+const ORACLE_CONF: Symbol = symbol_short!("OCONF");
+const RECOMPUTE: Symbol = symbol_short!("RECOMP");
+const SPREAD: Symbol = symbol_short!("SPREAD");
+const SKEW_LEVERAGE: Symbol = symbol_short!("SKEWLEV");
+const SETTLE_FINAL: Symbol = symbol_short!("SETTLEFIN");
+const SETTLE_VOTES: Symbol = symbol_short!("SETTLVOTE");
+const FEE_POOL: Symbol = symbol_short!("FEEPOOL");
+const KEEPER_REBATE: Symbol = symbol_short!("KEEPER");
+const KEEPER_PAID: Symbol = symbol_short!("KEEPPAID");
+const EMA_WEIGHT: Symbol = symbol_short!("EMAWEIGHT");
+const PRICE_BOUNDS: Symbol = symbol_short!("PBOUNDS");
+const YIELD_SOURCE: Symbol = symbol_short!("YIELDSRC");
+const YIELD_DEPLOYED: Symbol = symbol_short!("YIELDDEP");
+const NEXT_ID: Symbol = symbol_short!("NEXTID");
+const TIER_VERSION: Symbol = symbol_short!("MTIERVER");
+const STOP_LOSS: Symbol = symbol_short!("STOPLOSS");
+const TAKE_PROFIT: Symbol = symbol_short!("TAKEPROF");
+const TRIGGERS: Symbol = symbol_short!("TRIGGERS");
+const VOL_GUARD: Symbol = symbol_short!("VOLGUARD");
+const VOL_MODE: Symbol = symbol_short!("VOLMODE");
+const METRICS: Symbol = symbol_short!("METRICS");
+const MAX_LIFETIME: Symbol = symbol_short!("MAXLIFE");
+const POS_LIFETIME: Symbol = symbol_short!("LIFETIME");
+#[cfg(feature = "orders")]
+const ORDERS: Symbol = symbol_short!("ORDERS");
+#[cfg(feature = "orders")]
+const USER_ORDERS: Symbol = symbol_short!("USERORDS");
+const ORDER_ESCROW: Symbol = symbol_short!("ESCROW");
+const POS_OWNER: Symbol = symbol_short!("POSOWNER");
+const POS_APPROVE: Symbol = symbol_short!("POSAPPROV");
+const COLLATERAL_ASSETS: Symbol = symbol_short!("COLLASSET");
+const EXPIRY: Symbol = symbol_short!("EXPIRY");
+const BACKSTOP: Symbol = symbol_short!("BACKSTOP");
+const LIQ_SINCE: Symbol = symbol_short!("LIQSINCE");
+const MIN_VALUE: Symbol = symbol_short!("MINVALUE");
+const EVENT_NONCE: Symbol = symbol_short!("EVNONCE");
+const MAX_NOTIONAL: Symbol = symbol_short!("MAXNOTION");
+const LIQ_GRACE: Symbol = symbol_short!("LIQGRACE");
+const GRACE_LEFT: Symbol = symbol_short!("GRACELEFT");
+const JUMP_SEEN: Symbol = symbol_short!("JUMPSEEN");
+
+// Distance to liquidation (bps of current price) below which a position is flagged
+const WARNING_DISTANCE_BPS: i128 = 2000;
+const DANGER_DISTANCE_BPS: i128 = 500;
+
+// Default fee on the skew-increasing part of a trade (100 = 1%)
+const SKEW_FEE_BPS: i128 = 100;
+
+// Largest skew premium of the mark price over the index (1000 = 10%)
+const MAX_MARK_PREMIUM_BPS: i128 = 1000;
+
+// Largest bid/ask spread around the index (100 = 1%)
+const MAX_SPREAD_BPS: i128 = 100;
+
+// Fee changes are limited to a quarter of the current rate once per day
+const FEE_CHANGE_DIVISOR: i128 = 4;
+const FEE_CHANGE_INTERVAL: u64 = 86400;
+
+// Minimum seconds between margin call events for the same position
+const MARGIN_CALL_COOLDOWN: u64 = 3600;
+
+// Seconds after settlement starts during which oracles may override the
+// settlement price and no position can be settled
+const SETTLEMENT_DISPUTE_WINDOW: u64 = 3600;
+
+// Share of a stop-loss payout paid to the keeper that executes it (10 = 0.1%)
+const STOP_BOUNTY_BPS: i128 = 10;
+
+// Fee on a take-profit payout (20 = 0.2%), split evenly between the keeper
+// that executes it and the insurance fund
+const TAKE_PROFIT_FEE_BPS: i128 = 20;
+
+// Extra penalty on emergency closes, paid to the insurance fund (100 = 1%)
+const EMERGENCY_PENALTY_BPS: i128 = 100;
+
+// One whole unit of the base asset in `place_trade_units` sizes (7 decimals)
+const BASE_UNIT: i128 = 10_000_000;
+
+// Ledgers after the first liquidation bid during which only the best bidder may liquidate
+const LIQ_WINDOW_LEDGERS: u32 = 5;
+// Largest share of the liquidation reward a keeper may tip to insurance,
+// so the winning bidder always keeps half of it
+const MAX_TIP_BPS: u32 = 5000;
+
+// Delay between proposing and applying timelocked admin changes (~1 day of 5s ledgers)
+const TIMELOCK_LEDGERS: u32 = 17280;
+
+// Rebate paid from the incentive budget on the skew-reducing part of a trade (50 = 0.5%)
+#[cfg(feature = "rewards")]
+const INCENTIVE_BPS: i128 = 50;
+
+// The incentive rebate halves every epoch after the budget is first funded (~1 day)
+#[cfg(feature = "rewards")]
+const INCENTIVE_EPOCH_LEDGERS: u32 = 17280;
+
+// Ledgers a position must stay open after its last balancing trade before
+// the rebate is paid at close (~1 day)
+#[cfg(feature = "rewards")]
+const INCENTIVE_HOLD_LEDGERS: u32 = 17280;
+
+// Seconds an oracle report counts towards the median price
+const REPORT_WINDOW: u64 = 300;
+
+// Length in seconds of an oracle scoring epoch (one day)
+const ORACLE_EPOCH: u64 = 86400;
+
+// Cumulative price checkpoints kept for `get_twap`
+const TWAP_OBSERVATIONS: u32 = 32;
+
+// Most decimals accepted for oracle prices or the pUSD token
+const MAX_DECIMALS: u32 = 18;
+
+// Positions summed per `recompute_totals` call
+const RECOMPUTE_PAGE_SIZE: u32 = 50;
+
+// Closed trades kept per user on-chain; older ones are pruned to an ARCHIVE event
+const MAX_USER_HISTORY: u32 = 50;
+
+// Price bands `execute_triggers_in_range` scans per call, about two doublings of the price
+const MAX_TRIGGER_BANDS: u32 = 128;
 
 #[contracterror]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -25,15 +183,447 @@ pub enum ContractError {
     PositionNotOpen = 2,
     ZeroValue = 3,
     AboveMargin = 4,
+    InvalidMarginTiers = 5,
+    InsufficientMargin = 6,
+    LiquidationReserved = 7,
+    InvalidTip = 8,
+    InvalidPrice = 9,
+    PriceNotUpdated = 10,
+    SettlementActive = 11,
+    NotSettling = 12,
+    TimelockActive = 13,
+    NoPendingChange = 14,
+    InvalidLeverage = 15,
+    PositionTooSmallForPrice = 16,
+    InvariantViolated = 17,
+    AssetMismatch = 18,
+    NotOracle = 19,
+    StalePrice = 20,
+    AlreadyUpdated = 21,
+    OutdatedPrice = 22,
+    SlippageExceeded = 23,
+    EpochNotFinished = 24,
+    ParameterChangeTooLarge = 25,
+    InvalidDecimals = 26,
+    AssetNotAllowed = 27,
+    InvalidQuorum = 28,
+    AccountSuspended = 29,
+    RecomputeStale = 30,
+    DisputeWindowClosed = 31,
+    PriceOutOfBounds = 32,
+    InsufficientInsurance = 33,
+    IntentExpired = 34,
+    TriggerNotReached = 35,
+    LifetimeNotReached = 36,
+    OrderNotOpen = 37,
+    InvalidExpiry = 38,
+    NotExpired = 39,
+    NoBackstop = 40,
+    BelowMinimum = 41,
+    PositionTooLarge = 42,
+    LiquidationGrace = 43,
+    ReduceOnly = 44,
+    NotApproved = 45,
+    AlreadyInitialized = 46,
 }
 
+/// Packed position entry. Prices are stored as u64 in pUSD units per whole
+/// base unit (see `normalize_price`) and direction lives in `flags`; use
+/// the accessors rather than reading the raw fields. `size_base` is the
+/// position size in base-asset units with 7 decimals (see `BASE_UNIT`).
+/// `id` is assigned from the market's sequence when the position opens.
+/// `margin_version` is the margin tier curve the position is held to.
+/// `entry_spread` is the spread paid on entry, in pUSD, credited to the
+/// fee pool when the position settles.
 #[derive(Clone)]
 #[contracttype]
 pub struct Position {
+    pub id: u64,
+    pub value: i128,
+    pub size_base: i128,
+    pub open_price: u64,
+    pub close_price: u64,
+    pub opened_at_ledger: u32,
+    pub leverage: u32,
+    pub margin_version: u32,
+    pub flags: u32,
+    pub entry_spread: i128,
+}
+
+// Position flag bits
+const FLAG_LONG: u32 = 1;
+
+impl Position {
+    pub fn new(value: i128, open_price: u64, opened_at_ledger: u32, leverage: u32, long: bool) -> Self {
+        let flags = if long { FLAG_LONG } else { 0 };
+        let size_base = div_floor(value * i128::from(leverage) * BASE_UNIT, i128::from(open_price));
+        Position {
+            id: 0,
+            value,
+            size_base,
+            open_price,
+            close_price: 0,
+            opened_at_ledger,
+            leverage,
+            margin_version: 0,
+            flags,
+            entry_spread: 0,
+        }
+    }
+
+    pub fn is_long(&self) -> bool {
+        self.flags & FLAG_LONG != 0
+    }
+
+    pub fn open_price(&self) -> i128 {
+        i128::from(self.open_price)
+    }
+
+    pub fn close_price(&self) -> i128 {
+        i128::from(self.close_price)
+    }
+
+    pub fn leverage(&self) -> i128 {
+        i128::from(self.leverage)
+    }
+
+    /// Exposure in pUSD at the entry price. Taken from the size rather
+    /// than `value * leverage` so that margin added later does not count
+    /// as exposure.
+    pub fn notional(&self) -> i128 {
+        div_floor(self.size_base * self.open_price(), BASE_UNIT)
+    }
+}
+
+#[contracttype]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum RiskTier {
+    Safe = 0,
+    Warning = 1,
+    Danger = 2,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct PositionDetails {
+    pub position: Position,
+    pub current_value: i128,
+    pub liquidation_price: i128,
+    pub distance_bps: i128,
+    pub risk_tier: RiskTier,
+}
+
+/// Everything a wallet portfolio page needs about one user. `balance` and
+/// `allowance` are the user's pUSD available to open with.
+#[derive(Clone)]
+#[contracttype]
+pub struct AccountSnapshot {
+    pub balance: i128,
+    pub allowance: i128,
+    pub position: Option<PositionDetails>,
+    pub margin_call: Option<u64>,
+    pub suspension: Option<Suspension>,
+}
+
+// Entrypoint argument structs. Wallets render the fields of these by name
+// in auth prompts, so signers see what they approve (market, size,
+// direction, leverage) rather than a list of bare numbers.
+
+/// Order sized in base-asset units for `place_trade_units`. `asset` must
+/// match the market's asset so a signature cannot be replayed elsewhere.
+#[derive(Clone)]
+#[contracttype]
+pub struct UnitsOrder {
+    pub asset: String,
+    pub base_units: i128,
+    pub long: bool,
+    pub leverage: u32,
+}
+
+/// Backup oracle accepted only while the primary misses its heartbeat
+#[derive(Clone)]
+#[contracttype]
+pub struct FailoverConfig {
+    pub primary: Address,
+    pub backup: Address,
+    pub heartbeat: u64,
+}
+
+/// An oracle's track record over one scoring epoch
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct OracleStats {
+    pub updates: u32,
+    pub total_deviation_bps: i128,
+    pub stale_reports: u32,
+}
+
+/// Price statement signed off-chain by an oracle key. The signature
+/// covers the XDR encoding of the tuple `(network_id, contract, payload)`,
+/// so it is only valid for this contract on this network. `confidence` is
+/// the oracle's uncertainty around `price`, in the same units.
+#[derive(Clone)]
+#[contracttype]
+pub struct PricePayload {
+    pub asset: String,
+    pub price: i128,
+    pub confidence: i128,
+    pub timestamp: u64,
+}
+
+/// Trade request for `place_trade`. `limit_price` is the worst fill the
+/// trader accepts: a maximum for longs and a minimum for shorts.
+/// `valid_until_ledger` is the last ledger the request may execute in.
+/// A `reduce_only` request never opens exposure: it closes the trader's
+/// position on the opposite side instead.
+#[derive(Clone)]
+#[contracttype]
+pub struct OpenRequest {
     pub value: i128,
-    pub open_price: i128,
-    pub close_price: i128,
     pub long: bool,
+    pub leverage: u32,
+    pub limit_price: Option<i128>,
+    pub referrer: Option<Address>,
+    pub valid_until_ledger: Option<u32>,
+    pub reduce_only: bool,
+}
+
+#[contracttype]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum OrderStatus {
+    Open = 0,
+    Filled = 1,
+    Cancelled = 2,
+    Expired = 3,
+}
+
+/// Resting order whose margin is held in escrow until a keeper fills it
+/// at `request.limit_price` or better, or the trader cancels it
+#[derive(Clone)]
+#[contracttype]
+pub struct Order {
+    pub id: u64,
+    pub trader: Address,
+    pub request: OpenRequest,
+    pub status: OrderStatus,
+}
+
+/// Liquidation request for `liquidate_position`
+#[derive(Clone)]
+#[contracttype]
+pub struct LiquidationRequest {
+    pub user: Address,
+    pub tip_bps: u32,
+    pub recipient: Option<Address>,
+}
+
+/// Restriction on an account suspected of abuse, lifted at `until`.
+/// Closing a position is never blocked.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct Suspension {
+    pub open_only: bool,
+    pub until: u64,
+}
+
+/// Flat pUSD paid from the fee pool to whoever relays a signed price, at
+/// most once per `interval` seconds
+#[derive(Clone)]
+#[contracttype]
+pub struct KeeperRebate {
+    pub amount: i128,
+    pub interval: u64,
+}
+
+/// Contract that takes over positions no keeper liquidated within
+/// `delay_ledgers` of being marked liquidatable
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct BackstopConfig {
+    pub backstop: Address,
+    pub delay_ledgers: u32,
+}
+
+/// Where prices for one asset come from: its pushing oracles (`false` =
+/// suspended), an optional SEP-40 feed and the decimals both report in
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct OracleRoute {
+    pub oracles: Map<Address, bool>,
+    pub feed: Option<Address>,
+    pub decimals: u32,
+}
+
+/// Stop-loss or take-profit on a position. A stop triggers once the index
+/// moves against the position to `trigger_price`, a take-profit once it
+/// moves in its favour; any keeper can then close the position.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct StopOrder {
+    pub trader: Address,
+    pub trigger_price: i128,
+}
+
+/// Volatility mode trigger. When the RMS return between recent price
+/// checkpoints exceeds `threshold_bps`, maintenance margins used for
+/// liquidation drop by `buffer_bps`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct VolatilityGuard {
+    pub threshold_bps: i128,
+    pub buffer_bps: i128,
+}
+
+/// Realized volatility and whether volatility mode is on
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct VolatilityState {
+    pub volatility_bps: i128,
+    pub active: bool,
+}
+
+/// Usage counters for one entrypoint. `failures` only counts calls that
+/// return without acting (a failing transaction rolls back its counters).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[contracttype]
+pub struct EntrypointMetrics {
+    pub calls: u64,
+    pub failures: u64,
+    pub volume: i128,
+}
+
+/// Range outside which oracle prices are treated as corrupt and rejected
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PriceBounds {
+    pub min: i128,
+    pub max: i128,
+}
+
+/// Maintenance margin applied to positions with at least `min_notional`
+#[derive(Clone)]
+#[contracttype]
+pub struct MarginTier {
+    pub min_notional: i128,
+    pub margin_bps: i128,
+}
+
+/// Timelocked asset metadata change
+#[derive(Clone)]
+#[contracttype]
+pub struct PendingAsset {
+    pub asset: String,
+    pub eta_ledger: u32,
+}
+
+/// Address book entry waiting out the timelock; `None` removes the entry
+#[derive(Clone)]
+#[contracttype]
+pub struct PendingContract {
+    pub name: Symbol,
+    pub address: Option<Address>,
+    pub eta_ledger: u32,
+}
+
+/// Addresses of every component linked to this market, so frontends and
+/// bots can bootstrap from the perp address alone. `linked` holds the
+/// entries registered through `propose_contract` (vault share token,
+/// factory, treasury, ...).
+#[derive(Clone)]
+#[contracttype]
+pub struct ContractBook {
+    pub collateral: Address,
+    pub oracles: Vec<Address>,
+    pub price_feed: Option<Address>,
+    pub swap_router: Option<Address>,
+    pub yield_source: Option<Address>,
+    pub backstop: Option<Address>,
+    pub linked: Map<Symbol, Address>,
+}
+
+/// Standing commitment to tip part of a liquidation reward to the insurance fund
+#[derive(Clone)]
+#[contracttype]
+pub struct LiquidationBid {
+    pub liquidator: Address,
+    pub tip_bps: u32,
+    pub start_ledger: u32,
+}
+
+/// Open interest and the fee a marginal trade pays in each direction
+#[derive(Clone)]
+#[contracttype]
+pub struct SkewInfo {
+    pub long_oi: i128,
+    pub short_oi: i128,
+    pub imbalance_bps: i128,
+    pub long_fee_bps: i128,
+    pub short_fee_bps: i128,
+    pub fee_on_notional: bool,
+}
+
+/// Admin-funded pUSD budget that rewards skew-balancing trades
+#[derive(Clone)]
+#[contracttype]
+pub struct IncentiveBudget {
+    pub remaining: i128,
+    pub start_ledger: u32,
+}
+
+/// Running price × seconds total at the time of a price update
+#[derive(Clone)]
+#[contracttype]
+pub struct TwapObservation {
+    pub timestamp: u64,
+    pub cumulative: i128,
+    pub price: i128,
+}
+
+/// Partial open interest sums carried between `recompute_totals` pages,
+/// with the stored totals they were started against
+#[derive(Clone)]
+#[contracttype]
+pub struct RecomputeState {
+    pub next_page: u32,
+    pub long_oi: i128,
+    pub short_oi: i128,
+    pub stored_long: i128,
+    pub stored_short: i128,
+}
+
+/// pUSD held by the contract and what it is set aside for
+#[derive(Clone)]
+#[contracttype]
+pub struct LiquidityInfo {
+    pub balance: i128,
+    pub deployed: i128,
+    pub reserved: i128,
+    pub insurance: i128,
+    pub incentives: i128,
+    pub escrowed: i128,
+    pub free: i128,
+}
+
+/// Execution terms a trade would get right now
+#[derive(Clone)]
+#[contracttype]
+pub struct TradeQuote {
+    pub price: i128,
+    pub fee: i128,
+    pub size_base: i128,
+    pub liquidation_price: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct ShockReport {
+    pub price: i128,
+    pub liquidatable: u32,
+    pub notional_affected: i128,
+    pub insurance_draw: i128,
+    pub vault_pnl: i128,
 }
 
 #[contract]
@@ -45,217 +635,3743 @@ pub trait FungibleToken {
 
 }
 
+/// SEP-40 asset identifier, as used by Reflector
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum FeedAsset {
+    Stellar(Address),
+    Other(Symbol),
+}
+
+/// SEP-40 price record
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PriceData {
+    pub price: i128,
+    pub timestamp: u64,
+}
+
+/// The part of the SEP-40 oracle interface the perp reads
+#[contractclient(name = "PriceFeedClient")]
+pub trait PriceFeed {
+    fn lastprice(env: Env, asset: FeedAsset) -> Option<PriceData>;
+}
+
+/// DEX router used to convert payouts out of pUSD. It pulls `amount_in`
+/// of `token_in` from `from` through an allowance and sends at least
+/// `min_out` of `token_out` to `to`, returning the amount sent.
+#[contractclient(name = "SwapRouterClient")]
+pub trait SwapRouter {
+    fn swap_exact_in(
+        env: Env,
+        from: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_in: i128,
+        min_out: i128,
+        to: Address,
+    ) -> i128;
+}
+
+/// Low-risk yield source the insurance fund can be parked in (e.g. a
+/// Blend stable pool adapter). `deposit` pulls `amount` of pUSD from
+/// `from` through an allowance, `withdraw` sends `amount` back to `to`
+/// at once, and `balance` is what `owner` could withdraw right now.
+#[contractclient(name = "YieldSourceClient")]
+pub trait YieldSource {
+    fn deposit(env: Env, from: Address, amount: i128);
+    fn withdraw(env: Env, to: Address, amount: i128);
+    fn balance(env: Env, owner: Address) -> i128;
+}
+
+/// Liquidation backstop (e.g. an insurance DAO vault). `take_over` is
+/// called after `user`'s position has been merged into the backstop's
+/// position `position_id`, so it can add margin, hedge or close it.
+#[contractclient(name = "BackstopClient")]
+pub trait Backstop {
+    fn take_over(env: Env, user: Address, position_id: u64);
+}
+
 #[contractimpl]
 impl PerpContract {
 
 
-    /// Initialize contract parameters
-    pub fn initialize(env: Env, asset: String, leverage: i128, p_usd: Address, oracle: Address) {
+    /// Initialize contract parameters. `oracle_decimals` is the precision
+    /// of reported prices; they are rescaled to the pUSD token's decimals.
+    /// A non-zero `ema_weight_bps` stores an exponential moving average of
+    /// accepted prices, giving each new price that weight. Can only be
+    /// called once, signed by `admin`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn initialize(
+        env: Env,
+        admin: Address,
+        asset: String,
+        leverage: i128,
+        p_usd: Address,
+        oracle: Address,
+        price_feed: Option<Address>,
+        oracle_decimals: u32,
+        ema_weight_bps: u32,
+        price_bounds: PriceBounds,
+    ) {
+        if env.storage().instance().has(&ADMIN) {
+            panic_with_error!(&env, ContractError::AlreadyInitialized);
+        }
+        admin.require_auth();
+        let token_decimals = TokenClient::new(&env, &p_usd).decimals();
+        if oracle_decimals > MAX_DECIMALS || token_decimals > MAX_DECIMALS {
+            panic_with_error!(&env, ContractError::InvalidDecimals);
+        }
+        if ema_weight_bps > 10000 {
+            panic_with_error!(&env, ContractError::InvalidPrice);
+        }
+        check_bounds(&env, &price_bounds);
+        env.storage().instance().set(&PRICE_BOUNDS, &price_bounds);
+        env.storage().instance().set(&EMA_WEIGHT, &ema_weight_bps);
+        env.storage().instance().set(&TOKEN_DECIMALS, &token_decimals);
+        env.storage().instance().set(&ADMIN, &admin);
         env.storage().instance().set(&ASSET, &asset);
+        env.storage().instance().set(&MARKET, &asset_symbol(&env, &asset));
         env.storage().instance().set(&LEVERAGE, &leverage);
         env.storage().instance().set(&PUSD, &p_usd);
 
         let mut oracles: Map<Address, bool> = Map::new(&env);
         oracles.set(oracle.clone(), true);
-        env.storage().instance().set(&ORACLES, &oracles);
+        set_market_route(&env, &OracleRoute { oracles, feed: price_feed, decimals: oracle_decimals });
         env.storage().instance().set(&MARGIN_REQ, &i128::from(300));
         env.storage().instance().set(&LONG_POS, &0_i128);
         env.storage().instance().set(&SHORT_POS, &0_i128);
+        env.storage().instance().set(&INSURANCE, &0_i128);
 
-        let history: Vec<Position> = Vec::new(&env);
-        env.storage().instance().set(&TRADE_HISTORY, &history);
+        let tiers: Vec<MarginTier> = Vec::new(&env);
+        env.storage().instance().set(&MARGIN_TIERS, &tiers);
     }
 
-    /// Place a new trade
-    pub fn place_trade(env: Env, trader: Address, value: i128, long: bool) {
-        trader.require_auth();
-        // Load or create positions map
-        let mut positions: Map<Address, Position> = env
-            .storage()
-            .persistent()
-            .get(&POSITIONS)
-            .unwrap_or_else(|| Map::new(&env));
-        // 2do check user doesn't already have a postion
-        if value <= 0 {
-            panic_with_error!(&env, ContractError::ZeroValue);
-        }
+    /// Report a price observed at `timestamp` from an authorised oracle.
+    /// The stored price is the median of recent reports once the oracle
+    /// quorum is met.
+    pub fn set_price(env: Env, oracle: Address, price: i128, timestamp: u64) {
+        oracle.require_auth();
+        accept_report(&env, &oracle, price, 0, timestamp);
+    }
 
-        // Transfer in pUSD
-        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
-        TokenClient::new(&env, &p_usd).transfer_from(
-            &env.current_contract_address(),
-            &trader,
-            &env.current_contract_address(),
-            &value,
-        );
-        // Calculate fee
-        let fee = Self::calculate_fee(&env, value, long);
-        let remaining = value - fee;
-
-        // Update totals
-        let mut total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap();
-        let mut total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap();
-        if long {
-            total_long += remaining;
-            env.storage().instance().set(&LONG_POS, &total_long);
-        } else {
-            total_short += remaining;
-            env.storage().instance().set(&SHORT_POS, &total_short);
-        }
+    /// `set_price` with a confidence interval. Trades fill `confidence`
+    /// away from the price, against the trader.
+    pub fn set_price_with_confidence(env: Env, oracle: Address, price: i128, confidence: i128, timestamp: u64) {
+        oracle.require_auth();
+        accept_report(&env, &oracle, price, confidence, timestamp);
+    }
 
-        // Store and persist position
-        let price: i128 = env.storage().instance().get(&PRICE).unwrap_or(0_i128);
-        let position = Position { value: remaining, open_price: price, close_price: 0, long };
-        positions.set(trader.clone(), position);
-        env.storage().persistent().set(&POSITIONS, &positions);
+    /// Confidence interval of the current price
+    pub fn get_confidence(env: Env) -> i128 {
+        env.storage().instance().get(&CONFIDENCE).unwrap_or(0)
+    }
 
-        env.events().publish((symbol_short!("PLACE"),), (trader, value, long));
+    /// Submit a price signed by a registered oracle key. Anyone may relay
+    /// it; the report counts as coming from the oracle the key belongs to.
+    pub fn submit_signed_price(env: Env, payload: PricePayload, signature: BytesN<64>, pubkey: BytesN<32>) {
+        accept_signed_report(&env, payload, signature, pubkey);
     }
 
-    /// Calculate fee for a trade
-    pub fn calculate_fee(env: &Env, value: i128, long: bool) -> i128 {
-        let mut fee: i128 = 0;
-        let total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap_or(0_i128);
-        let total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap_or(0_i128);
-        if total_long > total_short && long {
-            fee = value / 100; // 1%
+    /// `submit_signed_price` on behalf of `keeper`, who earns the keeper
+    /// rebate if the report publishes a new price. Returns the rebate paid.
+    pub fn relay_price(env: Env, keeper: Address, payload: PricePayload, signature: BytesN<64>, pubkey: BytesN<32>) -> i128 {
+        keeper.require_auth();
+        if !accept_signed_report(&env, payload, signature, pubkey) {
+            return 0;
         }
-        if total_short > total_long && !long {
-            fee = value / 100; // 1%
+        pay_keeper_rebate(&env, &keeper)
+    }
+
+    /// Rebate paid from the fee pool to keepers relaying prices or
+    /// executing orders, at most once per `interval` seconds. A zero
+    /// amount turns rebates off.
+    pub fn set_keeper_rebate(env: Env, amount: i128, interval: u64) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if amount < 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
         }
-        return fee;
+        env.storage().instance().set(&KEEPER_REBATE, &KeeperRebate { amount, interval });
     }
 
-    /// Calculate current position value
-    pub fn calculate_position(env: &Env, user: Address) -> i128 {
-        let positions: Map<Address, Position> = env
+    /// Skew fees and spread revenue collected, less keeper rebates
+    pub fn get_fee_pool(env: Env) -> i128 {
+        env.storage().instance().get(&FEE_POOL).unwrap_or(0_i128)
+    }
+
+    /// Register an ed25519 key that signs prices on behalf of `oracle`
+    pub fn add_oracle_key(env: Env, oracle: Address, pubkey: BytesN<32>) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let mut keys: Map<BytesN<32>, Address> = env
             .storage()
-            .persistent()
-            .get(&POSITIONS)
-            .unwrap_or_else(|| Map::new(env));
-        let position = match positions.get(user.clone()) {
-            Some(p) => p,
-            None => return 0,
-        };
-        let price: i128 = env.storage().instance().get(&PRICE).unwrap_or(0_i128);
-        let mut gain: i128 = 0;
-        let mut loss: i128 = 0;
-        if position.long {
-            if price > position.open_price {
-                gain = price - position.open_price;
-            } else {
-                loss = position.open_price - price;
-            }
-        } else {
-            if price < position.open_price {
-                gain = position.open_price - price;
-            } else {
-                loss = price - position.open_price;
-            }
-        }
-        let leverage: i128 = env.storage().instance().get(&LEVERAGE).unwrap();
-        let mut ret = position.value;
-        let multiplier = (leverage * position.value * 10000) / position.open_price;
-        if gain > 0 {
-            ret = position.value + ((gain * multiplier) / 10000);
-        } else if loss > 0 {
-            if (loss * multiplier) > position.value {
-                return 0; // liquidated
-            }
-            ret = position.value - ((loss * multiplier) / 10000);
-        }
-        ret
+            .instance()
+            .get(&ORACLE_KEYS)
+            .unwrap_or_else(|| Map::new(&env));
+        keys.set(pubkey, oracle);
+        env.storage().instance().set(&ORACLE_KEYS, &keys);
     }
 
-    /// Close an open trade
-    pub fn close_trade(env: Env, trader: Address) {
-        trader.require_auth();
-        let mut positions: Map<Address, Position> = env
+    /// Revoke an oracle signing key
+    pub fn remove_oracle_key(env: Env, pubkey: BytesN<32>) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let mut keys: Map<BytesN<32>, Address> = env
             .storage()
-            .persistent()
-            .get(&POSITIONS)
-            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
-        let position = positions.get(trader.clone()).unwrap();
-        let ret_bal = Self::calculate_position(&env, trader.clone());
-
-        // Update history
-        let mut history: Vec<Position> = env.storage().instance().get(&TRADE_HISTORY).unwrap();
-        let mut closed = position.clone();
-        closed.close_price = env.storage().instance().get(&PRICE).unwrap_or(0_i128);
-        history.push_back(closed.clone());
-        env.storage().instance().set(&TRADE_HISTORY, &history);
-
-        // Update totals and remove
-        let mut total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap();
-        let mut total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap();
-        if position.long {
-            total_long -= position.value;
-            env.storage().instance().set(&LONG_POS, &total_long);
-        } else {
-            total_short -= position.value;
-            env.storage().instance().set(&SHORT_POS, &total_short);
+            .instance()
+            .get(&ORACLE_KEYS)
+            .unwrap_or_else(|| Map::new(&env));
+        keys.remove(pubkey);
+        env.storage().instance().set(&ORACLE_KEYS, &keys);
+    }
+
+    /// Time-weighted average of the pushed price over the last `window`
+    /// seconds. Fails with `PriceNotUpdated` until the recorded history
+    /// covers the window.
+    pub fn get_twap(env: Env, window: u64) -> i128 {
+        if window == 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
         }
-        positions.remove(trader.clone());
-        env.storage().persistent().set(&POSITIONS, &positions);
+        let observations: Vec<TwapObservation> = env
+            .storage()
+            .instance()
+            .get(&TWAP)
+            .unwrap_or_else(|| Vec::new(&env));
+        let now = env.ledger().timestamp();
+        let last = observations
+            .last()
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PriceNotUpdated));
+        let start = now
+            .checked_sub(window)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PriceNotUpdated));
 
-        // Payout
-        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
-        TokenClient::new(&env, &p_usd).transfer(
-            &env.current_contract_address(),
-            &trader,
-            &ret_bal,
-        );
+        // Newest checkpoint at or before the start of the window
+        let mut index = observations.len();
+        while index > 0 && observations.get_unchecked(index - 1).timestamp > start {
+            index -= 1;
+        }
+        if index == 0 {
+            panic_with_error!(&env, ContractError::PriceNotUpdated);
+        }
+        let from = observations.get_unchecked(index - 1);
+        let cumulative_now = last.cumulative + last.price * i128::from(now - last.timestamp);
+        let cumulative_start = from.cumulative + from.price * i128::from(start - from.timestamp);
+        div_floor(cumulative_now - cumulative_start, i128::from(window))
     }
 
-    /// Liquidate an under-margined position
-    pub fn liquidate_position(env: Env, liquidator: Address, user: Address) {
-        liquidator.require_auth();
-        let mut positions: Map<Address, Position> = env
+    /// Up to `count` of the most recent accepted prices, oldest first,
+    /// from the last `TWAP_OBSERVATIONS` checkpoints
+    pub fn get_price_history(env: Env, count: u32) -> Vec<PriceData> {
+        let observations: Vec<TwapObservation> = env
             .storage()
-            .persistent()
-            .get(&POSITIONS)
-            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
-        let position = positions.get(user.clone()).unwrap();
-        let ret_bal = Self::calculate_position(&env, user.clone());
-        let margin_req: i128 = env.storage().instance().get(&MARGIN_REQ).unwrap();
-        let required_val = (position.value * margin_req) / 10000;
-        if ret_bal >= required_val {
-            panic_with_error!(&env, ContractError::AboveMargin);
+            .instance()
+            .get(&TWAP)
+            .unwrap_or_else(|| Vec::new(&env));
+        let mut history = Vec::new(&env);
+        for observation in observations.iter().skip(observations.len().saturating_sub(count) as usize) {
+            history.push_back(PriceData { price: observation.price, timestamp: observation.timestamp });
         }
+        history
+    }
 
-        // Archive history
-        let mut history: Vec<Position> = env.storage().instance().get(&TRADE_HISTORY).unwrap();
-        let mut closed = position.clone();
-        closed.close_price = env.storage().instance().get(&PRICE).unwrap_or(0_i128);
-        history.push_back(closed.clone());
-        env.storage().instance().set(&TRADE_HISTORY, &history);
+    /// Maximum age in seconds of the price used to open, close or liquidate.
+    /// Zero disables the check.
+    pub fn set_max_price_age(env: Env, max_age: u64) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        env.storage().instance().set(&MAX_PRICE_AGE, &max_age);
+    }
+
+    /// Number of accepted price updates after a staleness outage before
+    /// liquidations resume, so traders can top up margin against the
+    /// post-gap price. Zero disables the grace period.
+    pub fn set_liquidation_grace(env: Env, updates: u32) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        env.storage().instance().set(&LIQ_GRACE, &updates);
+    }
+
+    /// Price updates still needed before liquidations resume
+    pub fn get_liquidation_grace(env: Env) -> u32 {
+        env.storage().instance().get(&GRACE_LEFT).unwrap_or(0)
+    }
+
+    /// Enter volatility mode automatically when realized volatility over
+    /// the price checkpoints exceeds `threshold_bps`, widening liquidation
+    /// buffers by `buffer_bps`. A zero threshold disables it.
+    pub fn set_volatility_guard(env: Env, threshold_bps: i128, buffer_bps: i128) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if threshold_bps < 0 || !(0..=10000).contains(&buffer_bps) {
+            panic_with_error!(&env, ContractError::InvalidPrice);
+        }
+        env.storage().instance().set(&VOL_GUARD, &VolatilityGuard { threshold_bps, buffer_bps });
+        update_volatility_mode(&env);
+    }
+
+    /// Realized volatility in bps and whether volatility mode is on
+    pub fn get_volatility(env: Env) -> VolatilityState {
+        VolatilityState {
+            volatility_bps: realized_volatility(&env),
+            active: env.storage().instance().get(&VOL_MODE).unwrap_or(false),
+        }
+    }
+
+    /// Largest move in bps from the current price that a single oracle can
+    /// make. Bigger jumps are held until confirmed. Zero disables the check.
+    pub fn set_max_price_jump(env: Env, max_jump_bps: i128) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if max_jump_bps < 0 {
+            panic_with_error!(&env, ContractError::InvalidPrice);
+        }
+        env.storage().instance().set(&MAX_PRICE_JUMP, &max_jump_bps);
+    }
+
+    /// Replace the range of accepted oracle prices
+    pub fn set_price_bounds(env: Env, price_bounds: PriceBounds) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        check_bounds(&env, &price_bounds);
+        env.storage().instance().set(&PRICE_BOUNDS, &price_bounds);
+    }
+
+    /// Range of accepted oracle prices
+    pub fn get_price_bounds(env: Env) -> PriceBounds {
+        env.storage().instance().get(&PRICE_BOUNDS).unwrap()
+    }
+
+    /// Premium in bps added to the index when open interest is entirely on
+    /// one side; the mark price scales it by the skew. Zero (the default)
+    /// makes the mark equal the index. The mark decides liquidations, so
+    /// changes are limited as for `set_skew_fee`.
+    pub fn set_mark_premium(env: Env, max_premium_bps: i128) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if !(0..=MAX_MARK_PREMIUM_BPS).contains(&max_premium_bps) {
+            panic_with_error!(&env, ContractError::InvalidPrice);
+        }
+        let current: i128 = env.storage().instance().get(&MARK_PREMIUM).unwrap_or(0);
+        require_gradual_change(&env, current, max_premium_bps, PREMIUM_CHANGED);
+        env.storage().instance().set(&MARK_PREMIUM, &max_premium_bps);
+    }
+
+    /// Mark price used to value positions and check liquidations
+    pub fn get_mark_price(env: Env) -> i128 {
+        mark_price(&env, current_price(&env))
+    }
+
+    /// Authorise a new price oracle
+    pub fn add_oracle(env: Env, oracle: Address) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let mut route = market_route(&env);
+        route.oracles.set(oracle.clone(), true);
+        set_market_route(&env, &route);
+        env.events().publish((symbol_short!("ORACLE"), oracle), true);
+    }
+
+    /// Revoke a price oracle and discard its last report
+    pub fn remove_oracle(env: Env, oracle: Address) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let mut route = market_route(&env);
+        route.oracles.remove(oracle.clone());
+        set_market_route(&env, &route);
+        let mut reports: Map<Address, PriceData> = env
+            .storage()
+            .instance()
+            .get(&ORACLE_REPORTS)
+            .unwrap_or_else(|| Map::new(&env));
+        reports.remove(oracle.clone());
+        env.storage().instance().set(&ORACLE_REPORTS, &reports);
+        let mut confidences: Map<Address, i128> = env
+            .storage()
+            .instance()
+            .get(&ORACLE_CONF)
+            .unwrap_or_else(|| Map::new(&env));
+        confidences.remove(oracle.clone());
+        env.storage().instance().set(&ORACLE_CONF, &confidences);
+        env.events().publish((symbol_short!("ORACLE"), oracle), false);
+    }
+
+    /// Currently authorised price oracles
+    pub fn get_oracles(env: Env) -> Vec<Address> {
+        let mut active = Vec::new(&env);
+        for (oracle, enabled) in market_route(&env).oracles.iter() {
+            if enabled {
+                active.push_back(oracle);
+            }
+        }
+        active
+    }
+
+    /// Oracle routes by asset symbol
+    pub fn get_oracle_routes(env: Env) -> Map<Symbol, OracleRoute> {
+        env.storage().instance().get(&ORACLE_ROUTES).unwrap()
+    }
+
+    /// Oracle route for `asset`, if one is configured
+    pub fn get_oracle_route(env: Env, asset: Symbol) -> Option<OracleRoute> {
+        Self::get_oracle_routes(env).get(asset)
+    }
+
+    /// Latest report from `oracle`, for heartbeat monitoring
+    pub fn get_last_report(env: Env, oracle: Address) -> Option<PriceData> {
+        let reports: Map<Address, PriceData> = env
+            .storage()
+            .instance()
+            .get(&ORACLE_REPORTS)
+            .unwrap_or_else(|| Map::new(&env));
+        reports.get(oracle)
+    }
+
+    /// Hold the registered oracle `backup` in reserve, letting it report
+    /// only while `primary` has not reported for more than `heartbeat`
+    /// seconds, instead of the market freezing
+    pub fn set_failover(env: Env, primary: Address, backup: Address, heartbeat: u64) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if heartbeat == 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        if !market_route(&env).oracles.get(backup.clone()).unwrap_or(false) {
+            panic_with_error!(&env, ContractError::NotOracle);
+        }
+        env.storage().instance().set(&FAILOVER, &FailoverConfig { primary, backup, heartbeat });
+        env.storage().instance().set(&FAILOVER_ON, &false);
+    }
+
+    /// An oracle's statistics for a scoring epoch (`timestamp / ORACLE_EPOCH`)
+    pub fn get_oracle_stats(env: Env, oracle: Address, epoch: u64) -> OracleStats {
+        env.storage()
+            .persistent()
+            .get(&(ORACLE_STATS, oracle, epoch))
+            .unwrap_or(OracleStats { updates: 0, total_deviation_bps: 0, stale_reports: 0 })
+    }
+
+    /// Average deviation from the accepted median, in bps, above which
+    /// `review_oracle` suspends an oracle
+    pub fn set_oracle_threshold(env: Env, max_deviation_bps: i128) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if max_deviation_bps <= 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        env.storage().instance().set(&ORACLE_THRESHOLD, &max_deviation_bps);
+    }
+
+    /// Score an oracle over a finished epoch and suspend it when its
+    /// average deviation exceeds the threshold or most of its reports
+    /// were stale. Returns whether it was suspended.
+    pub fn review_oracle(env: Env, oracle: Address, epoch: u64) -> bool {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if epoch >= env.ledger().timestamp() / ORACLE_EPOCH {
+            panic_with_error!(&env, ContractError::EpochNotFinished);
+        }
+        let stats = Self::get_oracle_stats(env.clone(), oracle.clone(), epoch);
+        if stats.updates == 0 {
+            return false;
+        }
+        let threshold: i128 = env.storage().instance().get(&ORACLE_THRESHOLD).unwrap_or(i128::MAX);
+        let average = stats.total_deviation_bps / i128::from(stats.updates);
+        if average <= threshold && stats.stale_reports * 2 <= stats.updates {
+            return false;
+        }
+        let mut route = market_route(&env);
+        if !route.oracles.contains_key(oracle.clone()) {
+            return false;
+        }
+        route.oracles.set(oracle.clone(), false);
+        set_market_route(&env, &route);
+        env.events().publish((symbol_short!("SUSPEND"), oracle), (epoch, average, stats.stale_reports));
+        true
+    }
+
+    /// Number of oracles that must have reported within `REPORT_WINDOW`
+    /// before the median of their reports becomes the price. It cannot
+    /// exceed the number of active oracles, which would freeze the price.
+    pub fn set_oracle_quorum(env: Env, quorum: u32) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if quorum == 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        if quorum > Self::get_oracles(env.clone()).len() {
+            panic_with_error!(&env, ContractError::InvalidQuorum);
+        }
+        env.storage().instance().set(&ORACLE_QUORUM, &quorum);
+    }
+
+    /// Reports needed before the median becomes the price (default 1),
+    /// clamped to the oracles currently able to report
+    pub fn get_oracle_quorum(env: Env) -> u32 {
+        oracle_quorum(&env)
+    }
+
+    /// Replace the maintenance margin curve, ordered by ascending notional.
+    /// Open positions keep the curve they were opened under until the
+    /// trader increases them.
+    pub fn set_margin_tiers(env: Env, tiers: Vec<MarginTier>) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let base_req: i128 = env.storage().instance().get(&MARGIN_REQ).unwrap();
+        let mut prev_notional: i128 = 0;
+        let mut prev_margin = base_req;
+        for tier in tiers.iter() {
+            if tier.min_notional <= prev_notional || tier.margin_bps < prev_margin || tier.margin_bps > 10000 {
+                panic_with_error!(&env, ContractError::InvalidMarginTiers);
+            }
+            prev_notional = tier.min_notional;
+            prev_margin = tier.margin_bps;
+        }
+        let version = margin_version(&env);
+        let current: Vec<MarginTier> = env.storage().instance().get(&MARGIN_TIERS).unwrap();
+        env.storage().persistent().set(&(MARGIN_TIERS, version), &current);
+        env.storage().instance().set(&TIER_VERSION, &(version + 1));
+        env.storage().instance().set(&MARGIN_TIERS, &tiers);
+    }
+
+    /// Version of the margin tier curve new positions are opened under
+    pub fn get_margin_version(env: Env) -> u32 {
+        margin_version(&env)
+    }
+
+    /// Propose a new asset display name, applicable after the timelock
+    pub fn propose_asset(env: Env, asset: String) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let eta_ledger = env.ledger().sequence() + TIMELOCK_LEDGERS;
+        let pending = PendingAsset { asset: asset.clone(), eta_ledger };
+        env.storage().instance().set(&PENDING_ASSET, &pending);
+        env.events().publish((symbol_short!("METAPROP"),), (asset, eta_ledger));
+    }
+
+    /// Apply the pending asset name once its timelock has passed. Only
+    /// display metadata changes; no risk state is touched.
+    pub fn apply_asset(env: Env) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let pending: PendingAsset = env
+            .storage()
+            .instance()
+            .get(&PENDING_ASSET)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::NoPendingChange));
+        if env.ledger().sequence() < pending.eta_ledger {
+            panic_with_error!(&env, ContractError::TimelockActive);
+        }
+        let old: String = env.storage().instance().get(&ASSET).unwrap();
+        env.storage().instance().set(&ASSET, &pending.asset);
+        env.storage().instance().remove(&PENDING_ASSET);
+        env.events().publish((symbol_short!("META"),), (old, pending.asset));
+    }
+
+    /// Asset display name
+    pub fn get_asset(env: Env) -> String {
+        env.storage().instance().get(&ASSET).unwrap()
+    }
+
+    /// Change the skew fee rate. Each change may move the rate by at most a
+    /// quarter of its current value (or 1 bps from zero), at most once a
+    /// day, so fee shocks cannot be sprung on open positions.
+    pub fn set_skew_fee(env: Env, fee_bps: i128) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let current = skew_fee_bps(&env);
+        if fee_bps < 0 {
+            panic_with_error!(&env, ContractError::ParameterChangeTooLarge);
+        }
+        require_gradual_change(&env, current, fee_bps, SKEW_FEE_CHANGED);
+        env.storage().instance().set(&SKEW_FEE, &fee_bps);
+        env.events().publish((SKEW_FEE,), (current, fee_bps));
+    }
+
+    /// Half-spread in bps that fills pay on top of the index: buys fill
+    /// above it and sells below. What traders pay is credited to the fee
+    /// pool as each position settles. The exit spread applies to open
+    /// positions, so changes are limited as for `set_skew_fee`.
+    pub fn set_spread(env: Env, spread_bps: i128) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if !(0..=MAX_SPREAD_BPS).contains(&spread_bps) {
+            panic_with_error!(&env, ContractError::InvalidPrice);
+        }
+        let current: i128 = env.storage().instance().get(&SPREAD).unwrap_or(0);
+        require_gradual_change(&env, current, spread_bps, SPREAD_CHANGED);
+        env.storage().instance().set(&SPREAD, &spread_bps);
+    }
+
+    /// Floor for the skew-scaled leverage cap. While one side holds more
+    /// open interest, its maximum leverage falls in proportion to the
+    /// long/short ratio, down to `min_leverage`. Zero disables the cap.
+    pub fn set_skew_leverage(env: Env, min_leverage: u32) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let default: i128 = env.storage().instance().get(&LEVERAGE).unwrap();
+        if i128::from(min_leverage) > default {
+            panic_with_error!(&env, ContractError::InvalidLeverage);
+        }
+        env.storage().instance().set(&SKEW_LEVERAGE, &min_leverage);
+    }
+
+    /// Largest leverage a new trade on the given side may use right now
+    pub fn get_max_leverage(env: Env, long: bool) -> i128 {
+        max_leverage(&env, long)
+    }
+
+    /// Charge the skew fee on leveraged notional instead of posted margin
+    pub fn set_fee_on_notional(env: Env, on_notional: bool) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        env.storage().instance().set(&FEE_ON_NOTIONAL, &on_notional);
+    }
+
+    /// Require a price update newer than a position's open ledger before it
+    /// can be closed, so trades cannot round-trip on a single lagging price
+    pub fn set_require_fresh_close(env: Env, required: bool) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        env.storage().instance().set(&FRESH_CLOSE, &required);
+    }
+
+    /// Suspend `user` for `duration` seconds. With `open_only` only new
+    /// positions are refused; otherwise the account also may not bid on or
+    /// perform liquidations. Closing stays available either way.
+    pub fn suspend_account(env: Env, user: Address, open_only: bool, duration: u64) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if duration == 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        let suspension = Suspension { open_only, until: env.ledger().timestamp() + duration };
+        env.storage().persistent().set(&(SUSPENSION, user.clone()), &suspension);
+        let nonce = event_nonce(&env, &user);
+        env.events().publish((SUSPENSION, user), (suspension.open_only, suspension.until, nonce));
+    }
+
+    /// Lift a suspension before it expires
+    pub fn unsuspend_account(env: Env, user: Address) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        env.storage().persistent().remove(&(SUSPENSION, user.clone()));
+        let nonce = event_nonce(&env, &user);
+        env.events().publish((symbol_short!("UNSUSPEND"), user), (nonce,));
+    }
+
+    /// Suspension in force for `user`, if any
+    pub fn get_suspension(env: Env, user: Address) -> Option<Suspension> {
+        env.storage()
+            .persistent()
+            .get::<_, Suspension>(&(SUSPENSION, user))
+            .filter(|suspension| env.ledger().timestamp() < suspension.until)
+    }
+
+    /// Smallest margin in pUSD a new position may be opened with. Zero
+    /// turns the minimum off.
+    pub fn set_min_position_value(env: Env, min_value: i128) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if min_value < 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        env.storage().instance().set(&MIN_VALUE, &min_value);
+    }
+
+    /// Largest notional (margin × leverage) a single account may hold.
+    /// Zero turns the cap off.
+    pub fn set_max_position_notional(env: Env, cap: i128) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if cap < 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        env.storage().instance().set(&MAX_NOTIONAL, &cap);
+    }
+
+    /// Largest notional a single account may hold, zero when uncapped
+    pub fn get_max_position_notional(env: Env) -> i128 {
+        env.storage().instance().get(&MAX_NOTIONAL).unwrap_or(0)
+    }
+
+    /// Smallest margin a new position may be opened with
+    pub fn get_min_position_value(env: Env) -> i128 {
+        env.storage().instance().get(&MIN_VALUE).unwrap_or(0)
+    }
+
+    /// Maintenance margin in bps for a new position of the given notional
+    pub fn get_margin_req(env: Env, notional: i128) -> i128 {
+        maintenance_margin(&env, notional, margin_version(&env))
+    }
+
+    /// Place a new trade described by `request`
+    pub fn place_trade(env: Env, trader: Address, request: OpenRequest) {
+        trader.require_auth();
+        let value = request.value;
+        fill_request(&env, &trader, request, false);
+        record_call(&env, symbol_short!("place"), value, false);
+        check_invariants(&env);
+    }
+
+    /// Place a trade sized in base-asset units (7 decimals, so 1_000_000 is
+    /// 0.1 BTC). The margin pulled from the trader is the notional at the
+    /// current price divided by `leverage`, which may not exceed the default.
+    /// Returns the margin charged.
+    pub fn place_trade_units(env: Env, trader: Address, order: UnitsOrder) -> i128 {
+        trader.require_auth();
+        let asset: String = env.storage().instance().get(&ASSET).unwrap();
+        if order.asset != asset {
+            panic_with_error!(&env, ContractError::AssetMismatch);
+        }
+        let leverage = i128::from(order.leverage);
+        if leverage < 1 || leverage > max_leverage(&env, order.long) {
+            panic_with_error!(&env, ContractError::InvalidLeverage);
+        }
+        if order.base_units <= 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        let price = execution_price(&env, fresh_price(&env), order.long);
+        packed_price(&env, price);
+        let value = div_ceil(order.base_units * price, BASE_UNIT * leverage);
+        open_position(&env, &trader, value, order.long, leverage, false);
+        record_call(&env, symbol_short!("placeunit"), value, false);
+        check_invariants(&env);
+        value
+    }
+
+    /// Calculate fee for a trade at the default leverage. Only the part of
+    /// the trade that leaves its own side heavier than the other after the
+    /// trade is charged, on the margin or, in notional mode, on
+    /// margin × leverage.
+    pub fn calculate_fee(env: &Env, value: i128, long: bool) -> i128 {
+        let leverage: i128 = env.storage().instance().get(&LEVERAGE).unwrap();
+        skew_fee(env, value, long, leverage)
+    }
+
+    /// Worst-case execution for a trade of `value` margin at `leverage`:
+    /// the fill price, the fee, the resulting size and where it would be
+    /// liquidated. The price allows for one more oracle update landing
+    /// first, moved by up to the circuit breaker limit and widened by the
+    /// widest confidence any oracle has reported.
+    pub fn quote_worst_case(env: Env, value: i128, long: bool, leverage: u32) -> TradeQuote {
+        if leverage < 1 || i128::from(leverage) > max_leverage(&env, long) {
+            panic_with_error!(&env, ContractError::InvalidLeverage);
+        }
+        if value <= 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        let price = worst_execution_price(&env, fresh_price(&env), long);
+        let fee = skew_fee(&env, value, long, i128::from(leverage));
+        let mut position = Position::new(value - fee, packed_price(&env, price), env.ledger().sequence(), leverage, long);
+        position.margin_version = margin_version(&env);
+        TradeQuote {
+            price,
+            fee,
+            size_base: position.size_base,
+            liquidation_price: liquidation_price(&env, &position),
+        }
+    }
+
+    /// Current skew and the fee rates in force for each direction.
+    /// `imbalance_bps` is positive when longs dominate.
+    pub fn get_skew(env: Env) -> SkewInfo {
+        let long_oi: i128 = env.storage().instance().get(&LONG_POS).unwrap_or(0_i128);
+        let short_oi: i128 = env.storage().instance().get(&SHORT_POS).unwrap_or(0_i128);
+        let total = long_oi + short_oi;
+        let imbalance_bps = if total > 0 { ((long_oi - short_oi) * 10000) / total } else { 0 };
+        // A marginal trade pays unless its side is currently the lighter one
+        let fee_bps = skew_fee_bps(&env);
+        let long_fee_bps = if long_oi >= short_oi { fee_bps } else { 0 };
+        let short_fee_bps = if short_oi >= long_oi { fee_bps } else { 0 };
+        let fee_on_notional: bool = env.storage().instance().get(&FEE_ON_NOTIONAL).unwrap_or(false);
+        SkewInfo { long_oi, short_oi, imbalance_bps, long_fee_bps, short_fee_bps, fee_on_notional }
+    }
+
+    /// Calculate current position value at the mark price
+    pub fn calculate_position(env: &Env, user: Address) -> i128 {
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| Map::new(env));
+        let position = match positions.get(user.clone()) {
+            Some(p) => p,
+            None => return 0,
+        };
+        let price = mark_price(env, current_price(env));
+        position_value_at(&position, price).max(0)
+    }
+
+    /// Position snapshot with liquidation price and risk tier for front-ends
+    pub fn get_position_details(env: Env, user: Address) -> PositionDetails {
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| Map::new(&env));
+        let position = positions
+            .get(user.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let current_value = Self::calculate_position(&env, user);
+        let price = mark_price(&env, current_price(&env));
+        let liquidation_price = liquidation_price(&env, &position);
+
+        // Distance from the current price to liquidation, in bps of the current price
+        let mut distance_bps: i128 = 0;
+        if price > 0 {
+            let gap = if position.is_long() {
+                price - liquidation_price
+            } else {
+                liquidation_price - price
+            };
+            distance_bps = (gap * 10000 / price).max(0);
+        }
+        let risk_tier = if distance_bps >= WARNING_DISTANCE_BPS {
+            RiskTier::Safe
+        } else if distance_bps >= DANGER_DISTANCE_BPS {
+            RiskTier::Warning
+        } else {
+            RiskTier::Danger
+        };
+
+        PositionDetails { position, current_value, liquidation_price, distance_bps, risk_tier }
+    }
+
+    /// Convert `amount` in `token`'s decimals to the contract's internal
+    /// precision (the pUSD token's), with the same truncation as incoming
+    /// oracle prices
+    pub fn to_internal(env: Env, amount: i128, token: Address) -> i128 {
+        let internal: u32 = env.storage().instance().get(&TOKEN_DECIMALS).unwrap();
+        rescale(amount, token_decimals(&env, &token), internal)
+    }
+
+    /// Convert an internal `amount` to `token`'s decimals, truncating
+    /// towards zero when `token` has fewer decimals
+    pub fn from_internal(env: Env, amount: i128, token: Address) -> i128 {
+        let internal: u32 = env.storage().instance().get(&TOKEN_DECIMALS).unwrap();
+        rescale(amount, internal, token_decimals(&env, &token))
+    }
 
-        // Update totals and remove position
-        let mut total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap();
-        let mut total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap();
-        if position.long {
-            total_long -= position.value;
-            env.storage().instance().set(&LONG_POS, &total_long);
+    /// Wallet balance, allowance, open position, last margin call and
+    /// suspension of `user` in one call
+    pub fn get_account(env: Env, user: Address) -> AccountSnapshot {
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        let token = TokenClient::new(&env, &p_usd);
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| Map::new(&env));
+        let position = if positions.contains_key(user.clone()) {
+            Some(Self::get_position_details(env.clone(), user.clone()))
         } else {
-            total_short -= position.value;
-            env.storage().instance().set(&SHORT_POS, &total_short);
+            None
+        };
+        AccountSnapshot {
+            balance: token.balance(&user),
+            allowance: token.allowance(&user, &env.current_contract_address()),
+            position,
+            margin_call: Self::get_margin_call(env.clone(), user.clone()),
+            suspension: Self::get_suspension(env.clone(), user),
         }
-        positions.remove(user.clone());
+    }
+
+    /// Permissionless margin call. If the position is inside the warning
+    /// band, record the time and emit an `MCALL` event, at most once per
+    /// cooldown. Returns whether an event was emitted.
+    pub fn flag_margin_call(env: Env, user: Address) -> bool {
+        let details = Self::get_position_details(env.clone(), user.clone());
+        if details.risk_tier == RiskTier::Safe {
+            record_call(&env, symbol_short!("mcall"), 0, true);
+            return false;
+        }
+        let key = (MARGIN_CALL, user.clone());
+        let now = env.ledger().timestamp();
+        if let Some(flagged_at) = env.storage().persistent().get::<_, u64>(&key) {
+            if now < flagged_at + MARGIN_CALL_COOLDOWN {
+                record_call(&env, symbol_short!("mcall"), 0, true);
+                return false;
+            }
+        }
+        env.storage().persistent().set(&key, &now);
+        let nonce = event_nonce(&env, &user);
+        env.events().publish(
+            (MARGIN_CALL, user),
+            (details.current_value, details.liquidation_price, details.risk_tier, details.position.id, nonce),
+        );
+        record_call(&env, symbol_short!("mcall"), 0, false);
+        true
+    }
+
+    /// Usage counters per entrypoint: `place`, `placeunit`, `fill`,
+    /// `close`, `addmargin`, `increase`, `stop`, `takeprof`, `emergency`,
+    /// `liquidate` and `mcall`
+    pub fn get_metrics(env: Env) -> Map<Symbol, EntrypointMetrics> {
+        env.storage().instance().get(&METRICS).unwrap_or_else(|| Map::new(&env))
+    }
+
+    /// Time of the last margin call on an open position, if any
+    pub fn get_margin_call(env: Env, user: Address) -> Option<u64> {
+        env.storage().persistent().get(&(MARGIN_CALL, user))
+    }
+
+    /// Read-only report of what open positions would look like at `new_price`
+    pub fn simulate_price_shock(env: Env, new_price: i128) -> ShockReport {
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| Map::new(&env));
+        let mut report = ShockReport {
+            price: new_price,
+            liquidatable: 0,
+            notional_affected: 0,
+            insurance_draw: 0,
+            vault_pnl: 0,
+        };
+        for (_, position) in positions.iter() {
+            let equity = position_value_at(&position, new_price);
+            let margin_req = liquidation_margin(&env, &position);
+            let required_val = div_ceil(position.value * margin_req, 10000);
+            if equity < required_val {
+                report.liquidatable += 1;
+                report.notional_affected += position.notional();
+            }
+            // Losses beyond the posted margin are bad debt
+            if equity < 0 {
+                report.insurance_draw -= equity;
+            }
+            report.vault_pnl += position.value - equity.max(0);
+        }
+        report
+    }
+
+    /// Post `amount` more pUSD as margin on the trader's open position.
+    /// Entry price and size are unchanged, so the liquidation price moves
+    /// away from the market. Returns the new margin.
+    pub fn add_margin(env: Env, trader: Address, amount: i128) -> i128 {
+        trader.require_auth();
+        if amount <= 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        let mut positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let mut position = positions
+            .get(trader.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).transfer_from(
+            &env.current_contract_address(),
+            &trader,
+            &env.current_contract_address(),
+            &amount,
+        );
+        position.value += amount;
+        positions.set(trader.clone(), position.clone());
         env.storage().persistent().set(&POSITIONS, &positions);
 
-        // Reward liquidator
-        let reward = ret_bal / 3;
-        if reward > 0 {
-            let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
-            TokenClient::new(&env, &p_usd).transfer(
-                &env.current_contract_address(),
-                &liquidator,
-                &reward,
-            );
-            
+        let total_key = if position.is_long() { LONG_POS } else { SHORT_POS };
+        let total: i128 = env.storage().instance().get(&total_key).unwrap();
+        env.storage().instance().set(&total_key, &(total + amount));
+
+        env.storage().persistent().remove(&(LIQ_SINCE, trader.clone()));
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish((symbol_short!("ADDMARGIN"), trader), (amount, position.value, position.id, nonce));
+        record_call(&env, symbol_short!("addmargin"), amount, false);
+        check_invariants(&env);
+        position.value
+    }
+
+    /// Add `additional_value` of margin to the trader's position at its
+    /// leverage and the current price, paying the skew fee as on open. The
+    /// entry price becomes the size-weighted average of the old and new
+    /// entries, rounded against the trader.
+    pub fn increase_position(env: Env, trader: Address, additional_value: i128) {
+        trader.require_auth();
+        require_trading(&env);
+        require_not_suspended(&env, &trader, true);
+        let mut positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let mut position = positions
+            .get(trader.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let long = position.is_long();
+        let leverage = position.leverage();
+        if leverage > max_leverage(&env, long) {
+            panic_with_error!(&env, ContractError::InvalidLeverage);
+        }
+        require_within_cap(&env, position.notional() + additional_value * leverage);
+        let (remaining, balancing) = take_margin(&env, &trader, additional_value, long, leverage, false);
+        let value = position.value + remaining;
+        let notional = position.notional() + remaining * leverage;
+        if maintenance_margin(&env, notional, margin_version(&env)) * leverage >= 10000 {
+            panic_with_error!(&env, ContractError::InsufficientMargin);
+        }
+
+        let index = fresh_price(&env);
+        let price = execution_price(&env, index, long);
+        let added = Position::new(remaining, packed_price(&env, price), env.ledger().sequence(), position.leverage, long);
+        if added.size_base == 0 {
+            panic_with_error!(&env, ContractError::PositionTooSmallForPrice);
+        }
+        position.entry_spread += div_floor(added.size_base * spread_at(&env, index), BASE_UNIT);
+        let size_base = position.size_base + added.size_base;
+        let weighted = position.size_base * position.open_price() + added.size_base * added.open_price();
+        let open_price = if long { div_ceil(weighted, size_base) } else { div_floor(weighted, size_base) };
+        position.value = value;
+        position.size_base = size_base;
+        position.open_price = packed_price(&env, open_price);
+        // Restart the fresh-close clock for the added size
+        position.opened_at_ledger = env.ledger().sequence();
+        // A modified position moves onto the current margin terms
+        position.margin_version = margin_version(&env);
+        positions.set(trader.clone(), position.clone());
+        env.storage().persistent().set(&POSITIONS, &positions);
+        accrue_incentive(&env, position.id, balancing);
+
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish(
+            (symbol_short!("INCREASE"), trader),
+            (additional_value, position.value, position.open_price, position.id, nonce),
+        );
+        record_call(&env, symbol_short!("increase"), additional_value, false);
+        check_invariants(&env);
+    }
+
+    /// Hand the trader's position to `to`, who must not hold one and must
+    /// sign to accept it. Stops, take-profits, the lifetime and any
+    /// operator approval set by the old owner are cleared; a margin call
+    /// flag or liquidatable mark moves with the position. Returns the
+    /// position id.
+    pub fn transfer_position(env: Env, from: Address, to: Address) -> u64 {
+        from.require_auth();
+        to.require_auth();
+        move_position(&env, &from, &to)
+    }
+
+    /// Let `operator` transfer the trader's current position with
+    /// `transfer_position_from`, or revoke it with `None`. The approval
+    /// lapses once the position changes hands or closes. Returns the
+    /// position id.
+    pub fn approve_position(env: Env, trader: Address, operator: Option<Address>) -> u64 {
+        trader.require_auth();
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let position = positions
+            .get(trader.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let key = (POS_APPROVE, position.id);
+        match &operator {
+            Some(operator) => env.storage().persistent().set(&key, operator),
+            None => env.storage().persistent().remove(&key),
         }
-        env.events().publish((symbol_short!("LIQ"),), (user, liquidator, ret_bal));
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish((symbol_short!("APPROVE"), trader), (operator, position.id, nonce));
+        position.id
+    }
+
+    /// Operator approved to transfer open position `position_id`, if any
+    pub fn get_position_approval(env: Env, position_id: u64) -> Option<Address> {
+        env.storage().persistent().get(&(POS_APPROVE, position_id))
+    }
+
+    /// Transfer `from`'s position to `to` as its approved operator. `to`
+    /// must sign to accept it, as with `transfer_position`. Returns the
+    /// position id.
+    pub fn transfer_position_from(env: Env, operator: Address, from: Address, to: Address) -> u64 {
+        operator.require_auth();
+        to.require_auth();
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let position = positions
+            .get(from.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let approved: Option<Address> = env.storage().persistent().get(&(POS_APPROVE, position.id));
+        if approved != Some(operator) {
+            panic_with_error!(&env, ContractError::NotApproved);
+        }
+        move_position(&env, &from, &to)
+    }
+
+    /// Current owner of open position `position_id`, if any
+    pub fn get_position_owner(env: Env, position_id: u64) -> Option<Address> {
+        env.storage().persistent().get(&(POS_OWNER, position_id))
+    }
+
+    /// Default lifetime in ledgers after which any keeper may close a
+    /// position with `close_abandoned`. Zero turns the default off.
+    pub fn set_max_lifetime(env: Env, ledgers: u32) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        env.storage().instance().set(&MAX_LIFETIME, &ledgers);
+    }
+
+    /// Default position lifetime in ledgers, zero when off
+    pub fn get_max_lifetime(env: Env) -> u32 {
+        env.storage().instance().get(&MAX_LIFETIME).unwrap_or(0)
+    }
+
+    /// Set a lifetime in ledgers for the trader's position, or clear it
+    /// with `None`. The market default still applies if it is shorter.
+    /// Returns the position id.
+    pub fn set_position_lifetime(env: Env, trader: Address, ledgers: Option<u32>) -> u64 {
+        trader.require_auth();
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let position = positions
+            .get(trader.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let key = (POS_LIFETIME, position.id);
+        match ledgers {
+            Some(0) => panic_with_error!(&env, ContractError::ZeroValue),
+            Some(ledgers) => env.storage().persistent().set(&key, &ledgers),
+            None => env.storage().persistent().remove(&key),
+        }
+        position.id
+    }
+
+    /// Ledger after which `user`'s position can be closed as abandoned, if
+    /// it has a lifetime
+    pub fn get_position_expiry(env: Env, user: Address) -> Option<u32> {
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let position = positions
+            .get(user)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        position_expiry(&env, &position)
+    }
+
+    /// Close `user`'s position at market once its lifetime has run out,
+    /// paying the trader as a normal close would. The keeper earns the
+    /// keeper rebate if one is due. Returns the rebate paid.
+    pub fn close_abandoned(env: Env, keeper: Address, user: Address) -> i128 {
+        keeper.require_auth();
+        require_not_suspended(&env, &keeper, false);
+        let expiry = Self::get_position_expiry(env.clone(), user.clone());
+        if !matches!(expiry, Some(expiry) if env.ledger().sequence() >= expiry) {
+            panic_with_error!(&env, ContractError::LifetimeNotReached);
+        }
+
+        let (price, spread) = exit_price(&env, &user, fresh_price(&env));
+        let (closed, ret_bal) = settle_position(&env, &user, price, spread);
+        cover_shortfall(&env, ret_bal);
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).transfer(&env.current_contract_address(), &user, &ret_bal);
+        let nonce = event_nonce(&env, &user);
+        env.events().publish((symbol_short!("DEADMAN"), user), (keeper.clone(), price, ret_bal, closed.id, nonce));
+        let rebate = pay_keeper_rebate(&env, &keeper);
+        check_invariants(&env);
+        rebate
+    }
+
+    /// Register a stop-loss at `trigger_price` on the trader's position, or
+    /// cancel it with `None`. The trigger must be below the current price
+    /// for a long and above it for a short. Returns the position id.
+    pub fn set_stop_loss(env: Env, trader: Address, trigger_price: Option<i128>) -> u64 {
+        trader.require_auth();
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let position = positions
+            .get(trader.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let key = (STOP_LOSS, position.id);
+        clear_trigger(&env, &STOP_LOSS, position.id);
+        let Some(trigger_price) = trigger_price else {
+            let nonce = event_nonce(&env, &trader);
+            env.events().publish((symbol_short!("STOPSET"), trader), (0_i128, position.id, nonce));
+            return position.id;
+        };
+        let price = fresh_price(&env);
+        let wrong_side = if position.is_long() { trigger_price >= price } else { trigger_price <= price };
+        if trigger_price <= 0 || wrong_side {
+            panic_with_error!(&env, ContractError::InvalidPrice);
+        }
+        env.storage().persistent().set(&key, &StopOrder { trader: trader.clone(), trigger_price });
+        index_trigger(&env, &STOP_LOSS, position.id, trigger_price);
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish((symbol_short!("STOPSET"), trader), (trigger_price, position.id, nonce));
+        position.id
+    }
+
+    /// Stop-loss registered on position `position_id`, if any
+    pub fn get_stop_loss(env: Env, position_id: u64) -> Option<StopOrder> {
+        env.storage().persistent().get(&(STOP_LOSS, position_id))
+    }
+
+    /// Close position `position_id` once the index has crossed its
+    /// stop-loss trigger. The keeper earns `STOP_BOUNTY_BPS` of the payout
+    /// and the keeper rebate if one is due, and the trader receives the
+    /// rest. Returns what the keeper was paid.
+    pub fn execute_stop(env: Env, keeper: Address, position_id: u64) -> i128 {
+        keeper.require_auth();
+        require_not_suspended(&env, &keeper, false);
+        let order: StopOrder = env
+            .storage()
+            .persistent()
+            .get(&(STOP_LOSS, position_id))
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let index = fresh_price(&env);
+        if !trigger_reached(&env, &STOP_LOSS, &order, index) {
+            panic_with_error!(&env, ContractError::TriggerNotReached);
+        }
+        let bounty = fill_stop(&env, &keeper, position_id, order, index);
+        let rebate = pay_keeper_rebate(&env, &keeper);
+        check_invariants(&env);
+        bounty + rebate
+    }
+
+    /// Register a take-profit at `trigger_price` on the trader's position,
+    /// or cancel it with `None`. The trigger must be above the current price
+    /// for a long and below it for a short. Returns the position id.
+    pub fn set_take_profit(env: Env, trader: Address, trigger_price: Option<i128>) -> u64 {
+        trader.require_auth();
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let position = positions
+            .get(trader.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let key = (TAKE_PROFIT, position.id);
+        clear_trigger(&env, &TAKE_PROFIT, position.id);
+        let Some(trigger_price) = trigger_price else {
+            let nonce = event_nonce(&env, &trader);
+            env.events().publish((symbol_short!("TPSET"), trader), (0_i128, position.id, nonce));
+            return position.id;
+        };
+        let price = fresh_price(&env);
+        let wrong_side = if position.is_long() { trigger_price <= price } else { trigger_price >= price };
+        if trigger_price <= 0 || wrong_side {
+            panic_with_error!(&env, ContractError::InvalidPrice);
+        }
+        env.storage().persistent().set(&key, &StopOrder { trader: trader.clone(), trigger_price });
+        index_trigger(&env, &TAKE_PROFIT, position.id, trigger_price);
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish((symbol_short!("TPSET"), trader), (trigger_price, position.id, nonce));
+        position.id
+    }
+
+    /// Take-profit registered on position `position_id`, if any
+    pub fn get_take_profit(env: Env, position_id: u64) -> Option<StopOrder> {
+        env.storage().persistent().get(&(TAKE_PROFIT, position_id))
+    }
+
+    /// Close position `position_id` once the index has reached its
+    /// take-profit trigger. `TAKE_PROFIT_FEE_BPS` of the payout is split
+    /// between the keeper and the insurance fund and the trader receives
+    /// the rest. The keeper also earns the keeper rebate if one is due.
+    /// Returns what the keeper was paid.
+    pub fn execute_take_profit(env: Env, keeper: Address, position_id: u64) -> i128 {
+        keeper.require_auth();
+        require_not_suspended(&env, &keeper, false);
+        let order: StopOrder = env
+            .storage()
+            .persistent()
+            .get(&(TAKE_PROFIT, position_id))
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let index = fresh_price(&env);
+        if !trigger_reached(&env, &TAKE_PROFIT, &order, index) {
+            panic_with_error!(&env, ContractError::TriggerNotReached);
+        }
+        let bounty = fill_take_profit(&env, &keeper, position_id, order, index);
+        let rebate = pay_keeper_rebate(&env, &keeper);
+        check_invariants(&env);
+        bounty + rebate
+    }
+
+    /// Execute the stop-losses and take-profits with triggers between
+    /// `price_from` and `price_to` that the index has crossed, reading at
+    /// most `limit` of them. Triggers are stored by price band, so a keeper
+    /// only reads the bands an oracle update moved through. The keeper earns
+    /// each order's bounty or fee share, and the keeper rebate once if any
+    /// order was executed. Returns what the keeper was paid.
+    pub fn execute_triggers_in_range(env: Env, keeper: Address, price_from: i128, price_to: i128, limit: u32) -> i128 {
+        keeper.require_auth();
+        require_not_suspended(&env, &keeper, false);
+        if price_from <= 0 || price_to < price_from {
+            panic_with_error!(&env, ContractError::InvalidPrice);
+        }
+        let (first, last) = (trigger_band(price_from), trigger_band(price_to));
+        if last - first >= MAX_TRIGGER_BANDS {
+            panic_with_error!(&env, ContractError::InvalidPrice);
+        }
+        let index = fresh_price(&env);
+        let mut read: u32 = 0;
+        let mut executed: u32 = 0;
+        let mut paid: i128 = 0;
+        'bands: for band in first..=last {
+            let entries: Vec<(Symbol, u64)> =
+                env.storage().persistent().get(&(TRIGGERS, band)).unwrap_or_else(|| Vec::new(&env));
+            for (kind, position_id) in entries.iter() {
+                if read == limit {
+                    break 'bands;
+                }
+                read += 1;
+                // Closing a position drops its other order along the way
+                let Some(order) = env.storage().persistent().get::<_, StopOrder>(&(kind.clone(), position_id)) else {
+                    continue;
+                };
+                if order.trigger_price < price_from
+                    || order.trigger_price > price_to
+                    || !trigger_reached(&env, &kind, &order, index)
+                {
+                    continue;
+                }
+                paid += if kind == STOP_LOSS {
+                    fill_stop(&env, &keeper, position_id, order, index)
+                } else {
+                    fill_take_profit(&env, &keeper, position_id, order, index)
+                };
+                executed += 1;
+            }
+        }
+        if executed > 0 {
+            paid += pay_keeper_rebate(&env, &keeper);
+        }
+        check_invariants(&env);
+        paid
+    }
+
+    /// Close the trader's position and open the opposite side with
+    /// `new_value` of margin at the same leverage and oracle price. The
+    /// payout funds the new margin; only the difference moves between the
+    /// trader and the contract. Returns the payout of the closed position.
+    pub fn flip_position(env: Env, trader: Address, new_value: i128) -> i128 {
+        trader.require_auth();
+        if new_value <= 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        let (closed, ret_bal) = close_position(&env, &trader);
+        let long = !closed.is_long();
+        let leverage = closed.leverage();
+        if leverage > max_leverage(&env, long) {
+            panic_with_error!(&env, ContractError::InvalidLeverage);
+        }
+
+        cover_shortfall(&env, (ret_bal - new_value).max(0));
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        let token = TokenClient::new(&env, &p_usd);
+        if ret_bal > new_value {
+            token.transfer(&env.current_contract_address(), &trader, &(ret_bal - new_value));
+        } else if ret_bal < new_value {
+            token.transfer_from(
+                &env.current_contract_address(),
+                &trader,
+                &env.current_contract_address(),
+                &(new_value - ret_bal),
+            );
+        }
+        open_position(&env, &trader, new_value, long, leverage, true);
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish((symbol_short!("FLIP"), trader), (ret_bal, new_value, long, closed.id, nonce));
+        check_invariants(&env);
+        ret_bal
+    }
+
+    /// Close an open trade, paying out to `recipient` if given. Fails with
+    /// `IntentExpired` after `valid_until_ledger`.
+    pub fn close_trade(env: Env, trader: Address, recipient: Option<Address>, valid_until_ledger: Option<u32>) {
+        trader.require_auth();
+        require_unexpired(&env, valid_until_ledger);
+        let (closed, ret_bal) = close_position(&env, &trader);
+
+        // Payout
+        cover_shortfall(&env, ret_bal);
+        let recipient = recipient.unwrap_or(trader.clone());
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).transfer(
+            &env.current_contract_address(),
+            &recipient,
+            &ret_bal,
+        );
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish(
+            (symbol_short!("CLOSE"), trader),
+            (recipient, closed.close_price(), ret_bal, closed.id, nonce),
+        );
+        record_call(&env, symbol_short!("close"), ret_bal, false);
+        check_invariants(&env);
+    }
+
+    /// Close an open trade and swap the payout into a whitelisted `asset`
+    /// through the swap router, failing unless the trader receives at least
+    /// `min_out`. Returns the amount of `asset` received.
+    pub fn close_trade_for_asset(env: Env, trader: Address, asset: Address, min_out: i128) -> i128 {
+        trader.require_auth();
+        let allowed: Map<Address, bool> = env
+            .storage()
+            .instance()
+            .get(&PAYOUT_ASSETS)
+            .unwrap_or_else(|| Map::new(&env));
+        let router: Address = env
+            .storage()
+            .instance()
+            .get(&SWAP_ROUTER)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::AssetNotAllowed));
+        if !allowed.get(asset.clone()).unwrap_or(false) {
+            panic_with_error!(&env, ContractError::AssetNotAllowed);
+        }
+        if min_out <= 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        let (closed, ret_bal) = close_position(&env, &trader);
+
+        // The router pulls the payout through an allowance; the amount out
+        // is measured from the trader's balance rather than trusted
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        let out_token = TokenClient::new(&env, &asset);
+        let before = out_token.balance(&trader);
+        cover_shortfall(&env, ret_bal);
+        if ret_bal > 0 {
+            TokenClient::new(&env, &p_usd).approve(
+                &env.current_contract_address(),
+                &router,
+                &ret_bal,
+                &env.ledger().sequence(),
+            );
+            SwapRouterClient::new(&env, &router).swap_exact_in(
+                &env.current_contract_address(),
+                &p_usd,
+                &asset,
+                &ret_bal,
+                &min_out,
+                &trader,
+            );
+        }
+        let received = out_token.balance(&trader) - before;
+        if received < min_out {
+            panic_with_error!(&env, ContractError::SlippageExceeded);
+        }
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish((symbol_short!("SWAPOUT"), trader), (ret_bal, asset, received, closed.id, nonce));
+        check_invariants(&env);
+        received
+    }
+
+    /// Place a trade funded with `amount_in` of a whitelisted `asset`,
+    /// swapped to pUSD through the swap router. The pUSD received, which
+    /// must be at least `min_out`, replaces `request.value` as the margin.
+    /// Returns the pUSD received.
+    pub fn place_trade_with_asset(
+        env: Env,
+        trader: Address,
+        asset: Address,
+        amount_in: i128,
+        min_out: i128,
+        request: OpenRequest,
+    ) -> i128 {
+        trader.require_auth();
+        let allowed: Map<Address, bool> = env
+            .storage()
+            .instance()
+            .get(&COLLATERAL_ASSETS)
+            .unwrap_or_else(|| Map::new(&env));
+        let router: Address = env
+            .storage()
+            .instance()
+            .get(&SWAP_ROUTER)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::AssetNotAllowed));
+        if !allowed.get(asset.clone()).unwrap_or(false) {
+            panic_with_error!(&env, ContractError::AssetNotAllowed);
+        }
+        if amount_in <= 0 || min_out <= 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+
+        // Pull the asset in and let the router take it through an
+        // allowance; the pUSD received is measured rather than trusted
+        let in_token = TokenClient::new(&env, &asset);
+        in_token.transfer_from(&env.current_contract_address(), &trader, &env.current_contract_address(), &amount_in);
+        in_token.approve(&env.current_contract_address(), &router, &amount_in, &env.ledger().sequence());
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        let margin_token = TokenClient::new(&env, &p_usd);
+        let before = margin_token.balance(&env.current_contract_address());
+        SwapRouterClient::new(&env, &router).swap_exact_in(
+            &env.current_contract_address(),
+            &asset,
+            &p_usd,
+            &amount_in,
+            &min_out,
+            &env.current_contract_address(),
+        );
+        let received = margin_token.balance(&env.current_contract_address()) - before;
+        if received < min_out {
+            panic_with_error!(&env, ContractError::SlippageExceeded);
+        }
+
+        fill_request(&env, &trader, OpenRequest { value: received, ..request }, true);
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish((symbol_short!("SWAPIN"), trader), (asset, amount_in, received, nonce));
+        check_invariants(&env);
+        received
+    }
+
+    /// Allow or disallow an asset as collateral for `place_trade_with_asset`
+    pub fn set_collateral_asset(env: Env, asset: Address, allowed: bool) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let mut assets: Map<Address, bool> = env
+            .storage()
+            .instance()
+            .get(&COLLATERAL_ASSETS)
+            .unwrap_or_else(|| Map::new(&env));
+        assets.set(asset, allowed);
+        env.storage().instance().set(&COLLATERAL_ASSETS, &assets);
+    }
+
+    /// Router used by `close_trade_for_asset` and `place_trade_with_asset`
+    pub fn set_swap_router(env: Env, router: Address) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        env.storage().instance().set(&SWAP_ROUTER, &router);
+    }
+
+    /// Allow or disallow an asset as a payout target for `close_trade_for_asset`
+    pub fn set_payout_asset(env: Env, asset: Address, allowed: bool) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let mut assets: Map<Address, bool> = env
+            .storage()
+            .instance()
+            .get(&PAYOUT_ASSETS)
+            .unwrap_or_else(|| Map::new(&env));
+        assets.set(asset, allowed);
+        env.storage().instance().set(&PAYOUT_ASSETS, &assets);
+    }
+
+    /// Close at the fresh price with a penalty paid to the insurance fund.
+    /// Available to the position owner as a last-resort exit, except
+    /// during a settlement dispute window. Price checks are the same as
+    /// for `close_trade`.
+    pub fn emergency_close(env: Env, trader: Address) {
+        trader.require_auth();
+        let (closed, ret_bal) = close_position(&env, &trader);
+        let penalty = div_ceil(ret_bal * EMERGENCY_PENALTY_BPS, 10000);
+        let payout = ret_bal - penalty;
+
+        let insurance: i128 = env.storage().instance().get(&INSURANCE).unwrap_or(0_i128);
+        env.storage().instance().set(&INSURANCE, &(insurance + penalty));
+
+        cover_shortfall(&env, payout);
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).transfer(
+            &env.current_contract_address(),
+            &trader,
+            &payout,
+        );
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish((symbol_short!("EMERG"),), (trader, payout, penalty, closed.id, nonce));
+        record_call(&env, symbol_short!("emergency"), ret_bal, false);
+        check_invariants(&env);
+    }
+
+    /// Commit to tip `tip_bps` of the liquidation reward for `user` to the
+    /// insurance fund. The best standing bid gets an exclusive window.
+    pub fn commit_liquidation_bid(env: Env, liquidator: Address, user: Address, tip_bps: u32) {
+        liquidator.require_auth();
+        require_not_suspended(&env, &liquidator, false);
+        if tip_bps > MAX_TIP_BPS {
+            panic_with_error!(&env, ContractError::InvalidTip);
+        }
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| Map::new(&env));
+        let position = positions
+            .get(user.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        if liquidation_value(&env, &position).is_none() {
+            panic_with_error!(&env, ContractError::AboveMargin);
+        }
+        let key = (LIQ_BID, user.clone());
+        let mut start_ledger = env.ledger().sequence();
+        if let Some(bid) = env.storage().temporary().get::<_, LiquidationBid>(&key) {
+            if start_ledger < bid.start_ledger + LIQ_WINDOW_LEDGERS {
+                if tip_bps <= bid.tip_bps {
+                    panic_with_error!(&env, ContractError::InvalidTip);
+                }
+                start_ledger = bid.start_ledger;
+            }
+        }
+        let bid = LiquidationBid { liquidator: liquidator.clone(), tip_bps, start_ledger };
+        env.storage().temporary().set(&key, &bid);
+        let nonce = event_nonce(&env, &user);
+        env.events().publish((symbol_short!("LIQBID"),), (user, liquidator, tip_bps, nonce));
+    }
+
+    /// Enter settlement mode for a delisting or incident wind-down. The
+    /// current oracle price is proposed as the settlement price and new
+    /// trades are refused from here on. Positions can only be settled once
+    /// the dispute window has passed.
+    pub fn start_settlement(env: Env) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        begin_settlement(&env);
+    }
+
+    /// Turn the market into a dated future expiring at `expiry` (a ledger
+    /// timestamp), or back into a perpetual with `None`. The expiry must
+    /// be in the future and cannot be changed once it has passed.
+    pub fn set_expiry(env: Env, expiry: Option<u64>) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let now = env.ledger().timestamp();
+        let current: Option<u64> = env.storage().instance().get(&EXPIRY);
+        if current.is_some_and(|current| current <= now) || expiry.is_some_and(|expiry| expiry <= now) {
+            panic_with_error!(&env, ContractError::InvalidExpiry);
+        }
+        match expiry {
+            Some(expiry) => env.storage().instance().set(&EXPIRY, &expiry),
+            None => env.storage().instance().remove(&EXPIRY),
+        }
+    }
+
+    /// Expiry timestamp of a dated market, `None` for a perpetual
+    pub fn get_expiry(env: Env) -> Option<u64> {
+        env.storage().instance().get(&EXPIRY)
+    }
+
+    /// Permissionless: once the market has expired, capture the current
+    /// oracle price as the settlement price and enter settlement mode
+    pub fn expire_market(env: Env) {
+        if !is_expired(&env) {
+            panic_with_error!(&env, ContractError::NotExpired);
+        }
+        begin_settlement(&env);
+    }
+
+    /// Permissionless close of `trader`'s position at the settlement price
+    /// of an expired market, once the dispute window has passed. The
+    /// payout always goes to the trader. Returns the payout.
+    pub fn settle_expired(env: Env, trader: Address) -> i128 {
+        if !is_expired(&env) {
+            panic_with_error!(&env, ContractError::NotExpired);
+        }
+        let price: i128 = env
+            .storage()
+            .instance()
+            .get(&SETTLE_PRICE)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::NotSettling));
+        let (closed, ret_bal) = settle_position(&env, &trader, price, 0);
+
+        cover_shortfall(&env, ret_bal);
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).transfer(&env.current_contract_address(), &trader, &ret_bal);
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish((symbol_short!("EXPIRED"), trader), (price, ret_bal, closed.id, nonce));
+        check_invariants(&env);
+        ret_bal
+    }
+
+    /// Vote for a different settlement price during the dispute window.
+    /// Once the oracle quorum has voted, the median of the votes replaces
+    /// the proposed price.
+    pub fn dispute_settlement(env: Env, oracle: Address, price: i128) {
+        oracle.require_auth();
+        if !market_route(&env).oracles.get(oracle.clone()).unwrap_or(false) {
+            panic_with_error!(&env, ContractError::NotOracle);
+        }
+        let final_at: u64 = env
+            .storage()
+            .instance()
+            .get(&SETTLE_FINAL)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::NotSettling));
+        if env.ledger().timestamp() >= final_at {
+            panic_with_error!(&env, ContractError::DisputeWindowClosed);
+        }
+        let price = normalize_price(&env, price);
+        require_in_bounds(&env, price);
+        packed_price(&env, price);
+
+        let mut votes: Map<Address, i128> = env
+            .storage()
+            .instance()
+            .get(&SETTLE_VOTES)
+            .unwrap_or_else(|| Map::new(&env));
+        votes.set(oracle.clone(), price);
+        env.events().publish((symbol_short!("DISPUTE"), oracle), price);
+        let quorum = oracle_quorum(&env);
+        if votes.len() < quorum {
+            env.storage().instance().set(&SETTLE_VOTES, &votes);
+            return;
+        }
+        let mut sorted: Vec<i128> = Vec::new(&env);
+        for vote in votes.values().iter() {
+            insert_sorted(&mut sorted, vote);
+        }
+        let settled = median(&sorted);
+        env.storage().instance().set(&SETTLE_PRICE, &settled);
+        env.storage().instance().remove(&SETTLE_VOTES);
+        env.events().publish((symbol_short!("SETTLE"),), (settled, final_at));
+    }
+
+    /// Settlement price while in settlement mode
+    pub fn get_settlement_price(env: Env) -> Option<i128> {
+        env.storage().instance().get(&SETTLE_PRICE)
+    }
+
+    /// Admin close of `user`'s position at the settlement price. Only
+    /// available in settlement mode; the payout always goes to the owner.
+    pub fn force_close(env: Env, user: Address, reason: Symbol) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let price: i128 = env
+            .storage()
+            .instance()
+            .get(&SETTLE_PRICE)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::NotSettling));
+        let (closed, ret_bal) = settle_position(&env, &user, price, 0);
+
+        cover_shortfall(&env, ret_bal);
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).transfer(
+            &env.current_contract_address(),
+            &user,
+            &ret_bal,
+        );
+        let nonce = event_nonce(&env, &user);
+        env.events().publish((symbol_short!("FORCE"), user), (reason, price, ret_bal, closed.id, nonce));
+        check_invariants(&env);
+    }
+
+    /// Liquidate a position under-margined at the mark price, returning
+    /// `tip_bps` of the reward to the insurance fund and paying the rest to
+    /// `recipient` (or the liquidator when `None`)
+    pub fn liquidate_position(env: Env, liquidator: Address, request: LiquidationRequest) {
+        liquidator.require_auth();
+        require_not_suspended(&env, &liquidator, false);
+        require_no_grace(&env);
+        let LiquidationRequest { user, tip_bps, recipient } = request;
+        if tip_bps > MAX_TIP_BPS {
+            panic_with_error!(&env, ContractError::InvalidTip);
+        }
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let position = positions
+            .get(user.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let Some((price, ret_bal, spread)) = liquidation_value(&env, &position) else {
+            panic_with_error!(&env, ContractError::AboveMargin);
+        };
+
+        // Honour the exclusive window of the best standing bid
+        let mut tip_bps = tip_bps;
+        let bid_key = (LIQ_BID, user.clone());
+        if let Some(bid) = env.storage().temporary().get::<_, LiquidationBid>(&bid_key) {
+            if env.ledger().sequence() < bid.start_ledger + LIQ_WINDOW_LEDGERS {
+                if bid.liquidator != liquidator {
+                    panic_with_error!(&env, ContractError::LiquidationReserved);
+                }
+                tip_bps = tip_bps.max(bid.tip_bps);
+            }
+            env.storage().temporary().remove(&bid_key);
+        }
+
+        // Archive and remove position
+        settle_position(&env, &user, price, spread);
+
+        // Reward liquidator, less the tip to the insurance fund
+        let reward = div_floor(ret_bal, 3);
+        let tip = div_ceil(reward * i128::from(tip_bps), 10000);
+        if tip > 0 {
+            let insurance: i128 = env.storage().instance().get(&INSURANCE).unwrap_or(0_i128);
+            env.storage().instance().set(&INSURANCE, &(insurance + tip));
+        }
+        if reward - tip > 0 {
+            cover_shortfall(&env, reward - tip);
+            let recipient = recipient.unwrap_or(liquidator.clone());
+            let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+            TokenClient::new(&env, &p_usd).transfer(
+                &env.current_contract_address(),
+                &recipient,
+                &(reward - tip),
+            );
+        }
+        let nonce = event_nonce(&env, &user);
+        env.events().publish((symbol_short!("LIQ"),), (user, liquidator, ret_bal, position.id, nonce));
+        record_call(&env, symbol_short!("liquidate"), ret_bal, false);
+        check_invariants(&env);
+    }
+
+    /// Backstop that takes over positions left unliquidated for
+    /// `delay_ledgers` after being marked, or none with `None`
+    pub fn set_backstop(env: Env, config: Option<BackstopConfig>) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        match config {
+            Some(config) => env.storage().instance().set(&BACKSTOP, &config),
+            None => env.storage().instance().remove(&BACKSTOP),
+        }
+    }
+
+    /// Configured liquidation backstop, if any
+    pub fn get_backstop(env: Env) -> Option<BackstopConfig> {
+        env.storage().instance().get(&BACKSTOP)
+    }
+
+    /// Permissionless: record the ledger from which `user`'s position is
+    /// liquidatable, starting the backstop delay. A healthy position clears
+    /// its mark. Returns the ledger it has been liquidatable since, if any.
+    pub fn mark_liquidatable(env: Env, user: Address) -> Option<u32> {
+        require_no_grace(&env);
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let position = positions
+            .get(user.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let key = (LIQ_SINCE, user);
+        if liquidation_value(&env, &position).is_none() {
+            env.storage().persistent().remove(&key);
+            return None;
+        }
+        let since = env.storage().persistent().get(&key).unwrap_or(env.ledger().sequence());
+        env.storage().persistent().set(&key, &since);
+        Some(since)
+    }
+
+    /// Permissionless: hand a position that is still liquidatable
+    /// `delay_ledgers` after being marked to the backstop, which merges it
+    /// into its own position and is notified through `take_over`. The
+    /// trader forfeits the remaining margin, as in a liquidation.
+    pub fn backstop_take_over(env: Env, user: Address) {
+        require_no_grace(&env);
+        let config: BackstopConfig = env
+            .storage()
+            .instance()
+            .get(&BACKSTOP)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::NoBackstop));
+        let mut positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let position = positions
+            .get(user.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::PositionNotOpen));
+        let Some((price, ret_bal, _)) = liquidation_value(&env, &position) else {
+            panic_with_error!(&env, ContractError::AboveMargin);
+        };
+        let since: u32 = env
+            .storage()
+            .persistent()
+            .get(&(LIQ_SINCE, user.clone()))
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::TimelockActive));
+        if env.ledger().sequence() < since.saturating_add(config.delay_ledgers) {
+            panic_with_error!(&env, ContractError::TimelockActive);
+        }
+
+        let merged = match positions.get(config.backstop.clone()) {
+            Some(book) => {
+                add_open_value(&env, book.is_long(), -book.value);
+                merge_positions(&env, &book, &position, price)
+            }
+            None => position.clone(),
+        };
+        add_open_value(&env, position.is_long(), -position.value);
+        add_open_value(&env, merged.is_long(), merged.value);
+        positions.remove(user.clone());
+        positions.set(config.backstop.clone(), merged.clone());
+        env.storage().persistent().set(&POSITIONS, &positions);
+        env.storage().persistent().remove(&(POS_OWNER, position.id));
+        env.storage().persistent().set(&(POS_OWNER, merged.id), &config.backstop);
+        env.storage().persistent().remove(&(LIQ_SINCE, user.clone()));
+        env.storage().persistent().remove(&(LIQ_SINCE, config.backstop.clone()));
+        env.storage().persistent().remove(&(MARGIN_CALL, user.clone()));
+        env.storage().temporary().remove(&(LIQ_BID, user.clone()));
+        clear_trigger(&env, &STOP_LOSS, position.id);
+        clear_trigger(&env, &TAKE_PROFIT, position.id);
+        env.storage().persistent().remove(&(POS_LIFETIME, position.id));
+        env.storage().persistent().remove(&(POS_APPROVE, position.id));
+
+        let nonce = event_nonce(&env, &user);
+        env.events().publish(
+            (symbol_short!("BACKSTOP"), user.clone()),
+            (config.backstop.clone(), price, ret_bal, position.id, nonce),
+        );
+        BackstopClient::new(&env, &config.backstop).take_over(&user, &merged.id);
+        check_invariants(&env);
+    }
+
+    /// Current insurance fund balance in pUSD, including the part parked
+    /// in the yield source at its current value
+    pub fn insurance_balance(env: Env) -> i128 {
+        let insurance: i128 = env.storage().instance().get(&INSURANCE).unwrap_or(0_i128);
+        let deployed: i128 = env.storage().instance().get(&YIELD_DEPLOYED).unwrap_or(0_i128);
+        match env.storage().instance().get::<_, Address>(&YIELD_SOURCE) {
+            Some(source) if deployed > 0 => {
+                insurance + YieldSourceClient::new(&env, &source).balance(&env.current_contract_address()) - deployed
+            }
+            _ => insurance,
+        }
+    }
+
+    /// Yield source for idle insurance. Anything parked in the previous
+    /// source is recalled first.
+    pub fn set_yield_source(env: Env, source: Address) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let deployed = mark_yield(&env);
+        if deployed > 0 {
+            recall_from_source(&env, deployed);
+        }
+        env.storage().instance().set(&YIELD_SOURCE, &source);
+    }
+
+    /// Move `amount` of the insurance fund into the yield source
+    pub fn deploy_insurance(env: Env, amount: i128) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if amount <= 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        let source: Address = env
+            .storage()
+            .instance()
+            .get(&YIELD_SOURCE)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::AssetNotAllowed));
+        let deployed = mark_yield(&env);
+        let insurance: i128 = env.storage().instance().get(&INSURANCE).unwrap_or(0_i128);
+        if amount > insurance - deployed {
+            panic_with_error!(&env, ContractError::InsufficientInsurance);
+        }
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).approve(
+            &env.current_contract_address(),
+            &source,
+            &amount,
+            &env.ledger().sequence(),
+        );
+        YieldSourceClient::new(&env, &source).deposit(&env.current_contract_address(), &amount);
+        env.storage().instance().set(&YIELD_DEPLOYED, &(deployed + amount));
+        env.events().publish((symbol_short!("DEPLOY"),), amount);
+        check_invariants(&env);
+    }
+
+    /// Bring `amount` of the deployed insurance back from the yield source
+    pub fn recall_insurance(env: Env, amount: i128) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if amount <= 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        if amount > mark_yield(&env) {
+            panic_with_error!(&env, ContractError::InsufficientInsurance);
+        }
+        recall_from_source(&env, amount);
+        check_invariants(&env);
+    }
+
+    /// Insurance currently parked in the yield source, at its value when
+    /// last deposited, withdrawn or marked
+    pub fn get_insurance_deployed(env: Env) -> i128 {
+        env.storage().instance().get(&YIELD_DEPLOYED).unwrap_or(0_i128)
+    }
+
+    /// Breakdown of the contract's pUSD: what open positions would be paid
+    /// at the current price, the insurance fund, the incentive budget and
+    /// what is left over. Insurance parked in the yield source is counted
+    /// as `deployed`. `free` is negative if the contract is short.
+    pub fn get_liquidity(env: Env) -> LiquidityInfo {
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| Map::new(&env));
+        let price = current_price(&env);
+        let mut reserved: i128 = 0;
+        for (_, position) in positions.iter() {
+            reserved += position_value_at(&position, price).max(0);
+        }
+        let insurance: i128 = env.storage().instance().get(&INSURANCE).unwrap_or(0_i128);
+        let incentives = env
+            .storage()
+            .instance()
+            .get::<_, IncentiveBudget>(&INCENTIVES)
+            .map_or(0, |budget| budget.remaining);
+        let deployed: i128 = env.storage().instance().get(&YIELD_DEPLOYED).unwrap_or(0_i128);
+        let escrowed: i128 = env.storage().instance().get(&ORDER_ESCROW).unwrap_or(0_i128);
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        let balance = TokenClient::new(&env, &p_usd).balance(&env.current_contract_address());
+        LiquidityInfo {
+            balance,
+            deployed,
+            reserved,
+            insurance,
+            incentives,
+            escrowed,
+            free: balance + deployed - reserved - insurance - incentives - escrowed,
+        }
+    }
+
+    /// Rebuild the open interest totals from stored positions,
+    /// `RECOMPUTE_PAGE_SIZE` positions per call. Page 0 starts a new pass
+    /// and pages must follow in order. After the last page the totals are
+    /// overwritten and a `RECOMPUTE` event reports the corrections. Fails
+    /// with `RecomputeStale` if the totals moved mid-pass. Returns whether
+    /// the pass is complete.
+    pub fn recompute_totals(env: Env, page: u32) -> bool {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap_or(0_i128);
+        let total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap_or(0_i128);
+        let mut state = if page == 0 {
+            RecomputeState { next_page: 0, long_oi: 0, short_oi: 0, stored_long: total_long, stored_short: total_short }
+        } else {
+            env.storage()
+                .instance()
+                .get(&RECOMPUTE)
+                .unwrap_or_else(|| panic_with_error!(&env, ContractError::RecomputeStale))
+        };
+        if page != state.next_page || total_long != state.stored_long || total_short != state.stored_short {
+            panic_with_error!(&env, ContractError::RecomputeStale);
+        }
+
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| Map::new(&env));
+        let start = page.saturating_mul(RECOMPUTE_PAGE_SIZE);
+        for (_, position) in positions.iter().skip(start as usize).take(RECOMPUTE_PAGE_SIZE as usize) {
+            if position.is_long() {
+                state.long_oi += position.value;
+            } else {
+                state.short_oi += position.value;
+            }
+        }
+        if start.saturating_add(RECOMPUTE_PAGE_SIZE) < positions.len() {
+            state.next_page += 1;
+            env.storage().instance().set(&RECOMPUTE, &state);
+            return false;
+        }
+
+        env.storage().instance().set(&LONG_POS, &state.long_oi);
+        env.storage().instance().set(&SHORT_POS, &state.short_oi);
+        env.storage().instance().remove(&RECOMPUTE);
+        env.events().publish(
+            (symbol_short!("RECOMPUTE"),),
+            (state.long_oi - total_long, state.short_oi - total_short),
+        );
+        check_invariants(&env);
+        true
+    }
+
+    /// Sweep untracked pUSD (rounding dust, direct transfers) into the
+    /// insurance fund: whatever the contract holds above posted margin, the
+    /// fee pool, the insurance fund, the incentive budget and order escrow.
+    /// Unrealized PnL is not counted, since a price move can reverse it.
+    /// Returns the amount swept.
+    pub fn reconcile(env: Env) -> i128 {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let liquidity = Self::get_liquidity(env.clone());
+        let total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap_or(0_i128);
+        let total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap_or(0_i128);
+        let fee_pool: i128 = env.storage().instance().get(&FEE_POOL).unwrap_or(0_i128);
+        let liabilities =
+            total_long + total_short + fee_pool + liquidity.insurance + liquidity.incentives + liquidity.escrowed;
+        let surplus = (liquidity.balance + liquidity.deployed - liabilities).max(0);
+        if surplus > 0 {
+            env.storage().instance().set(&INSURANCE, &(liquidity.insurance + surplus));
+        }
+        env.events().publish((symbol_short!("RECON"),), (liquidity.balance, liabilities, surplus));
+        check_invariants(&env);
+        surplus
+    }
+
+    /// Last event nonce issued for `user`, zero before their first event
+    pub fn get_event_nonce(env: Env, user: Address) -> u64 {
+        env.storage().persistent().get(&(EVENT_NONCE, user)).unwrap_or(0)
+    }
+
+    /// Re-emit a user's closed trades as events for off-chain history
+    /// tooling. Replays carry no event nonce since they repeat old data.
+    pub fn emit_history(env: Env, user: Address, start: u32, limit: u32) -> u32 {
+        let history: Vec<Position> = env
+            .storage()
+            .persistent()
+            .get(&(TRADE_HISTORY, user.clone()))
+            .unwrap_or_else(|| Vec::new(&env));
+        let end = history.len().min(start.saturating_add(limit));
+        let mut emitted: u32 = 0;
+        for index in start..end {
+            let closed = history.get_unchecked(index);
+            env.events().publish((TRADE_HISTORY, user.clone()), (index, closed));
+            emitted += 1;
+        }
+        emitted
+    }
+}
+
+/// Address book of the contracts linked to this market
+#[contractimpl]
+impl PerpContract {
+    /// Addresses of the components this market is linked to
+    pub fn get_contracts(env: Env) -> ContractBook {
+        ContractBook {
+            collateral: env.storage().instance().get(&PUSD).unwrap(),
+            oracles: Self::get_oracles(env.clone()),
+            price_feed: market_route(&env).feed,
+            swap_router: env.storage().instance().get(&SWAP_ROUTER),
+            yield_source: env.storage().instance().get(&YIELD_SOURCE),
+            backstop: env.storage().instance().get::<_, BackstopConfig>(&BACKSTOP).map(|config| config.backstop),
+            linked: env.storage().instance().get(&CONTRACT_BOOK).unwrap_or_else(|| Map::new(&env)),
+        }
+    }
+
+    /// Propose registering `address` under `name` in the address book, or
+    /// removing the entry with `None`, applicable after the timelock
+    pub fn propose_contract(env: Env, name: Symbol, address: Option<Address>) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let eta_ledger = env.ledger().sequence() + TIMELOCK_LEDGERS;
+        let pending = PendingContract { name: name.clone(), address: address.clone(), eta_ledger };
+        env.storage().instance().set(&PENDING_CONTRACT, &pending);
+        env.events().publish((symbol_short!("BOOKPROP"), name), (address, eta_ledger));
+    }
+
+    /// Apply the proposed address book change once the timelock has passed
+    pub fn apply_contract(env: Env) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        let pending: PendingContract = env
+            .storage()
+            .instance()
+            .get(&PENDING_CONTRACT)
+            .unwrap_or_else(|| panic_with_error!(&env, ContractError::NoPendingChange));
+        if env.ledger().sequence() < pending.eta_ledger {
+            panic_with_error!(&env, ContractError::TimelockActive);
+        }
+        let mut book: Map<Symbol, Address> = env
+            .storage()
+            .instance()
+            .get(&CONTRACT_BOOK)
+            .unwrap_or_else(|| Map::new(&env));
+        if let Some(address) = pending.address.clone() {
+            book.set(pending.name.clone(), address);
+        } else {
+            book.remove(pending.name.clone());
+        }
+        env.storage().instance().set(&CONTRACT_BOOK, &book);
+        env.storage().instance().remove(&PENDING_CONTRACT);
+        env.events().publish((symbol_short!("BOOK"), pending.name), pending.address);
+    }
+}
+
+/// Skew-balancing incentives, left out of minimal builds with
+/// `--no-default-features`
+#[cfg(feature = "rewards")]
+#[contractimpl]
+impl PerpContract {
+    /// Fund the market's incentive budget with pUSD from the admin. The
+    /// decay schedule restarts when an exhausted budget is refilled.
+    pub fn fund_incentives(env: Env, amount: i128) {
+        let admin: Address = env.storage().instance().get(&ADMIN).unwrap();
+        admin.require_auth();
+        if amount <= 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).transfer(&admin, &env.current_contract_address(), &amount);
+
+        let mut budget = env.storage().instance().get(&INCENTIVES).unwrap_or(IncentiveBudget {
+            remaining: 0,
+            start_ledger: env.ledger().sequence(),
+        });
+        if budget.remaining == 0 {
+            budget.start_ledger = env.ledger().sequence();
+        }
+        budget.remaining += amount;
+        env.storage().instance().set(&INCENTIVES, &budget);
+        env.events().publish((INCENTIVES,), (amount, budget.remaining));
+        check_invariants(&env);
+    }
+
+    /// Remaining incentive budget and the ledger its decay started from
+    pub fn get_incentives(env: Env) -> Option<IncentiveBudget> {
+        env.storage().instance().get(&INCENTIVES)
+    }
+}
+
+/// Resting limit orders, left out of minimal builds with
+/// `--no-default-features`
+#[cfg(feature = "orders")]
+#[contractimpl]
+impl PerpContract {
+    /// Rest `request` as a limit order, moving its margin into escrow until
+    /// a keeper fills it with `fill_order` or the trader cancels it. The
+    /// request must carry a `limit_price`. Returns the order id.
+    pub fn place_order(env: Env, trader: Address, request: OpenRequest) -> u64 {
+        trader.require_auth();
+        require_trading(&env);
+        require_unexpired(&env, request.valid_until_ledger);
+        let leverage = i128::from(request.leverage);
+        if leverage < 1 || leverage > max_leverage(&env, request.long) {
+            panic_with_error!(&env, ContractError::InvalidLeverage);
+        }
+        if request.value <= 0 {
+            panic_with_error!(&env, ContractError::ZeroValue);
+        }
+        if !matches!(request.limit_price, Some(limit) if limit > 0) {
+            panic_with_error!(&env, ContractError::InvalidPrice);
+        }
+
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).transfer_from(
+            &env.current_contract_address(),
+            &trader,
+            &env.current_contract_address(),
+            &request.value,
+        );
+        let escrowed: i128 = env.storage().instance().get(&ORDER_ESCROW).unwrap_or(0_i128);
+        env.storage().instance().set(&ORDER_ESCROW, &(escrowed + request.value));
+
+        let id = next_id(&env);
+        let index_key = (USER_ORDERS, trader.clone());
+        let mut index: Vec<u64> = env.storage().persistent().get(&index_key).unwrap_or_else(|| Vec::new(&env));
+        index.push_back(id);
+        env.storage().persistent().set(&index_key, &index);
+        let order = Order { id, trader: trader.clone(), request, status: OrderStatus::Open };
+        env.storage().persistent().set(&(ORDERS, id), &order);
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish(
+            (symbol_short!("ORDER"), trader),
+            (order.request.value, order.request.long, order.request.limit_price, id, nonce),
+        );
+        check_invariants(&env);
+        id
+    }
+
+    /// Order `order_id`, if it exists
+    pub fn get_order(env: Env, order_id: u64) -> Option<Order> {
+        env.storage().persistent().get(&(ORDERS, order_id))
+    }
+
+    /// Ids of every order `user` has placed, oldest first
+    pub fn get_user_orders(env: Env, user: Address) -> Vec<u64> {
+        env.storage().persistent().get(&(USER_ORDERS, user)).unwrap_or_else(|| Vec::new(&env))
+    }
+
+    /// Open the position for order `order_id` out of its escrow once the
+    /// fill price reaches the limit. The keeper earns the keeper rebate if
+    /// one is due. Returns the rebate paid.
+    pub fn fill_order(env: Env, keeper: Address, order_id: u64) -> i128 {
+        keeper.require_auth();
+        require_not_suspended(&env, &keeper, false);
+        let mut order = open_order(&env, order_id);
+        let price = execution_price(&env, fresh_price(&env), order.request.long);
+        let limit = order.request.limit_price.unwrap();
+        if (order.request.long && price > limit) || (!order.request.long && price < limit) {
+            panic_with_error!(&env, ContractError::TriggerNotReached);
+        }
+
+        release_escrow(&env, order.request.value);
+        let value = order.request.value;
+        fill_request(&env, &order.trader, order.request.clone(), true);
+        order.status = OrderStatus::Filled;
+        env.storage().persistent().set(&(ORDERS, order_id), &order);
+        let nonce = event_nonce(&env, &order.trader);
+        env.events().publish((symbol_short!("FILL"), order.trader.clone()), (keeper.clone(), price, order_id, nonce));
+        record_call(&env, symbol_short!("fill"), value, false);
+        let rebate = pay_keeper_rebate(&env, &keeper);
+        check_invariants(&env);
+        rebate
+    }
+
+    /// Cancel one of the trader's open orders and refund its escrowed
+    /// margin. An order past its `valid_until_ledger` is marked `Expired`
+    /// rather than `Cancelled`.
+    pub fn cancel_order(env: Env, trader: Address, order_id: u64) {
+        trader.require_auth();
+        let mut order = open_order(&env, order_id);
+        if order.trader != trader {
+            panic_with_error!(&env, ContractError::OrderNotOpen);
+        }
+        let expired = matches!(order.request.valid_until_ledger, Some(last) if env.ledger().sequence() > last);
+        order.status = if expired { OrderStatus::Expired } else { OrderStatus::Cancelled };
+        release_escrow(&env, order.request.value);
+        env.storage().persistent().set(&(ORDERS, order_id), &order);
+
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).transfer(&env.current_contract_address(), &trader, &order.request.value);
+        let nonce = event_nonce(&env, &trader);
+        env.events().publish((symbol_short!("CANCEL"), trader), (order.request.value, order.status, order_id, nonce));
+        check_invariants(&env);
+    }
+}
+
+/// Whether the index has crossed `order`, a `kind` trigger: a stop-loss
+/// once it moves against the position, a take-profit once it moves in its
+/// favour
+fn trigger_reached(env: &Env, kind: &Symbol, order: &StopOrder, index: i128) -> bool {
+    let positions: Map<Address, Position> = env
+        .storage()
+        .persistent()
+        .get(&POSITIONS)
+        .unwrap_or_else(|| panic_with_error!(env, ContractError::PositionNotOpen));
+    let position = positions
+        .get(order.trader.clone())
+        .unwrap_or_else(|| panic_with_error!(env, ContractError::PositionNotOpen));
+    if (*kind == STOP_LOSS) == position.is_long() {
+        index <= order.trigger_price
+    } else {
+        index >= order.trigger_price
+    }
+}
+
+/// Close position `position_id` on its triggered stop-loss, paying the
+/// keeper's bounty. Returns the bounty.
+fn fill_stop(env: &Env, keeper: &Address, position_id: u64, order: StopOrder, index: i128) -> i128 {
+    let (price, spread) = exit_price(env, &order.trader, index);
+    let (_, ret_bal) = settle_position(env, &order.trader, price, spread);
+    let bounty = div_floor(ret_bal * STOP_BOUNTY_BPS, 10000);
+    cover_shortfall(env, ret_bal);
+    let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+    let token = TokenClient::new(env, &p_usd);
+    token.transfer(&env.current_contract_address(), &order.trader, &(ret_bal - bounty));
+    if bounty > 0 {
+        token.transfer(&env.current_contract_address(), keeper, &bounty);
+    }
+    let nonce = event_nonce(env, &order.trader);
+    env.events().publish((symbol_short!("STOP"), order.trader), (keeper.clone(), price, ret_bal - bounty, bounty, position_id, nonce));
+    record_call(env, symbol_short!("stop"), ret_bal, false);
+    bounty
+}
+
+/// Close position `position_id` on its triggered take-profit, splitting
+/// the fee between the keeper and the insurance fund. Returns the keeper's
+/// share.
+fn fill_take_profit(env: &Env, keeper: &Address, position_id: u64, order: StopOrder, index: i128) -> i128 {
+    let (price, spread) = exit_price(env, &order.trader, index);
+    let (_, ret_bal) = settle_position(env, &order.trader, price, spread);
+    let fee = div_floor(ret_bal * TAKE_PROFIT_FEE_BPS, 10000);
+    let bounty = div_floor(fee, 2);
+    let insurance: i128 = env.storage().instance().get(&INSURANCE).unwrap_or(0_i128);
+    env.storage().instance().set(&INSURANCE, &(insurance + fee - bounty));
+    cover_shortfall(env, ret_bal - fee + bounty);
+    let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+    let token = TokenClient::new(env, &p_usd);
+    token.transfer(&env.current_contract_address(), &order.trader, &(ret_bal - fee));
+    if bounty > 0 {
+        token.transfer(&env.current_contract_address(), keeper, &bounty);
+    }
+    let nonce = event_nonce(env, &order.trader);
+    env.events().publish(
+        (symbol_short!("TAKEPROF"), order.trader),
+        (keeper.clone(), price, ret_bal - fee, bounty, fee - bounty, position_id, nonce),
+    );
+    record_call(env, symbol_short!("takeprof"), ret_bal, false);
+    bounty
+}
+
+/// Price band of a trigger price: the position of its top seven bits and
+/// their value. Bands rise with the price and span 1/128 to 1/64 of it
+/// whatever the asset's decimals.
+fn trigger_band(price: i128) -> u32 {
+    let shift = (128 - price.leading_zeros()).saturating_sub(7);
+    shift * 64 + (price >> shift) as u32
+}
+
+/// File position `position_id`'s `kind` order under its price band
+fn index_trigger(env: &Env, kind: &Symbol, position_id: u64, trigger_price: i128) {
+    let key = (TRIGGERS, trigger_band(trigger_price));
+    let mut entries: Vec<(Symbol, u64)> = env.storage().persistent().get(&key).unwrap_or_else(|| Vec::new(env));
+    entries.push_back((kind.clone(), position_id));
+    env.storage().persistent().set(&key, &entries);
+}
+
+/// Remove position `position_id`'s `kind` order, if any, and its entry in
+/// the price band index
+fn clear_trigger(env: &Env, kind: &Symbol, position_id: u64) {
+    let order_key = (kind.clone(), position_id);
+    let Some(order) = env.storage().persistent().get::<_, StopOrder>(&order_key) else {
+        return;
+    };
+    env.storage().persistent().remove(&order_key);
+    let key = (TRIGGERS, trigger_band(order.trigger_price));
+    let mut entries: Vec<(Symbol, u64)> = env.storage().persistent().get(&key).unwrap_or_else(|| Vec::new(env));
+    if let Some(at) = entries.first_index_of((kind.clone(), position_id)) {
+        entries.remove(at);
+    }
+    if entries.is_empty() {
+        env.storage().persistent().remove(&key);
+    } else {
+        env.storage().persistent().set(&key, &entries);
+    }
+}
+
+/// Settle a trader's own close at the fresh price, honouring the
+/// fresh-close rule. Returns the closed position and the pUSD owed to the
+/// trader.
+fn close_position(env: &Env, trader: &Address) -> (Position, i128) {
+    let fresh_close: bool = env.storage().instance().get(&FRESH_CLOSE).unwrap_or(false);
+    if fresh_close {
+        let positions: Map<Address, Position> = env
+            .storage()
+            .persistent()
+            .get(&POSITIONS)
+            .unwrap_or_else(|| Map::new(env));
+        if let Some(position) = positions.get(trader.clone()) {
+            let price_ledger: u32 = env.storage().instance().get(&PRICE_LEDGER).unwrap_or(0);
+            if price_ledger <= position.opened_at_ledger {
+                panic_with_error!(env, ContractError::PriceNotUpdated);
+            }
+        }
+    }
+    let (price, spread) = exit_price(env, trader, fresh_price(env));
+    settle_position(env, trader, price, spread)
+}
+
+/// Check and open a trade request: expiry, leverage and the limit price,
+/// then the position itself
+fn fill_request(env: &Env, trader: &Address, request: OpenRequest, escrowed: bool) {
+    let OpenRequest { value, long, leverage, limit_price, referrer, valid_until_ledger, reduce_only } = request;
+    require_unexpired(env, valid_until_ledger);
+    let leverage = i128::from(leverage);
+    if leverage < 1 || leverage > max_leverage(env, long) {
+        panic_with_error!(env, ContractError::InvalidLeverage);
+    }
+    if let Some(limit) = limit_price {
+        let price = execution_price(env, fresh_price(env), long);
+        if (long && price > limit) || (!long && price < limit) {
+            panic_with_error!(env, ContractError::SlippageExceeded);
+        }
+    }
+    if reduce_only {
+        reduce_position(env, trader, long, if escrowed { value } else { 0 });
+        return;
+    }
+    open_position(env, trader, value, long, leverage, escrowed);
+    if let Some(referrer) = referrer {
+        let nonce = event_nonce(env, trader);
+        env.events().publish((symbol_short!("REFER"), referrer), (trader.clone(), value, nonce));
+    }
+}
+
+/// Fill a reduce-only request by closing the trader's position on the
+/// opposite side. Positions are held whole, so reducing means closing.
+/// The payout goes to the trader with any escrowed margin `refund`.
+fn reduce_position(env: &Env, trader: &Address, long: bool, refund: i128) {
+    let positions: Map<Address, Position> = env
+        .storage()
+        .persistent()
+        .get(&POSITIONS)
+        .unwrap_or_else(|| Map::new(env));
+    if positions.get(trader.clone()).is_none_or(|position| position.is_long() == long) {
+        panic_with_error!(env, ContractError::ReduceOnly);
+    }
+    let (closed, ret_bal) = close_position(env, trader);
+    cover_shortfall(env, ret_bal + refund);
+    if ret_bal + refund > 0 {
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(env, &p_usd).transfer(&env.current_contract_address(), trader, &(ret_bal + refund));
+    }
+    let nonce = event_nonce(env, trader);
+    env.events().publish((symbol_short!("REDUCE"), trader.clone()), (ret_bal, closed.id, nonce));
+}
+
+/// Order `order_id`, failing with `OrderNotOpen` unless it is still open
+#[cfg(feature = "orders")]
+fn open_order(env: &Env, order_id: u64) -> Order {
+    let order: Order = env
+        .storage()
+        .persistent()
+        .get(&(ORDERS, order_id))
+        .unwrap_or_else(|| panic_with_error!(env, ContractError::OrderNotOpen));
+    if order.status != OrderStatus::Open {
+        panic_with_error!(env, ContractError::OrderNotOpen);
+    }
+    order
+}
+
+/// Liquidation fill price, value and spread of `position` at the mark
+/// price, or `None` while it is at or above its maintenance margin
+fn liquidation_value(env: &Env, position: &Position) -> Option<(i128, i128, i128)> {
+    let mark = mark_price(env, fresh_price(env));
+    let price = execution_price(env, mark, !position.is_long());
+    let ret_bal = position_value_at(position, price).max(0);
+    let required_val = div_ceil(position.value * liquidation_margin(env, position), 10000);
+    (ret_bal < required_val).then_some((price, ret_bal, spread_at(env, mark)))
+}
+
+/// Fail with `SettlementActive` once the market is settling or past its
+/// expiry, when only closes are allowed
+fn require_trading(env: &Env) {
+    if env.storage().instance().has(&SETTLE_PRICE) || is_expired(env) {
+        panic_with_error!(env, ContractError::SettlementActive);
+    }
+}
+
+/// Merge `leg`, a position the backstop takes over, into the backstop's
+/// position `book`. The leg keeps its margin and entry, which values its
+/// size at the bankruptcy price with nothing left over. On the same side
+/// the sizes add up at their size-weighted entry, rounded against the
+/// backstop. On opposite sides the smaller is closed against the larger
+/// at `price` and its equity booked into the margin of what is left,
+/// which must keep some size and margin (`PositionOpen` otherwise).
+fn merge_positions(env: &Env, book: &Position, leg: &Position, price: i128) -> Position {
+    let mut merged = if book.is_long() == leg.is_long() {
+        let mut merged = book.clone();
+        let size_base = book.size_base + leg.size_base;
+        let weighted = book.size_base * book.open_price() + leg.size_base * leg.open_price();
+        let open_price = if book.is_long() { div_ceil(weighted, size_base) } else { div_floor(weighted, size_base) };
+        merged.value = book.value + leg.value;
+        merged.size_base = size_base;
+        merged.open_price = packed_price(env, open_price);
+        merged
+    } else {
+        let (larger, smaller) = if book.size_base >= leg.size_base { (book, leg) } else { (leg, book) };
+        let mut merged = larger.clone();
+        let change = if larger.is_long() {
+            price - larger.open_price()
+        } else {
+            larger.open_price() - price
+        };
+        merged.value = larger.value + div_floor(change * smaller.size_base, BASE_UNIT) + position_value_at(smaller, price);
+        merged.size_base = larger.size_base - smaller.size_base;
+        if merged.size_base == 0 || merged.value <= 0 {
+            panic_with_error!(env, ContractError::PositionOpen);
+        }
+        merged
+    };
+    merged.id = book.id;
+    merged.entry_spread = book.entry_spread + leg.entry_spread;
+    merged.margin_version = margin_version(env);
+    merged
+}
+
+/// Add `amount` to the open margin total of one side
+fn add_open_value(env: &Env, long: bool, amount: i128) {
+    let total_key = if long { LONG_POS } else { SHORT_POS };
+    let total: i128 = env.storage().instance().get(&total_key).unwrap_or(0);
+    env.storage().instance().set(&total_key, &(total + amount));
+}
+
+/// Move `from`'s position to `to`, who must not hold one, carrying its
+/// margin call flag and liquidatable mark and clearing the old owner's
+/// orders, lifetime and approval
+fn move_position(env: &Env, from: &Address, to: &Address) -> u64 {
+    require_not_suspended(env, from, false);
+    require_not_suspended(env, to, true);
+    let mut positions: Map<Address, Position> = env
+        .storage()
+        .persistent()
+        .get(&POSITIONS)
+        .unwrap_or_else(|| panic_with_error!(env, ContractError::PositionNotOpen));
+    let position = positions
+        .get(from.clone())
+        .unwrap_or_else(|| panic_with_error!(env, ContractError::PositionNotOpen));
+    if positions.contains_key(to.clone()) {
+        panic_with_error!(env, ContractError::PositionOpen);
+    }
+    positions.remove(from.clone());
+    positions.set(to.clone(), position.clone());
+    env.storage().persistent().set(&POSITIONS, &positions);
+    env.storage().persistent().set(&(POS_OWNER, position.id), to);
+
+    if let Some(flagged_at) = env.storage().persistent().get::<_, u64>(&(MARGIN_CALL, from.clone())) {
+        env.storage().persistent().remove(&(MARGIN_CALL, from.clone()));
+        env.storage().persistent().set(&(MARGIN_CALL, to.clone()), &flagged_at);
+    }
+    if let Some(since) = env.storage().persistent().get::<_, u32>(&(LIQ_SINCE, from.clone())) {
+        env.storage().persistent().remove(&(LIQ_SINCE, from.clone()));
+        env.storage().persistent().set(&(LIQ_SINCE, to.clone()), &since);
+    }
+    env.storage().temporary().remove(&(LIQ_BID, from.clone()));
+    clear_trigger(env, &STOP_LOSS, position.id);
+    clear_trigger(env, &TAKE_PROFIT, position.id);
+    env.storage().persistent().remove(&(POS_LIFETIME, position.id));
+    env.storage().persistent().remove(&(POS_APPROVE, position.id));
+
+    let nonces = (event_nonce(env, from), event_nonce(env, to));
+    env.events().publish((symbol_short!("TRANSFER"), from.clone()), (to.clone(), position.id, nonces.0, nonces.1));
+    check_invariants(env);
+    position.id
+}
+
+/// Fail with `PositionTooLarge` when `notional` exceeds the per-account cap
+fn require_within_cap(env: &Env, notional: i128) {
+    let cap: i128 = env.storage().instance().get(&MAX_NOTIONAL).unwrap_or(0);
+    if cap > 0 && notional > cap {
+        panic_with_error!(env, ContractError::PositionTooLarge);
+    }
+}
+
+/// Whether a dated market has reached its expiry
+fn is_expired(env: &Env) -> bool {
+    env.storage()
+        .instance()
+        .get::<_, u64>(&EXPIRY)
+        .is_some_and(|expiry| env.ledger().timestamp() >= expiry)
+}
+
+/// Freeze the current price as the proposed settlement price and open the
+/// dispute window
+fn begin_settlement(env: &Env) {
+    if env.storage().instance().has(&SETTLE_PRICE) {
+        panic_with_error!(env, ContractError::SettlementActive);
+    }
+    let price = current_price(env);
+    packed_price(env, price);
+    let final_at = env.ledger().timestamp() + SETTLEMENT_DISPUTE_WINDOW;
+    env.storage().instance().set(&SETTLE_PRICE, &price);
+    env.storage().instance().set(&SETTLE_FINAL, &final_at);
+    env.events().publish((symbol_short!("SETTLE"),), (price, final_at));
+}
+
+/// Next event nonce for `user`, starting at 1. Every event about a user
+/// carries one so consumers can spot gaps and duplicates when re-syncing.
+fn event_nonce(env: &Env, user: &Address) -> u64 {
+    let key = (EVENT_NONCE, user.clone());
+    let nonce = env.storage().persistent().get::<_, u64>(&key).unwrap_or(0) + 1;
+    env.storage().persistent().set(&key, &nonce);
+    nonce
+}
+
+/// Take `value` out of the order escrow total
+#[cfg(feature = "orders")]
+fn release_escrow(env: &Env, value: i128) {
+    let escrowed: i128 = env.storage().instance().get(&ORDER_ESCROW).unwrap_or(0_i128);
+    env.storage().instance().set(&ORDER_ESCROW, &(escrowed - value));
+}
+
+/// Take margin from the trader, charge the skew fee and record the position
+fn open_position(env: &Env, trader: &Address, value: i128, long: bool, leverage: i128, escrowed: bool) {
+    require_trading(env);
+    require_not_suspended(env, trader, true);
+    // Load or create positions map
+    let mut positions: Map<Address, Position> = env
+        .storage()
+        .persistent()
+        .get(&POSITIONS)
+        .unwrap_or_else(|| Map::new(env));
+    // A second open would overwrite the first and orphan its collateral
+    if positions.contains_key(trader.clone()) {
+        panic_with_error!(env, ContractError::PositionOpen);
+    }
+    // Dust positions are not worth a keeper's fee to liquidate
+    let min_value: i128 = env.storage().instance().get(&MIN_VALUE).unwrap_or(0);
+    if value < min_value {
+        panic_with_error!(env, ContractError::BelowMinimum);
+    }
+    require_within_cap(env, value * leverage);
+    let (remaining, balancing) = take_margin(env, trader, value, long, leverage, escrowed);
+
+    // Maintenance margin must stay below the margin posted at open
+    if maintenance_margin(env, remaining * leverage, margin_version(env)) * leverage >= 10000 {
+        panic_with_error!(env, ContractError::InsufficientMargin);
+    }
+
+    // Store and persist position
+    let index = fresh_price(env);
+    let price = execution_price(env, index, long);
+    let mut position = Position::new(
+        remaining,
+        packed_price(env, price),
+        env.ledger().sequence(),
+        u32::try_from(leverage).unwrap_or_else(|_| panic_with_error!(env, ContractError::InvalidLeverage)),
+        long,
+    );
+    // A zero base size would never move with price while still paying fees
+    if position.size_base == 0 {
+        panic_with_error!(env, ContractError::PositionTooSmallForPrice);
+    }
+    position.entry_spread = div_floor(position.size_base * spread_at(env, index), BASE_UNIT);
+    position.id = next_id(env);
+    position.margin_version = margin_version(env);
+    positions.set(trader.clone(), position.clone());
+    env.storage().persistent().set(&POSITIONS, &positions);
+    env.storage().persistent().set(&(POS_OWNER, position.id), trader);
+    accrue_incentive(env, position.id, balancing);
+
+    let nonce = event_nonce(env, trader);
+    env.events().publish((symbol_short!("PLACE"),), (trader.clone(), value, long, position.id, nonce));
+}
+
+/// Pull `value` pUSD from the trader (unless the contract already holds it
+/// in escrow for them), move the skew fee into the fee pool and add the rest to open
+/// interest. Returns the margin left and the skew-reducing part of the
+/// trade for `accrue_incentive`.
+fn take_margin(env: &Env, trader: &Address, value: i128, long: bool, leverage: i128, escrowed: bool) -> (i128, i128) {
+    if value <= 0 {
+        panic_with_error!(env, ContractError::ZeroValue);
+    }
+
+    // Transfer in pUSD
+    if !escrowed {
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(env, &p_usd).transfer_from(
+            &env.current_contract_address(),
+            trader,
+            &env.current_contract_address(),
+            &value,
+        );
+    }
+    // Calculate fee
+    let balancing = balancing_size(env, value, long);
+    let fee = skew_fee(env, value, long, leverage);
+    let remaining = value - fee;
+    let fee_pool: i128 = env.storage().instance().get(&FEE_POOL).unwrap_or(0_i128);
+    env.storage().instance().set(&FEE_POOL, &(fee_pool + fee));
+    if remaining <= 0 {
+        panic_with_error!(env, ContractError::InsufficientMargin);
+    }
+
+    // Update totals
+    let mut total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap();
+    let mut total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap();
+    if long {
+        total_long += remaining;
+        env.storage().instance().set(&LONG_POS, &total_long);
+    } else {
+        total_short += remaining;
+        env.storage().instance().set(&SHORT_POS, &total_short);
+    }
+    (remaining, balancing)
+}
+
+/// Current version of the margin tier curve
+fn margin_version(env: &Env) -> u32 {
+    env.storage().instance().get(&TIER_VERSION).unwrap_or(0)
+}
+
+/// Next id from the market's sequence, starting at 1
+fn next_id(env: &Env) -> u64 {
+    let id = env.storage().instance().get::<_, u64>(&NEXT_ID).unwrap_or(0) + 1;
+    env.storage().instance().set(&NEXT_ID, &id);
+    id
+}
+
+/// Fail with `IntentExpired` once the ledger is past `valid_until_ledger`,
+/// so a stuck or resubmitted transaction cannot fill at a moved price
+fn require_unexpired(env: &Env, valid_until_ledger: Option<u32>) {
+    if let Some(last_ledger) = valid_until_ledger {
+        if env.ledger().sequence() > last_ledger {
+            panic_with_error!(env, ContractError::IntentExpired);
+        }
+    }
+}
+
+/// Fail with `AccountSuspended` if `user` is suspended from opening or,
+/// for other actions, under a full suspension
+fn require_not_suspended(env: &Env, user: &Address, opening: bool) {
+    if let Some(suspension) = PerpContract::get_suspension(env.clone(), user.clone()) {
+        if opening || !suspension.open_only {
+            panic_with_error!(env, ContractError::AccountSuspended);
+        }
+    }
+}
+
+/// Ledger from which a position counts as abandoned: its opening ledger
+/// plus the shorter of its own lifetime and the market default
+fn position_expiry(env: &Env, position: &Position) -> Option<u32> {
+    let default: u32 = env.storage().instance().get(&MAX_LIFETIME).unwrap_or(0);
+    let own: Option<u32> = env.storage().persistent().get(&(POS_LIFETIME, position.id));
+    let lifetime = match (own, default) {
+        (Some(own), 0) => own,
+        (Some(own), default) => own.min(default),
+        (None, 0) => return None,
+        (None, default) => default,
+    };
+    Some(position.opened_at_ledger.saturating_add(lifetime))
+}
+
+/// Pay the keeper rebate out of the fee pool unless one was already paid
+/// within the interval. Returns the amount paid.
+fn pay_keeper_rebate(env: &Env, keeper: &Address) -> i128 {
+    let Some(config) = env.storage().instance().get::<_, KeeperRebate>(&KEEPER_REBATE) else {
+        return 0;
+    };
+    let now = env.ledger().timestamp();
+    if let Some(paid_at) = env.storage().instance().get::<_, u64>(&KEEPER_PAID) {
+        if now < paid_at + config.interval {
+            return 0;
+        }
+    }
+    let fee_pool: i128 = env.storage().instance().get(&FEE_POOL).unwrap_or(0_i128);
+    let rebate = config.amount.min(fee_pool);
+    if rebate <= 0 {
+        return 0;
+    }
+    env.storage().instance().set(&FEE_POOL, &(fee_pool - rebate));
+    env.storage().instance().set(&KEEPER_PAID, &now);
+    let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+    TokenClient::new(env, &p_usd).transfer(&env.current_contract_address(), keeper, &rebate);
+    let nonce = event_nonce(env, keeper);
+    env.events().publish((KEEPER_REBATE, keeper.clone()), (rebate, nonce));
+    rebate
+}
+
+/// Book the rebate earned by the skew-reducing part of a trade on
+/// position `position_id`. It is paid when the position closes, and only
+/// if it stayed open `INCENTIVE_HOLD_LEDGERS` after its last balancing
+/// trade, so opening and closing again earns nothing.
+#[cfg(feature = "rewards")]
+fn accrue_incentive(env: &Env, position_id: u64, balancing: i128) {
+    let Some(budget) = env.storage().instance().get::<_, IncentiveBudget>(&INCENTIVES) else {
+        return;
+    };
+    let epochs = (env.ledger().sequence() - budget.start_ledger) / INCENTIVE_EPOCH_LEDGERS;
+    let rebate = if epochs >= 127 {
+        0
+    } else {
+        (div_floor(balancing * INCENTIVE_BPS, 10000) >> epochs).min(budget.remaining)
+    };
+    if rebate <= 0 {
+        return;
+    }
+    let key = (REBATE_DUE, position_id);
+    let (due, _): (i128, u32) = env.storage().persistent().get(&key).unwrap_or((0, 0));
+    env.storage().persistent().set(&key, &(due + rebate, env.ledger().sequence()));
+}
+
+/// Pay the rebate booked on `position_id` to the trader closing it, from
+/// what is left of the budget, once the holding period has passed
+#[cfg(feature = "rewards")]
+fn pay_incentive(env: &Env, trader: &Address, position_id: u64) {
+    let key = (REBATE_DUE, position_id);
+    let Some((due, earned_at)) = env.storage().persistent().get::<_, (i128, u32)>(&key) else {
+        return;
+    };
+    env.storage().persistent().remove(&key);
+    let Some(mut budget) = env.storage().instance().get::<_, IncentiveBudget>(&INCENTIVES) else {
+        return;
+    };
+    let rebate = due.min(budget.remaining);
+    if env.ledger().sequence() < earned_at.saturating_add(INCENTIVE_HOLD_LEDGERS) || rebate <= 0 {
+        return;
+    }
+    budget.remaining -= rebate;
+    env.storage().instance().set(&INCENTIVES, &budget);
+    let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+    TokenClient::new(env, &p_usd).transfer(&env.current_contract_address(), trader, &rebate);
+    let nonce = event_nonce(env, trader);
+    env.events().publish((symbol_short!("REBATE"), trader.clone()), (rebate, nonce));
+}
+
+/// Builds without the `rewards` feature have no incentive budget
+#[cfg(not(feature = "rewards"))]
+fn accrue_incentive(_env: &Env, _position_id: u64, _balancing: i128) {}
+
+#[cfg(not(feature = "rewards"))]
+fn pay_incentive(_env: &Env, _trader: &Address, _position_id: u64) {}
+
+/// Market leverage, divided for the heavier side by how many times it
+/// outweighs the other, but not below the `SKEW_LEVERAGE` floor
+fn max_leverage(env: &Env, long: bool) -> i128 {
+    let default: i128 = env.storage().instance().get(&LEVERAGE).unwrap();
+    let floor: u32 = env.storage().instance().get(&SKEW_LEVERAGE).unwrap_or(0);
+    if floor == 0 {
+        return default;
+    }
+    let total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap_or(0_i128);
+    let total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap_or(0_i128);
+    let (same_side, other_side) = if long {
+        (total_long, total_short)
+    } else {
+        (total_short, total_long)
+    };
+    if same_side <= other_side {
+        return default;
+    }
+    (default * other_side / same_side).max(i128::from(floor))
+}
+
+/// Skew fee for a trade opened at `leverage`
+fn skew_fee(env: &Env, value: i128, long: bool, leverage: i128) -> i128 {
+    let mut skewing = value - balancing_size(env, value, long);
+    let on_notional: bool = env.storage().instance().get(&FEE_ON_NOTIONAL).unwrap_or(false);
+    if on_notional {
+        skewing *= leverage;
+    }
+    div_ceil(skewing * skew_fee_bps(env), 10000)
+}
+
+/// Fail unless a rate that open positions pay moves from `current` to
+/// `new` by at most a quarter of `current` (or 1 bps from zero), and no
+/// sooner than `FEE_CHANGE_INTERVAL` after its last change, recorded under
+/// `changed_key`
+fn require_gradual_change(env: &Env, current: i128, new: i128, changed_key: Symbol) {
+    let max_step = (current / FEE_CHANGE_DIVISOR).max(1);
+    if (new - current).abs() > max_step {
+        panic_with_error!(env, ContractError::ParameterChangeTooLarge);
+    }
+    let now = env.ledger().timestamp();
+    if let Some(changed_at) = env.storage().instance().get::<_, u64>(&changed_key) {
+        if now < changed_at + FEE_CHANGE_INTERVAL {
+            panic_with_error!(env, ContractError::TimelockActive);
+        }
+    }
+    env.storage().instance().set(&changed_key, &now);
+}
+
+/// Skew fee rate in force
+fn skew_fee_bps(env: &Env) -> i128 {
+    env.storage().instance().get(&SKEW_FEE).unwrap_or(SKEW_FEE_BPS)
+}
+
+/// The part of a trade that only reduces the current open interest skew
+fn balancing_size(env: &Env, value: i128, long: bool) -> i128 {
+    let total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap_or(0_i128);
+    let total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap_or(0_i128);
+    let (same_side, other_side) = if long {
+        (total_long, total_short)
+    } else {
+        (total_short, total_long)
+    };
+    // The first (other_side - same_side) of the trade only reduces skew
+    (other_side - same_side).clamp(0, value)
+}
+
+/// Expensive self-audit run at the end of every state-changing entrypoint
+/// in testnet builds: open interest totals must match the enumerated
+/// positions and the contract must hold at least the insurance fund.
+#[cfg(feature = "invariants")]
+fn check_invariants(env: &Env) {
+    let positions: Map<Address, Position> = env
+        .storage()
+        .persistent()
+        .get(&POSITIONS)
+        .unwrap_or_else(|| Map::new(env));
+    let mut sum_long: i128 = 0;
+    let mut sum_short: i128 = 0;
+    for (_, position) in positions.iter() {
+        if position.value <= 0 {
+            panic_with_error!(env, ContractError::InvariantViolated);
+        }
+        if position.is_long() {
+            sum_long += position.value;
+        } else {
+            sum_short += position.value;
+        }
+    }
+    let total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap_or(0_i128);
+    let total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap_or(0_i128);
+    if sum_long != total_long || sum_short != total_short {
+        panic_with_error!(env, ContractError::InvariantViolated);
+    }
+
+    let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+    let balance = TokenClient::new(env, &p_usd).balance(&env.current_contract_address());
+    let deployed: i128 = env.storage().instance().get(&YIELD_DEPLOYED).unwrap_or(0_i128);
+    let insurance: i128 = env.storage().instance().get(&INSURANCE).unwrap_or(0_i128);
+    let incentives = env
+        .storage()
+        .instance()
+        .get::<_, IncentiveBudget>(&INCENTIVES)
+        .map_or(0, |budget| budget.remaining);
+    let escrowed: i128 = env.storage().instance().get(&ORDER_ESCROW).unwrap_or(0_i128);
+    if balance + deployed < insurance + incentives + escrowed {
+        panic_with_error!(env, ContractError::InvariantViolated);
+    }
+}
+
+#[cfg(not(feature = "invariants"))]
+fn check_invariants(_env: &Env) {}
+
+/// Price used for trading: the frozen settlement price, else the SEP-40
+/// feed when one is configured, else the last price pushed by an oracle
+fn current_price(env: &Env) -> i128 {
+    if let Some(price) = env.storage().instance().get(&SETTLE_PRICE) {
+        return price;
+    }
+    latest_price(env).price
+}
+
+/// Index price plus a premium proportional to the open interest skew, so
+/// a crowded side is valued against the flow it would need to exit. The
+/// settlement price is used as is.
+fn mark_price(env: &Env, index: i128) -> i128 {
+    if env.storage().instance().has(&SETTLE_PRICE) {
+        return index;
+    }
+    let max_premium: i128 = env.storage().instance().get(&MARK_PREMIUM).unwrap_or(0);
+    let long_oi: i128 = env.storage().instance().get(&LONG_POS).unwrap_or(0_i128);
+    let short_oi: i128 = env.storage().instance().get(&SHORT_POS).unwrap_or(0_i128);
+    let total = long_oi + short_oi;
+    if max_premium == 0 || total == 0 {
+        return index;
+    }
+    index + div_floor(index * (long_oi - short_oi) * max_premium, total * 10000)
+}
+
+/// Fill price for a trade that buys (opening a long, closing a short) or
+/// sells at `price`: the confidence interval and the spread are added to
+/// buys and taken from sells. The settlement price is used as is.
+fn execution_price(env: &Env, price: i128, buying: bool) -> i128 {
+    if env.storage().instance().has(&SETTLE_PRICE) {
+        return price;
+    }
+    let confidence: i128 = env.storage().instance().get(&CONFIDENCE).unwrap_or(0);
+    let spread = spread_at(env, price);
+    if buying {
+        price + confidence + spread
+    } else {
+        price - confidence - spread
+    }
+}
+
+/// `execution_price` after the worst single oracle update the contract
+/// would accept without a second oracle confirming it: `price` moved
+/// against the trader by `MAX_PRICE_JUMP`, widened by the widest
+/// confidence any oracle has reported, plus the spread
+fn worst_execution_price(env: &Env, price: i128, buying: bool) -> i128 {
+    if env.storage().instance().has(&SETTLE_PRICE) {
+        return price;
+    }
+    let max_jump: i128 = env.storage().instance().get(&MAX_PRICE_JUMP).unwrap_or(0);
+    let jump = div_ceil(price * max_jump, 10000);
+    let confidences: Map<Address, i128> = env
+        .storage()
+        .instance()
+        .get(&ORACLE_CONF)
+        .unwrap_or_else(|| Map::new(env));
+    let current: i128 = env.storage().instance().get(&CONFIDENCE).unwrap_or(0);
+    let widest = confidences.values().iter().fold(current, i128::max);
+    let spread_bps: i128 = env.storage().instance().get(&SPREAD).unwrap_or(0);
+    if buying {
+        let moved = price + jump;
+        moved + widest + div_ceil(moved * spread_bps, 10000)
+    } else {
+        let moved = price - jump;
+        (moved - widest - div_ceil(moved * spread_bps, 10000)).max(1)
+    }
+}
+
+/// Half-spread a fill at index `price` pays, none once settling
+fn spread_at(env: &Env, price: i128) -> i128 {
+    if env.storage().instance().has(&SETTLE_PRICE) {
+        return 0;
+    }
+    let spread_bps: i128 = env.storage().instance().get(&SPREAD).unwrap_or(0);
+    div_ceil(price * spread_bps, 10000)
+}
+
+/// Fill price for closing `user`'s position at `price`, with the spread
+/// the fill pays
+fn exit_price(env: &Env, user: &Address, price: i128) -> (i128, i128) {
+    let positions: Map<Address, Position> = env
+        .storage()
+        .persistent()
+        .get(&POSITIONS)
+        .unwrap_or_else(|| Map::new(env));
+    match positions.get(user.clone()) {
+        Some(position) => (execution_price(env, price, !position.is_long()), spread_at(env, price)),
+        None => (price, 0),
+    }
+}
+
+/// `current_price` for execution: fails with `StalePrice` when the latest
+/// price is older than the configured maximum age
+fn fresh_price(env: &Env) -> i128 {
+    if let Some(price) = env.storage().instance().get(&SETTLE_PRICE) {
+        return price;
+    }
+    let latest = latest_price(env);
+    let max_age: u64 = env.storage().instance().get(&MAX_PRICE_AGE).unwrap_or(0);
+    if max_age > 0 && latest.timestamp + max_age < env.ledger().timestamp() {
+        panic_with_error!(env, ContractError::StalePrice);
+    }
+    latest.price
+}
+
+/// Latest price and its timestamp from the SEP-40 feed when one is
+/// configured, else from the last oracle push
+fn latest_price(env: &Env) -> PriceData {
+    if let Some(feed) = market_route(env).feed {
+        let asset = FeedAsset::Other(env.storage().instance().get(&MARKET).unwrap());
+        let data = PriceFeedClient::new(env, &feed)
+            .lastprice(&asset)
+            .unwrap_or_else(|| panic_with_error!(env, ContractError::PriceNotUpdated));
+        let price = normalize_price(env, data.price);
+        require_in_bounds(env, price);
+        return PriceData { price, timestamp: data.timestamp };
+    }
+    PriceData {
+        price: env.storage().instance().get(&PRICE).unwrap_or(0_i128),
+        timestamp: env.storage().instance().get(&PRICE_TIME).unwrap_or(0),
+    }
+}
+
+/// Verify a signed payload against the registered oracle key and fold it
+/// in as that oracle's report. Returns whether a new price was published.
+fn accept_signed_report(env: &Env, payload: PricePayload, signature: BytesN<64>, pubkey: BytesN<32>) -> bool {
+    let keys: Map<BytesN<32>, Address> = env
+        .storage()
+        .instance()
+        .get(&ORACLE_KEYS)
+        .unwrap_or_else(|| Map::new(env));
+    let oracle = keys
+        .get(pubkey.clone())
+        .unwrap_or_else(|| panic_with_error!(env, ContractError::NotOracle));
+    let asset: String = env.storage().instance().get(&ASSET).unwrap();
+    if payload.asset != asset {
+        panic_with_error!(env, ContractError::AssetMismatch);
+    }
+    let message = (env.ledger().network_id(), env.current_contract_address(), payload.clone()).to_xdr(env);
+    env.crypto().ed25519_verify(&pubkey, &message, &signature);
+    accept_report(env, &oracle, payload.price, payload.confidence, payload.timestamp)
+}
+
+/// Validate an oracle report and fold it into the median price. Returns
+/// false when the report is only buffered or held as a jump.
+fn accept_report(env: &Env, oracle: &Address, price: i128, confidence: i128, timestamp: u64) -> bool {
+    let price = normalize_price(env, price);
+    let confidence = normalize_price(env, confidence);
+    if !oracle_allowed(env, oracle) {
+        panic_with_error!(env, ContractError::NotOracle);
+    }
+    update_failover(env);
+    if env.storage().instance().has(&SETTLE_PRICE) {
+        panic_with_error!(env, ContractError::SettlementActive);
+    }
+    require_in_bounds(env, price);
+    packed_price(env, price);
+    if confidence < 0 || confidence >= price {
+        panic_with_error!(env, ContractError::InvalidPrice);
+    }
+
+    // One report per oracle per ledger, so a key cannot move the price
+    // back and forth around other calls in the same ledger
+    let mark_key = (ORACLE_MARK, oracle.clone());
+    let sequence = env.ledger().sequence();
+    if env.storage().temporary().get::<_, u32>(&mark_key) == Some(sequence) {
+        panic_with_error!(env, ContractError::AlreadyUpdated);
+    }
+    env.storage().temporary().set(&mark_key, &sequence);
+
+    let now = env.ledger().timestamp();
+    if timestamp > now {
+        panic_with_error!(env, ContractError::InvalidPrice);
+    }
+    let mut reports: Map<Address, PriceData> = env
+        .storage()
+        .instance()
+        .get(&ORACLE_REPORTS)
+        .unwrap_or_else(|| Map::new(env));
+    // Delayed or replayed reports can never replace a newer one
+    if let Some(previous) = reports.get(oracle.clone()) {
+        if timestamp <= previous.timestamp {
+            panic_with_error!(env, ContractError::OutdatedPrice);
+        }
+    }
+    reports.set(oracle.clone(), PriceData { price, timestamp });
+    env.storage().instance().set(&ORACLE_REPORTS, &reports);
+    let mut confidences: Map<Address, i128> = env
+        .storage()
+        .instance()
+        .get(&ORACLE_CONF)
+        .unwrap_or_else(|| Map::new(env));
+    confidences.set(oracle.clone(), confidence);
+    env.storage().instance().set(&ORACLE_CONF, &confidences);
+
+    let stats_key = (ORACLE_STATS, oracle.clone(), now / ORACLE_EPOCH);
+    let mut stats: OracleStats = env.storage().persistent().get(&stats_key).unwrap_or(OracleStats {
+        updates: 0,
+        total_deviation_bps: 0,
+        stale_reports: 0,
+    });
+    stats.updates += 1;
+    if timestamp + REPORT_WINDOW < now {
+        stats.stale_reports += 1;
+    }
+
+    // Collect recent reports in ascending price order, leaving out a
+    // primary oracle that has missed its heartbeat. The widest of their
+    // confidence intervals goes with the median.
+    let downed = env
+        .storage()
+        .instance()
+        .get::<_, FailoverConfig>(&FAILOVER)
+        .filter(|config| reports.get(config.primary.clone()).map_or(0, |report| report.timestamp) + config.heartbeat < now)
+        .map(|config| config.primary);
+    let mut fresh: Vec<i128> = Vec::new(env);
+    let mut widest: i128 = 0;
+    for (reporter, report) in reports.iter() {
+        if report.timestamp + REPORT_WINDOW < now || downed.as_ref() == Some(&reporter) {
+            continue;
+        }
+        widest = widest.max(confidences.get(reporter.clone()).unwrap_or(0));
+        insert_sorted(&mut fresh, report.price);
+    }
+    let quorum = oracle_quorum(env);
+    if fresh.len() < quorum {
+        env.storage().persistent().set(&stats_key, &stats);
+        env.events().publish((symbol_short!("REPORT"), oracle.clone()), (price, timestamp));
+        return false;
+    }
+    let median = median(&fresh);
+    stats.total_deviation_bps += div_ceil((price - median).abs() * 10000, median);
+    env.storage().persistent().set(&stats_key, &stats);
+
+    // Circuit breaker: a jump beyond the limit needs at least two
+    // oracles (and the quorum) reporting within the limit of the new
+    // price. A lone oracle confirms it with a second report within the
+    // limit of the first in a later ledger.
+    let max_jump: i128 = env.storage().instance().get(&MAX_PRICE_JUMP).unwrap_or(0);
+    let previous: i128 = env.storage().instance().get(&PRICE).unwrap_or(0);
+    if max_jump > 0 && previous > 0 && (median - previous).abs() * 10000 > max_jump * previous {
+        let mut agreeing: u32 = 0;
+        for report in fresh.iter() {
+            if (report - median).abs() * 10000 <= max_jump * median {
+                agreeing += 1;
+            }
+        }
+        let confirmed = active_oracles(env) < 2
+            && env
+                .storage()
+                .instance()
+                .get::<_, (i128, u32)>(&JUMP_SEEN)
+                .is_some_and(|(seen, ledger)| ledger < sequence && (median - seen).abs() * 10000 <= max_jump * seen);
+        if agreeing < quorum.max(2) && !confirmed {
+            env.storage().instance().set(&JUMP_SEEN, &(median, sequence));
+            env.events().publish((symbol_short!("JUMP"), oracle.clone()), (median, previous));
+            return false;
+        }
+    }
+    env.storage().instance().remove(&JUMP_SEEN);
+
+    let index = smoothed_price(env, median, previous);
+    count_grace(env, now);
+    env.storage().instance().set(&PRICE, &index);
+    env.storage().instance().set(&CONFIDENCE, &widest.min(index - 1));
+    env.storage().instance().set(&PRICE_TIME, &timestamp);
+    env.storage().instance().set(&PRICE_LEDGER, &sequence);
+    record_twap(env, index, now);
+    update_volatility_mode(env);
+    env.events().publish((PRICE, oracle.clone()), (index, timestamp));
+    true
+}
+
+/// Open the liquidation grace period when an accepted price ends a
+/// staleness outage, otherwise count one update off a running one
+fn count_grace(env: &Env, now: u64) {
+    let updates: u32 = env.storage().instance().get(&LIQ_GRACE).unwrap_or(0);
+    let max_age: u64 = env.storage().instance().get(&MAX_PRICE_AGE).unwrap_or(0);
+    let last: u64 = env.storage().instance().get(&PRICE_TIME).unwrap_or(0);
+    if updates > 0 && max_age > 0 && last > 0 && last + max_age < now {
+        env.storage().instance().set(&GRACE_LEFT, &updates);
+        env.events().publish((symbol_short!("GRACE"),), (last, updates));
+        return;
+    }
+    let left: u32 = env.storage().instance().get(&GRACE_LEFT).unwrap_or(0);
+    if left > 0 {
+        env.storage().instance().set(&GRACE_LEFT, &(left - 1));
+    }
+}
+
+/// Fail with `LiquidationGrace` while the grace period after a price
+/// outage is running
+fn require_no_grace(env: &Env) {
+    if env.storage().instance().get::<_, u32>(&GRACE_LEFT).unwrap_or(0) > 0 {
+        panic_with_error!(env, ContractError::LiquidationGrace);
+    }
+}
+
+/// Fold an accepted median into the stored price: as is, or as an
+/// exponential moving average when the market was initialized with an
+/// EMA weight. The first price is always taken as is.
+fn smoothed_price(env: &Env, median: i128, previous: i128) -> i128 {
+    let weight: u32 = env.storage().instance().get(&EMA_WEIGHT).unwrap_or(0);
+    if weight == 0 || previous == 0 {
+        return median;
+    }
+    previous + div_floor((median - previous) * i128::from(weight), 10000)
+}
+
+/// Insert `value` into an ascending list
+fn insert_sorted(sorted: &mut Vec<i128>, value: i128) {
+    let mut index = 0;
+    while index < sorted.len() && sorted.get_unchecked(index) < value {
+        index += 1;
+    }
+    sorted.insert(index, value);
+}
+
+/// Median of a non-empty ascending list; the floored mean of the middle
+/// two for an even count
+fn median(sorted: &Vec<i128>) -> i128 {
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 1 {
+        sorted.get_unchecked(mid)
+    } else {
+        div_floor(sorted.get_unchecked(mid - 1) + sorted.get_unchecked(mid), 2)
+    }
+}
+
+/// Configured quorum, clamped to the active oracles so that removing or
+/// suspending oracles cannot freeze the price. A failover pair counts
+/// once, since only one of the two reports at a time.
+fn oracle_quorum(env: &Env) -> u32 {
+    let quorum: u32 = env.storage().instance().get(&ORACLE_QUORUM).unwrap_or(1);
+    quorum.min(active_oracles(env)).max(1)
+}
+
+/// Enabled oracles that can report side by side, counting a failover
+/// pair once
+fn active_oracles(env: &Env) -> u32 {
+    let oracles = market_route(env).oracles;
+    let mut active = oracles.values().iter().filter(|enabled| *enabled).count() as u32;
+    if let Some(config) = env.storage().instance().get::<_, FailoverConfig>(&FAILOVER) {
+        if oracles.get(config.primary).unwrap_or(false) && oracles.get(config.backup).unwrap_or(false) {
+            active -= 1;
+        }
+    }
+    active
+}
+
+/// Whether `oracle` may report: it must be registered and not suspended,
+/// and the failover backup is held in reserve until the primary misses
+/// its heartbeat
+fn oracle_allowed(env: &Env, oracle: &Address) -> bool {
+    if !market_route(env).oracles.get(oracle.clone()).unwrap_or(false) {
+        return false;
+    }
+    match env.storage().instance().get::<_, FailoverConfig>(&FAILOVER) {
+        Some(config) if *oracle == config.backup => primary_last_seen(env, &config).is_some(),
+        _ => true,
+    }
+}
+
+/// Time of the primary's last report once it has missed its heartbeat
+fn primary_last_seen(env: &Env, config: &FailoverConfig) -> Option<u64> {
+    let reports: Map<Address, PriceData> = env
+        .storage()
+        .instance()
+        .get(&ORACLE_REPORTS)
+        .unwrap_or_else(|| Map::new(env));
+    let last_seen = reports.get(config.primary.clone()).map_or(0, |report| report.timestamp);
+    (last_seen + config.heartbeat < env.ledger().timestamp()).then_some(last_seen)
+}
+
+/// Track whether the backup is standing in for the primary. Emits
+/// `ORACLE_FAILOVER` when it takes over and clears the flag once the
+/// primary is back.
+fn update_failover(env: &Env) {
+    let Some(config) = env.storage().instance().get::<_, FailoverConfig>(&FAILOVER) else {
+        return;
+    };
+    let down = primary_last_seen(env, &config);
+    let active: bool = env.storage().instance().get(&FAILOVER_ON).unwrap_or(false);
+    if down.is_some() != active {
+        env.storage().instance().set(&FAILOVER_ON, &down.is_some());
+        if let Some(last_seen) = down {
+            env.events().publish((Symbol::new(env, "ORACLE_FAILOVER"), config.primary, config.backup), last_seen);
+        }
+    }
+}
+
+/// Add a TWAP checkpoint for a new price, dropping the oldest beyond
+/// `TWAP_OBSERVATIONS`
+fn record_twap(env: &Env, price: i128, timestamp: u64) {
+    let mut observations: Vec<TwapObservation> = env
+        .storage()
+        .instance()
+        .get(&TWAP)
+        .unwrap_or_else(|| Vec::new(env));
+    let cumulative = match observations.last() {
+        Some(last) => last.cumulative + last.price * i128::from(timestamp - last.timestamp),
+        None => 0,
+    };
+    // Several updates in one second only keep the latest price
+    if observations.last().is_some_and(|last| last.timestamp == timestamp) {
+        observations.pop_back();
+    }
+    observations.push_back(TwapObservation { timestamp, cumulative, price });
+    while observations.len() > TWAP_OBSERVATIONS {
+        observations.pop_front();
+    }
+    env.storage().instance().set(&TWAP, &observations);
+}
+
+/// Root mean square of the returns between consecutive price checkpoints,
+/// in bps. Zero with fewer than two checkpoints.
+fn realized_volatility(env: &Env) -> i128 {
+    let observations: Vec<TwapObservation> = env
+        .storage()
+        .instance()
+        .get(&TWAP)
+        .unwrap_or_else(|| Vec::new(env));
+    if observations.len() < 2 {
+        return 0;
+    }
+    let mut sum_squares: i128 = 0;
+    for index in 1..observations.len() {
+        let previous = observations.get_unchecked(index - 1).price;
+        let change = (observations.get_unchecked(index).price - previous) * 10000 / previous;
+        sum_squares += change * change;
+    }
+    isqrt(sum_squares / i128::from(observations.len() - 1))
+}
+
+/// Switch volatility mode on or off to match the guard, announcing
+/// changes with a `VOLMODE` event
+fn update_volatility_mode(env: &Env) {
+    let volatility = realized_volatility(env);
+    let active = env
+        .storage()
+        .instance()
+        .get::<_, VolatilityGuard>(&VOL_GUARD)
+        .is_some_and(|guard| guard.threshold_bps > 0 && volatility > guard.threshold_bps);
+    if active != env.storage().instance().get(&VOL_MODE).unwrap_or(false) {
+        env.storage().instance().set(&VOL_MODE, &active);
+        env.events().publish((symbol_short!("VOLMODE"),), (active, volatility));
+    }
+}
+
+/// Integer square root, rounded down
+fn isqrt(value: i128) -> i128 {
+    if value < 2 {
+        return value.max(0);
+    }
+    let mut x = value;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + value / x) / 2;
+    }
+    x
+}
+
+/// Feed symbol for the market's asset name: the base asset of a pair
+/// such as "BTC/USD", with any character a symbol cannot hold replaced
+/// by `_`
+fn asset_symbol(env: &Env, asset: &String) -> Symbol {
+    let mut buf = [0u8; 32];
+    let len = asset.len() as usize;
+    if len > buf.len() {
+        panic_with_error!(env, ContractError::AssetMismatch);
+    }
+    asset.copy_into_slice(&mut buf[..len]);
+    let base = buf[..len].split(|byte| *byte == b'/').next().unwrap_or(&[]);
+    let mut name = [0u8; 32];
+    for (slot, byte) in name.iter_mut().zip(base) {
+        *slot = if byte.is_ascii_alphanumeric() { *byte } else { b'_' };
+    }
+    let name = core::str::from_utf8(&name[..base.len()])
+        .unwrap_or_else(|_| panic_with_error!(env, ContractError::AssetMismatch));
+    Symbol::new(env, name)
+}
+
+/// Oracle route for this market's asset, keyed by the symbol fixed at
+/// initialize so display renames do not move it
+fn market_route(env: &Env) -> OracleRoute {
+    let market: Symbol = env.storage().instance().get(&MARKET).unwrap();
+    let routes: Map<Symbol, OracleRoute> = env.storage().instance().get(&ORACLE_ROUTES).unwrap();
+    routes.get(market).unwrap()
+}
+
+fn set_market_route(env: &Env, route: &OracleRoute) {
+    let market: Symbol = env.storage().instance().get(&MARKET).unwrap();
+    let mut routes: Map<Symbol, OracleRoute> = env
+        .storage()
+        .instance()
+        .get(&ORACLE_ROUTES)
+        .unwrap_or_else(|| Map::new(env));
+    routes.set(market, route.clone());
+    env.storage().instance().set(&ORACLE_ROUTES, &routes);
+}
+
+/// Position value at `price`, negative once losses exceed the margin
+fn position_value_at(position: &Position, price: i128) -> i128 {
+    let open_price = position.open_price();
+    let change = if position.is_long() {
+        price - open_price
+    } else {
+        open_price - price
+    };
+    position.value + div_floor(change * position.size_base, BASE_UNIT)
+}
+
+/// Maintenance margin in bps from version `version` of the tier curve,
+/// falling back to `MARGIN_REQ`
+fn maintenance_margin(env: &Env, notional: i128, version: u32) -> i128 {
+    let mut margin_req: i128 = env.storage().instance().get(&MARGIN_REQ).unwrap();
+    let tiers: Vec<MarginTier> = if version == margin_version(env) {
+        env.storage().instance().get(&MARGIN_TIERS)
+    } else {
+        env.storage().persistent().get(&(MARGIN_TIERS, version))
+    }
+    .unwrap_or_else(|| Vec::new(env));
+    for tier in tiers.iter() {
+        if notional < tier.min_notional {
+            break;
+        }
+        margin_req = tier.margin_bps;
+    }
+    margin_req
+}
+
+/// Maintenance margin in bps a position is liquidated below: its own
+/// tier curve, less the volatility buffer while volatility mode is on
+fn liquidation_margin(env: &Env, position: &Position) -> i128 {
+    let margin_req = maintenance_margin(env, position.notional(), position.margin_version);
+    if !env.storage().instance().get(&VOL_MODE).unwrap_or(false) {
+        return margin_req;
+    }
+    let buffer = env
+        .storage()
+        .instance()
+        .get::<_, VolatilityGuard>(&VOL_GUARD)
+        .map_or(0, |guard| guard.buffer_bps);
+    (margin_req - buffer).max(0)
+}
+
+/// Price at which a position falls below the maintenance margin
+fn liquidation_price(env: &Env, position: &Position) -> i128 {
+    let margin_req = liquidation_margin(env, position);
+    let required_val = div_ceil(position.value * margin_req, 10000);
+    let open_price = position.open_price();
+    let max_move = div_floor((position.value - required_val) * BASE_UNIT, position.size_base);
+    if position.is_long() {
+        open_price - max_move
+    } else {
+        open_price + max_move
+    }
+}
+
+/// Archive a user's position at `close_price`, update open interest
+/// and remove it. `exit_spread` is the half-spread per whole base unit
+/// the close paid. Returns the closed position and its settlement value.
+fn settle_position(env: &Env, user: &Address, close_price: i128, exit_spread: i128) -> (Position, i128) {
+    if let Some(final_at) = env.storage().instance().get::<_, u64>(&SETTLE_FINAL) {
+        if env.ledger().timestamp() < final_at {
+            panic_with_error!(env, ContractError::TimelockActive);
+        }
+    }
+    let mut positions: Map<Address, Position> = env
+        .storage()
+        .persistent()
+        .get(&POSITIONS)
+        .unwrap_or_else(|| panic_with_error!(env, ContractError::PositionNotOpen));
+    let position = positions
+        .get(user.clone())
+        .unwrap_or_else(|| panic_with_error!(env, ContractError::PositionNotOpen));
+    let ret_bal = position_value_at(&position, close_price).max(0);
+
+    // The spread paid on both legs is what the payout falls short of the
+    // value without it; credit that to the fee pool
+    let exit_spread = div_floor(position.size_base * exit_spread, BASE_UNIT);
+    let spread_revenue = (position_value_at(&position, close_price) + position.entry_spread + exit_spread).max(0) - ret_bal;
+    if spread_revenue > 0 {
+        let fee_pool: i128 = env.storage().instance().get(&FEE_POOL).unwrap_or(0_i128);
+        env.storage().instance().set(&FEE_POOL, &(fee_pool + spread_revenue));
+    }
+
+    // Update history
+    let mut closed = position.clone();
+    closed.close_price = packed_price(env, close_price);
+    record_user_history(env, user, &closed);
+
+    // Update totals and remove
+    let mut total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap();
+    let mut total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap();
+    if position.is_long() {
+        total_long -= position.value;
+        env.storage().instance().set(&LONG_POS, &total_long);
+    } else {
+        total_short -= position.value;
+        env.storage().instance().set(&SHORT_POS, &total_short);
+    }
+    positions.remove(user.clone());
+    env.storage().persistent().set(&POSITIONS, &positions);
+    env.storage().persistent().remove(&(MARGIN_CALL, user.clone()));
+    clear_trigger(env, &STOP_LOSS, position.id);
+    clear_trigger(env, &TAKE_PROFIT, position.id);
+    env.storage().persistent().remove(&(POS_LIFETIME, position.id));
+    env.storage().persistent().remove(&(POS_OWNER, position.id));
+    env.storage().persistent().remove(&(POS_APPROVE, position.id));
+    env.storage().persistent().remove(&(LIQ_SINCE, user.clone()));
+    pay_incentive(env, user, position.id);
+
+    (closed, ret_bal)
+}
+
+/// Count a call to entrypoint `name` moving `volume` of pUSD
+fn record_call(env: &Env, name: Symbol, volume: i128, failed: bool) {
+    let mut metrics: Map<Symbol, EntrypointMetrics> = env
+        .storage()
+        .instance()
+        .get(&METRICS)
+        .unwrap_or_else(|| Map::new(env));
+    let mut entry = metrics.get(name.clone()).unwrap_or_default();
+    entry.calls += 1;
+    if failed {
+        entry.failures += 1;
+    }
+    entry.volume += volume;
+    metrics.set(name, entry);
+    env.storage().instance().set(&METRICS, &metrics);
+}
+
+/// Book the yield source's gain or loss on the deployed insurance to the
+/// fund and return the deployed amount at its current value
+fn mark_yield(env: &Env) -> i128 {
+    let deployed: i128 = env.storage().instance().get(&YIELD_DEPLOYED).unwrap_or(0_i128);
+    let source = match env.storage().instance().get::<_, Address>(&YIELD_SOURCE) {
+        Some(source) if deployed > 0 => source,
+        _ => return deployed,
+    };
+    let value = YieldSourceClient::new(env, &source).balance(&env.current_contract_address());
+    let insurance: i128 = env.storage().instance().get(&INSURANCE).unwrap_or(0_i128);
+    env.storage().instance().set(&INSURANCE, &(insurance + value - deployed));
+    env.storage().instance().set(&YIELD_DEPLOYED, &value);
+    value
+}
+
+/// Withdraw `amount` of marked insurance from the yield source
+fn recall_from_source(env: &Env, amount: i128) {
+    let source: Address = env.storage().instance().get(&YIELD_SOURCE).unwrap();
+    let deployed: i128 = env.storage().instance().get(&YIELD_DEPLOYED).unwrap_or(0_i128);
+    YieldSourceClient::new(env, &source).withdraw(&env.current_contract_address(), &amount);
+    env.storage().instance().set(&YIELD_DEPLOYED, &(deployed - amount));
+    env.events().publish((symbol_short!("RECALL"),), amount);
+}
+
+/// Recall deployed insurance when the contract's own pUSD cannot cover a
+/// payout of `amount`, and charge the insurance fund for the part of the
+/// payout that only its funds cover
+fn cover_shortfall(env: &Env, amount: i128) {
+    let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+    let token = TokenClient::new(env, &p_usd);
+    let deployed: i128 = env.storage().instance().get(&YIELD_DEPLOYED).unwrap_or(0_i128);
+    if deployed > 0 && token.balance(&env.current_contract_address()) < amount {
+        let available = mark_yield(env);
+        let shortfall = (amount - token.balance(&env.current_contract_address())).min(available);
+        if shortfall > 0 {
+            recall_from_source(env, shortfall);
+        }
+    }
+
+    let balance = token.balance(&env.current_contract_address());
+    let deployed: i128 = env.storage().instance().get(&YIELD_DEPLOYED).unwrap_or(0_i128);
+    let insurance: i128 = env.storage().instance().get(&INSURANCE).unwrap_or(0_i128);
+    let incentives = env
+        .storage()
+        .instance()
+        .get::<_, IncentiveBudget>(&INCENTIVES)
+        .map_or(0, |budget| budget.remaining);
+    let escrowed: i128 = env.storage().instance().get(&ORDER_ESCROW).unwrap_or(0_i128);
+    let used = (insurance + incentives + escrowed + amount - balance - deployed).min(insurance);
+    if used > 0 {
+        env.storage().instance().set(&INSURANCE, &(insurance - used));
+        env.events().publish((symbol_short!("INSPAID"),), used);
+    }
+}
+
+/// Rescale an oracle price to pUSD units per whole base unit, so prices,
+/// margins and PnL share the token's fixed-point precision. Scaling down
+/// rounds towards zero.
+fn normalize_price(env: &Env, price: i128) -> i128 {
+    let token_decimals: u32 = env.storage().instance().get(&TOKEN_DECIMALS).unwrap_or(0);
+    rescale(price, market_route(env).decimals, token_decimals)
+}
+
+/// Move `amount` from `from` to `to` decimals, truncating towards zero
+/// when scaling down
+fn rescale(amount: i128, from: u32, to: u32) -> i128 {
+    if from >= to {
+        amount / 10_i128.pow(from - to)
+    } else {
+        amount * 10_i128.pow(to - from)
+    }
+}
+
+/// Decimals of `token`, rejecting precisions the contract cannot scale
+fn token_decimals(env: &Env, token: &Address) -> u32 {
+    let decimals = TokenClient::new(env, token).decimals();
+    if decimals > MAX_DECIMALS {
+        panic_with_error!(env, ContractError::InvalidDecimals);
+    }
+    decimals
+}
+
+/// Reject bounds that would let a zero or negative price through
+fn check_bounds(env: &Env, price_bounds: &PriceBounds) {
+    if price_bounds.min <= 0 || price_bounds.max < price_bounds.min {
+        panic_with_error!(env, ContractError::InvalidPrice);
+    }
+}
+
+/// Reject a price outside the configured sanity bounds
+fn require_in_bounds(env: &Env, price: i128) {
+    let price_bounds: PriceBounds = env.storage().instance().get(&PRICE_BOUNDS).unwrap();
+    if price < price_bounds.min || price > price_bounds.max {
+        panic_with_error!(env, ContractError::PriceOutOfBounds);
+    }
+}
+
+/// Narrow a price to the packed u64 representation, rejecting anything
+/// that is not a positive u64
+fn packed_price(env: &Env, price: i128) -> u64 {
+    match u64::try_from(price) {
+        Ok(packed) if packed > 0 => packed,
+        _ => panic_with_error!(env, ContractError::InvalidPrice),
+    }
+}
+
+// Rounding helpers. Every division that produces an amount owed to or by a
+// user rounds against the user so the contract can never pay out more than
+// it holds: fees and margin requirements round up, payouts round down.
+
+/// Division rounding towards negative infinity (`b` must be positive)
+fn div_floor(a: i128, b: i128) -> i128 {
+    a.div_euclid(b)
+}
+
+/// Division rounding towards positive infinity (`b` must be positive)
+fn div_ceil(a: i128, b: i128) -> i128 {
+    -((-a).div_euclid(b))
+}
+
+/// Append a closed position to the per-user history, archiving the
+/// oldest entries to events once `MAX_USER_HISTORY` is reached
+fn record_user_history(env: &Env, user: &Address, closed: &Position) {
+    let key = (TRADE_HISTORY, user.clone());
+    let mut history: Vec<Position> = env
+        .storage()
+        .persistent()
+        .get(&key)
+        .unwrap_or_else(|| Vec::new(env));
+    history.push_back(closed.clone());
+    while history.len() > MAX_USER_HISTORY {
+        let pruned = history.pop_front_unchecked();
+        let nonce = event_nonce(env, user);
+        env.events().publish((symbol_short!("ARCHIVE"), user.clone()), (pruned, nonce));
     }
+    env.storage().persistent().set(&key, &history);
 }
 
+pub mod testutils;
 mod test;
\ No newline at end of file
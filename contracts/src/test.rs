@@ -1,19 +1,21 @@
 #![cfg(test)]
+extern crate std;
 
 use super::*;
-use soroban_sdk::{testutils::{Address as _}, Address, Env, Vec, Map, IntoVal};
+use crate::testutils::{MockOracle, MockOracleClient};
+use soroban_sdk::{testutils::{Address as _, Events, Ledger}, Address, Env, Vec, Map, IntoVal};
 use sep_41_token::testutils::{MockTokenClient, MockTokenWASM};
 
 // Test helper to create a token mock that simulates the pUSD token
-fn create_token_contract(e: &Env) -> (Address, MockTokenClient) {
-    let admin = Address::generate(&e);
-    let token_id = e.register_contract_wasm(None, MockTokenWASM);
-    let token_client = MockTokenClient::new(&e, &token_id);
+fn create_token_contract(e: &Env) -> (Address, MockTokenClient<'_>) {
+    let admin = Address::generate(e);
+    let token_id = e.register(MockTokenWASM, ());
+    let token_client = MockTokenClient::new(e, &token_id);
     token_client.initialize(
         &admin,
         &7,
-        &String::from_str(&e, "Name"),
-        &String::from_str(&e, "Symbol"),
+        &String::from_str(e, "Name"),
+        &String::from_str(e, "Symbol"),
     );
     (token_id, token_client)
 }
@@ -21,18 +23,24 @@ fn create_token_contract(e: &Env) -> (Address, MockTokenClient) {
 
 // Test helper to setup test environment with initialized contract
 fn setup<'a>(e: &'a Env) -> (Address, PerpContractClient<'a>, Address, MockTokenClient<'a>) {
-    let client_id = e.register_contract(None, PerpContract);
+    let client_id = e.register(PerpContract, ());
     let client = PerpContractClient::new(e, &client_id);
+    let admin = Address::generate(e);
     let oracle = Address::generate(e);
     
     let (token_id, token) = create_token_contract(e);
     
-    // Initialize the contract
-    client.initialize(
+    // Initialize the contract, signed by the admin
+    client.mock_all_auths().initialize(
+        &admin,
         &"BTC".into_val(e),
         &10_i128,  // 10x leverage
         &token_id,
-        &oracle
+        &oracle,
+        &None,
+        &7,
+        &0,
+        &PriceBounds { min: 1, max: i128::MAX },
     );
     
     // Set a mock price
@@ -43,6 +51,34 @@ fn setup<'a>(e: &'a Env) -> (Address, PerpContractClient<'a>, Address, MockToken
     (client_id, client, token_id, token)
 }
 
+// Helper to build a liquidation request
+fn liquidation(user: &Address, tip_bps: u32, recipient: Option<Address>) -> LiquidationRequest {
+    LiquidationRequest { user: user.clone(), tip_bps, recipient }
+}
+
+// Helper to build a plain trade request at the default 10x leverage
+fn open_request(value: i128, long: bool) -> OpenRequest {
+    OpenRequest {
+        value,
+        long,
+        leverage: 10,
+        limit_price: None,
+        referrer: None,
+        valid_until_ledger: None,
+        reduce_only: false,
+    }
+}
+
+// Helper to build a BTC order sized in base units
+fn units_order(env: &Env, base_units: i128, long: bool, leverage: u32) -> UnitsOrder {
+    UnitsOrder { asset: String::from_str(env, "BTC"), base_units, long, leverage }
+}
+
+// Helper to fetch the oracle registered by `setup`
+fn oracle(env: &Env, client_id: &Address) -> Address {
+    PerpContractClient::new(env, client_id).get_oracles().first().unwrap()
+}
+
 // Helper to mint tokens for test users
 fn mint_tokens(env: &Env, token_id: &Address, user: &Address, amount: i128) {
     let mock_token = MockTokenClient::new(env, token_id);
@@ -101,21 +137,29 @@ fn test_place_trade_long() {
     // Approve spend
     token.approve(&trader, &client_id, &1000_i128, &0_u32);
     // Place a long trade
-    client.place_trade(&trader, &1000_i128, &true);
+    client.place_trade(&trader, &open_request(1000_i128, true));
     
     // Verify position was created and long position increased
     env.as_contract(&client.address, || {
         let positions: Map<Address, Position> = env.storage().persistent().get(&POSITIONS).unwrap();
         let position = positions.get(trader.clone()).unwrap();
         
-        assert_eq!(position.value, 1000_i128); // No fee in this simple case
-        assert_eq!(position.open_price, 50000_i128);
-        assert_eq!(position.close_price, 0_i128);
-        assert_eq!(position.long, true);
+        assert_eq!(position.value, 990_i128); // 1% fee for skewing an empty market
+        assert_eq!(position.open_price(), 50000_i128);
+        assert_eq!(position.close_price(), 0_i128);
+        assert!(position.is_long());
         
         let total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap();
-        assert_eq!(total_long, 1000_i128);
+        assert_eq!(total_long, 990_i128);
     });
+
+    // A second open while the first is live is refused
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    assert_eq!(
+        client.try_place_trade(&trader, &open_request(1000_i128, false)),
+        Err(Ok(ContractError::PositionOpen.into()))
+    );
 }
 
 
@@ -134,23 +178,43 @@ fn test_place_trade_short() {
     // Approve spend
     token.approve(&trader, &client_id, &500_i128, &0_u32);
     // Place a short trade
-    client.place_trade(&trader, &500_i128, &false);
+    client.place_trade(&trader, &open_request(500_i128, false));
     
     // Verify position was created and short position increased
     env.as_contract(&client.address, || {
         let positions: Map<Address, Position> = env.storage().persistent().get(&POSITIONS).unwrap();
         let position = positions.get(trader.clone()).unwrap();
         
-        assert_eq!(position.value, 500_i128);
-        assert_eq!(position.open_price, 50000_i128);
-        assert_eq!(position.close_price, 0_i128);
-        assert_eq!(position.long, false);
+        assert_eq!(position.value, 495_i128);
+        assert_eq!(position.open_price(), 50000_i128);
+        assert_eq!(position.close_price(), 0_i128);
+        assert!(!position.is_long());
         
         let total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap();
-        assert_eq!(total_short, 500_i128);
+        assert_eq!(total_short, 495_i128);
     });
 }
 
+#[test]
+#[should_panic(expected = "Error(Contract, #46)")]
+fn test_initialize_only_once() {
+    let env = Env::default();
+    let (_, client, token_id, _) = setup(&env);
+    env.mock_all_auths();
+    let attacker = Address::generate(&env);
+    client.initialize(
+        &attacker,
+        &"BTC".into_val(&env),
+        &100_i128,
+        &token_id,
+        &attacker,
+        &None,
+        &7,
+        &0,
+        &PriceBounds { min: 1, max: i128::MAX },
+    );
+}
+
 #[test]
 #[should_panic(expected = "Error(Contract, #3)")]
 fn test_place_trade_zero_value() {
@@ -164,7 +228,7 @@ fn test_place_trade_zero_value() {
     env.mock_all_auths();
     
     // Try to place a trade with zero value
-    client.place_trade(&trader, &0_i128, &true);
+    client.place_trade(&trader, &open_request(0_i128, true));
     // Expected to panic with ContractError::ZeroValue
 }
 
@@ -185,7 +249,7 @@ fn test_calculate_position_long_profit() {
     env.mock_all_auths();
 
     token.approve(&trader, &client_id, &1000_i128, &0_u32);
-    client.place_trade(&trader, &1000_i128, &true);
+    client.place_trade(&trader, &open_request(1000_i128, true));
     
     // Price goes up
     env.as_contract(&client_id, || {
@@ -203,11 +267,11 @@ fn test_calculate_position_long_profit() {
     // Expected profit calculation:
     // Price increase: 55000 - 50000 = 5000
     // Leverage: 10x
-    // Position size: 1000
-    // Multiplier: 10 * 1000 / 50000 = 0.2
-    // Profit: 5000 * 0.2 = 1000
-    // Total value: 1000 (initial) + 1000 (profit) = 2000
-    assert_eq!(position_value, 2000_i128);
+    // Position size: 990 (1000 less 1% fee)
+    // Multiplier: 10 * 990 / 50000 = 0.198
+    // Profit: 5000 * 0.198 = 990
+    // Total value: 990 (initial) + 990 (profit) = 1980
+    assert_eq!(position_value, 1980_i128);
 }
 
 
@@ -222,7 +286,7 @@ fn test_calculate_position_long_loss() {
     
     env.mock_all_auths();
     token.approve(&trader, &client_id, &1000_i128, &0_u32);
-    client.place_trade(&trader, &1000_i128, &true);
+    client.place_trade(&trader, &open_request(1000_i128, true));
     
     // Price goes down
     env.as_contract(&client_id, || {
@@ -234,8 +298,8 @@ fn test_calculate_position_long_loss() {
     
     // Expected loss calculation:
     // Price decrease: 50000 - 45000 = 5000
-    // Multiplier: 10 * 1000 / 50000 = 0.2
-    // Loss: 5000 * 0.2 = 1000
+    // Multiplier: 10 * 990 / 50000 = 0.198
+    // Loss: 5000 * 0.198 = 990
     // Since loss equals margin, position would be worth 0
     assert_eq!(position_value, 0_i128);
 }
@@ -252,7 +316,7 @@ fn test_calculate_position_short_profit() {
     
     env.mock_all_auths();
     token.approve(&trader, &client_id, &1000_i128, &0_u32);
-    client.place_trade(&trader, &1000_i128, &false);
+    client.place_trade(&trader, &open_request(1000_i128, false));
     
     // Price goes down (profit for short)
     env.as_contract(&client_id, || {
@@ -264,10 +328,10 @@ fn test_calculate_position_short_profit() {
     
     // Expected profit calculation:
     // Price decrease: 50000 - 45000 = 5000
-    // Multiplier: 10 * 1000 / 50000 = 0.2
-    // Profit: 5000 * 0.2 = 1000
-    // Total value: 1000 (initial) + 1000 (profit) = 2000
-    assert_eq!(position_value, 2000_i128);
+    // Multiplier: 10 * 990 / 50000 = 0.198
+    // Profit: 5000 * 0.198 = 990
+    // Total value: 990 (initial) + 990 (profit) = 1980
+    assert_eq!(position_value, 1980_i128);
 }
 
 
@@ -310,7 +374,7 @@ fn test_close_trade() {
     
     env.mock_all_auths();
     token.approve(&trader, &client_id, &1000_i128, &0_u32);
-    client.place_trade(&trader, &1000_i128, &true);
+    client.place_trade(&trader, &open_request(1000_i128, true));
     
     // Price goes up
     env.as_contract(&client_id, || {
@@ -318,21 +382,25 @@ fn test_close_trade() {
     });
     
     // Close the trade
-    client.close_trade(&trader);
+    client.close_trade(&trader, &None, &None);
+    let (_, topics, data) = env.events().all().last_unchecked();
+    assert_eq!(topics, (symbol_short!("CLOSE"), trader.clone()).into_val(&env));
+    let data: (Address, i128, i128, u64, u64) = data.into_val(&env);
+    assert_eq!(data, (trader.clone(), 55000_i128, 1980_i128, 1_u64, 2_u64));
     
     // Check the trade history and that position was removed
     env.as_contract(&client.address, || {
         let positions: Map<Address, Position> = env.storage().persistent().get(&POSITIONS).unwrap();
         assert!(!positions.contains_key(trader.clone()));
         
-        let history: Vec<Position> = env.storage().instance().get(&TRADE_HISTORY).unwrap();
+        let history: Vec<Position> = env.storage().persistent().get(&(TRADE_HISTORY, trader.clone())).unwrap();
         assert_eq!(history.len(), 1);
         
         let closed_position = history.get_unchecked(0);
-        assert_eq!(closed_position.value, 1000_i128);
-        assert_eq!(closed_position.open_price, 50000_i128);
-        assert_eq!(closed_position.close_price, 55000_i128);
-        assert_eq!(closed_position.long, true);
+        assert_eq!(closed_position.value, 990_i128);
+        assert_eq!(closed_position.open_price(), 50000_i128);
+        assert_eq!(closed_position.close_price(), 55000_i128);
+        assert!(closed_position.is_long());
         
         let total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap();
         assert_eq!(total_long, 0_i128);
@@ -351,7 +419,7 @@ fn test_close_nonexistent_trade() {
     
     env.mock_all_auths();
     // Try to close a non-existent position
-    client.close_trade(&trader);
+    client.close_trade(&trader, &None, &None);
     // Expected to panic with ContractError::PositionNotOpen
 }
 
@@ -370,7 +438,7 @@ fn test_liquidate_position() {
     
     env.mock_all_auths();
     token.approve(&trader, &client_id, &1000_i128, &0_u32);
-    client.place_trade(&trader, &1000_i128, &true);
+    client.place_trade(&trader, &open_request(1000_i128, true));
     
     // Price drops significantly - position now undercollateralized
     env.as_contract(&client_id, || {
@@ -378,18 +446,18 @@ fn test_liquidate_position() {
     });
     
     // Liquidate the position
-    client.liquidate_position(&liquidator, &trader);
+    client.liquidate_position(&liquidator, &liquidation(&trader, 0, None));
     
     // Check that position was removed and liquidator received reward
     env.as_contract(&client.address, || {
         let positions: Map<Address, Position> = env.storage().persistent().get(&POSITIONS).unwrap();
         assert!(!positions.contains_key(trader.clone()));
         
-        let history: Vec<Position> = env.storage().instance().get(&TRADE_HISTORY).unwrap();
+        let history: Vec<Position> = env.storage().persistent().get(&(TRADE_HISTORY, trader.clone())).unwrap();
         assert_eq!(history.len(), 1);
         
         let closed_position = history.get_unchecked(0);
-        assert_eq!(closed_position.close_price, 100_i128);
+        assert_eq!(closed_position.close_price(), 100_i128);
         
         let total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap();
         assert_eq!(total_long, 0_i128);
@@ -410,10 +478,10 @@ fn test_liquidate_healthy_position() {
     
     env.mock_all_auths();
     token.approve(&trader, &client_id, &1000_i128, &0_u32);
-    client.place_trade(&trader, &1000_i128, &true);
+    client.place_trade(&trader, &open_request(1000_i128, true));
     
     // Try to liquidate the position
-    client.liquidate_position(&liquidator, &trader);
+    client.liquidate_position(&liquidator, &liquidation(&trader, 0, None));
     // Expected to panic with ContractError::AboveMargin
 }
 
@@ -429,12 +497,29 @@ fn test_calculate_fee_balanced_market() {
         env.storage().instance().set(&LONG_POS, &5000_i128);
         env.storage().instance().set(&SHORT_POS, &5000_i128);
         
-        // Fee should be zero for both sides in balanced market
+        // Either side tips a balanced market, so both pay the full fee
         let fee_long = PerpContract::calculate_fee(&env, 1000_i128, true);
         let fee_short = PerpContract::calculate_fee(&env, 1000_i128, false);
         
-        assert_eq!(fee_long, 0);
-        assert_eq!(fee_short, 0);
+        assert_eq!(fee_long, 10);
+        assert_eq!(fee_short, 10);
+    });
+}
+
+#[test]
+fn test_calculate_fee_skew_flip() {
+    let env = Env::default();
+    let (client_id, _, _, _) = setup(&env);
+
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&LONG_POS, &5000_i128);
+        env.storage().instance().set(&SHORT_POS, &2000_i128);
+
+        // A short up to 3000 only rebalances the market
+        assert_eq!(PerpContract::calculate_fee(&env, 3000_i128, false), 0);
+
+        // Beyond that it flips the skew and pays on the excess
+        assert_eq!(PerpContract::calculate_fee(&env, 10000_i128, false), 70);
     });
 }
 
@@ -454,10 +539,10 @@ fn test_multiple_positions() {
     
     // Place different positions
     token.approve(&trader1, &client_id, &1000_i128, &0_u32);
-    client.place_trade(&trader1, &1000_i128, &true);  // Long
+    client.place_trade(&trader1, &open_request(1000_i128, true));  // Long
 
     token.approve(&trader2, &client_id, &2000_i128, &0_u32);
-    client.place_trade(&trader2, &2000_i128, &false); // Short
+    client.place_trade(&trader2, &open_request(2000_i128, false)); // Short
     
     // Verify positions were created correctly
     env.as_contract(&client.address, || {
@@ -466,16 +551,3228 @@ fn test_multiple_positions() {
         let position1 = positions.get(trader1.clone()).unwrap();
         let position2 = positions.get(trader2.clone()).unwrap();
         
-        assert_eq!(position1.value, 1000_i128);
-        assert_eq!(position1.long, true);
+        assert_eq!(position1.value, 990_i128);
+        assert!(position1.is_long());
         
-        assert_eq!(position2.value, 2000_i128);
-        assert_eq!(position2.long, false);
+        // 990 of the short rebalances, the remaining 1010 pays 1%
+        assert_eq!(position2.value, 1989_i128);
+        assert!(!position2.is_long());
         
         let total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap();
         let total_short: i128 = env.storage().instance().get(&SHORT_POS).unwrap();
         
-        assert_eq!(total_long, 1000_i128);
-        assert_eq!(total_short, 2000_i128);
+        assert_eq!(total_long, 990_i128);
+        assert_eq!(total_short, 1989_i128);
+    });
+}
+#[test]
+fn test_emit_history() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 2000_i128);
+
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &2000_i128, &0_u32);
+
+    // Open and close two trades to build up history
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    client.close_trade(&trader, &None, &None);
+    client.place_trade(&trader, &open_request(500_i128, false));
+    client.close_trade(&trader, &None, &None);
+
+    // Replay everything from the start
+    assert_eq!(client.emit_history(&trader, &0_u32, &10_u32), 2);
+    let events = env.events().all();
+    let (contract, topics, data) = events.get_unchecked(events.len() - 1);
+    assert_eq!(contract, client_id);
+    assert_eq!(topics, (TRADE_HISTORY, trader.clone()).into_val(&env));
+    let (index, closed): (u32, Position) = data.into_val(&env);
+    assert_eq!(index, 1);
+    assert_eq!(closed.value, 495_i128);
+    assert!(!closed.is_long());
+
+    // Paging past the end emits nothing
+    assert_eq!(client.emit_history(&trader, &1_u32, &10_u32), 1);
+    assert_eq!(client.emit_history(&trader, &2_u32, &10_u32), 0);
+
+    // Unknown users have no history
+    let stranger = Address::generate(&env);
+    assert_eq!(client.emit_history(&stranger, &0_u32, &10_u32), 0);
+}
+
+#[test]
+fn test_user_history_is_capped_and_archived() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader = Address::generate(&env);
+    let trades = MAX_USER_HISTORY + 1;
+    mint_tokens(&env, &token_id, &trader, 100_i128 * i128::from(trades));
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &(100_i128 * i128::from(trades)), &0_u32);
+
+    for _ in 0..MAX_USER_HISTORY {
+        client.place_trade(&trader, &open_request(100_i128, true));
+        client.close_trade(&trader, &None, &None);
+    }
+    // History is full of longs; the next close pushes the oldest out
+    env.as_contract(&client_id, || {
+        let history: Vec<Position> = env.storage().persistent().get(&(TRADE_HISTORY, trader.clone())).unwrap();
+        assert_eq!(history.len(), MAX_USER_HISTORY);
+    });
+
+    client.place_trade(&trader, &open_request(100_i128, false));
+    client.close_trade(&trader, &None, &None);
+    let archived = env
+        .events()
+        .all()
+        .iter()
+        .find(|(_, topics, _)| *topics == (symbol_short!("ARCHIVE"), trader.clone()).into_val(&env))
+        .map(|(_, _, data)| -> (Position, u64) { data.into_val(&env) })
+        .unwrap();
+    assert!(archived.0.is_long());
+
+    // Storage holds only the most recent trades, newest last
+    env.as_contract(&client_id, || {
+        let history: Vec<Position> = env.storage().persistent().get(&(TRADE_HISTORY, trader.clone())).unwrap();
+        assert_eq!(history.len(), MAX_USER_HISTORY);
+        assert!(!history.last_unchecked().is_long());
+    });
+}
+
+#[test]
+fn test_get_position_details_risk_tiers() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // 10x long of 990 from 50000 with a 3% maintenance margin:
+    // max adverse move = (990 - 30) * 50000 / (10 * 990) = 4848
+    let details = client.get_position_details(&trader);
+    assert_eq!(details.liquidation_price, 45152_i128);
+    assert_eq!(details.current_value, 990_i128);
+    assert_eq!(details.distance_bps, 969_i128);
+    assert_eq!(details.risk_tier, RiskTier::Warning);
+
+    // Price rallies well away from liquidation
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &60000_i128);
+    });
+    let details = client.get_position_details(&trader);
+    assert_eq!(details.distance_bps, 2474_i128);
+    assert_eq!(details.risk_tier, RiskTier::Safe);
+
+    // Price drops close to liquidation
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &46000_i128);
+    });
+    let details = client.get_position_details(&trader);
+    assert_eq!(details.risk_tier, RiskTier::Danger);
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #2)")]
+fn test_get_position_details_no_position() {
+    let env = Env::default();
+    let (_, client, _, _) = setup(&env);
+
+    let user = Address::generate(&env);
+    client.get_position_details(&user);
+}
+
+#[test]
+fn test_calculate_position_partial_loss() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // A 2% drop at 10x loses 20% of the 990 margin
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &49000_i128);
+    });
+    assert_eq!(client.calculate_position(&trader), 792_i128);
+}
+
+#[test]
+fn test_simulate_price_shock() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader1 = Address::generate(&env);
+    let trader2 = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader1, 1000_i128);
+    mint_tokens(&env, &token_id, &trader2, 2000_i128);
+
+    env.mock_all_auths();
+    token.approve(&trader1, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader1, &open_request(1000_i128, true));
+    token.approve(&trader2, &client_id, &2000_i128, &0_u32);
+    client.place_trade(&trader2, &open_request(2000_i128, false));
+
+    // No shock: nothing liquidatable and the vault is flat
+    let report = client.simulate_price_shock(&50000_i128);
+    assert_eq!(report.liquidatable, 0);
+    assert_eq!(report.vault_pnl, 0);
+
+    // 20% drop: the long of 990 is 990 underwater, the short of 1989 gains 3978
+    let report = client.simulate_price_shock(&40000_i128);
+    assert_eq!(report.price, 40000_i128);
+    assert_eq!(report.liquidatable, 1);
+    assert_eq!(report.notional_affected, 9900_i128);
+    assert_eq!(report.insurance_draw, 990_i128);
+    assert_eq!(report.vault_pnl, -2988_i128);
+
+    // Simulation must not touch live state
+    let price: i128 = env.as_contract(&client_id, || env.storage().instance().get(&PRICE).unwrap());
+    assert_eq!(price, 50000_i128);
+    assert_eq!(client.calculate_position(&trader1), 990_i128);
+}
+
+#[test]
+fn test_margin_tiers() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    env.mock_all_auths();
+    let tiers = Vec::from_array(
+        &env,
+        [
+            MarginTier { min_notional: 5000, margin_bps: 500 },
+            MarginTier { min_notional: 50000, margin_bps: 900 },
+        ],
+    );
+    client.set_margin_tiers(&tiers);
+
+    // Below the first tier the base requirement applies
+    assert_eq!(client.get_margin_req(&4000_i128), 300_i128);
+    assert_eq!(client.get_margin_req(&5000_i128), 500_i128);
+    assert_eq!(client.get_margin_req(&60000_i128), 900_i128);
+
+    // 990 at 10x sits in the 5% tier: max adverse move = (990 - 50) * 50000 / 9900
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    let details = client.get_position_details(&trader);
+    assert_eq!(details.liquidation_price, 45253_i128);
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #5)")]
+fn test_margin_tiers_unordered() {
+    let env = Env::default();
+    let (_, client, _, _) = setup(&env);
+
+    env.mock_all_auths();
+    let tiers = Vec::from_array(
+        &env,
+        [
+            MarginTier { min_notional: 50000, margin_bps: 500 },
+            MarginTier { min_notional: 10000, margin_bps: 900 },
+        ],
+    );
+    client.set_margin_tiers(&tiers);
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #6)")]
+fn test_place_trade_above_margin_tier() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    // Notional of 15000 or more requires 10% maintenance, which 10x cannot satisfy
+    env.mock_all_auths();
+    let tiers = Vec::from_array(&env, [MarginTier { min_notional: 15000, margin_bps: 1000 }]);
+    client.set_margin_tiers(&tiers);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 2000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &2000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(2000_i128, true));
+}
+
+#[test]
+fn test_emergency_close() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // 1% penalty on the 990 settlement stays with the insurance fund
+    client.emergency_close(&trader);
+    assert_eq!(token.balance(&trader), 980_i128);
+    assert_eq!(client.insurance_balance(), 10_i128);
+    assert_eq!(client.calculate_position(&trader), 0_i128);
+
+    env.as_contract(&client_id, || {
+        let total_long: i128 = env.storage().instance().get(&LONG_POS).unwrap();
+        assert_eq!(total_long, 0_i128);
+        let history: Vec<Position> = env.storage().persistent().get(&(TRADE_HISTORY, trader.clone())).unwrap();
+        assert_eq!(history.len(), 1);
+    });
+}
+
+#[test]
+fn test_rounding_helpers() {
+    assert_eq!(div_floor(7, 2), 3);
+    assert_eq!(div_floor(-7, 2), -4);
+    assert_eq!(div_ceil(7, 2), 4);
+    assert_eq!(div_ceil(-7, 2), -3);
+    assert_eq!(div_ceil(8, 2), 4);
+}
+
+#[test]
+fn test_fee_rounds_up() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&LONG_POS, &5000_i128);
+        env.storage().instance().set(&SHORT_POS, &2000_i128);
+    });
+
+    // 1% of 150 is 1.5, charged as 2
+    assert_eq!(client.calculate_fee(&150_i128, &true), 2_i128);
+}
+
+#[test]
+fn test_settlement_conserves_balance() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader1 = Address::generate(&env);
+    let trader2 = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader1, 333_i128);
+    mint_tokens(&env, &token_id, &trader2, 333_i128);
+
+    env.mock_all_auths();
+    token.approve(&trader1, &client_id, &333_i128, &0_u32);
+    client.place_trade(&trader1, &open_request(333_i128, true));
+    token.approve(&trader2, &client_id, &333_i128, &0_u32);
+    client.place_trade(&trader2, &open_request(333_i128, false));
+
+    // A move that produces fractional PnL on both sides
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &50007_i128);
+    });
+    client.close_trade(&trader1, &None, &None);
+    client.close_trade(&trader2, &None, &None);
+
+    // Offsetting positions can never pay out more than was deposited
+    let paid_out = token.balance(&trader1) + token.balance(&trader2);
+    assert!(paid_out <= 666_i128);
+    assert_eq!(token.balance(&client_id), 666_i128 - paid_out);
+}
+
+// Helper to open a 990 long at 50000 and drop the price to just inside liquidation
+fn open_liquidatable_long(env: &Env, client_id: &Address, token_id: &Address, token: &MockTokenClient) -> Address {
+    let client = PerpContractClient::new(env, client_id);
+    env.as_contract(client_id, || {
+        env.storage().instance().set(&PRICE, &50000_i128);
+    });
+    let trader = Address::generate(env);
+    mint_tokens(env, token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // Equity falls to 27, below the 30 maintenance requirement
+    env.as_contract(client_id, || {
+        env.storage().instance().set(&PRICE, &45140_i128);
+    });
+    assert_eq!(client.calculate_position(&trader), 27_i128);
+    trader
+}
+
+#[test]
+fn test_liquidation_charges_insurance_only_for_the_reward_paid() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = open_liquidatable_long(&env, &client_id, &token_id, &token);
+    let liquidator = Address::generate(&env);
+
+    // Insurance claims every pUSD the contract holds
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&INSURANCE, &1000_i128);
     });
-}
\ No newline at end of file
+    client.liquidate_position(&liquidator, &liquidation(&trader, 0, None));
+
+    // Only the reward of 9 leaves; the rest of the 27 stays in the contract
+    assert_eq!(token.balance(&liquidator), 9_i128);
+    assert_eq!(token.balance(&client_id), 991_i128);
+    assert_eq!(client.insurance_balance(), 991_i128);
+}
+
+#[test]
+fn test_liquidate_with_tip() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = open_liquidatable_long(&env, &client_id, &token_id, &token);
+    let liquidator = Address::generate(&env);
+
+    // Reward of 9, half tipped to the insurance fund (rounded up)
+    client.liquidate_position(&liquidator, &liquidation(&trader, 5000, None));
+    assert_eq!(token.balance(&liquidator), 4_i128);
+    assert_eq!(client.insurance_balance(), 5_i128);
+}
+
+#[test]
+fn test_second_liquidation_in_same_ledger_fails_cleanly() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = open_liquidatable_long(&env, &client_id, &token_id, &token);
+    let first = Address::generate(&env);
+    let second = Address::generate(&env);
+
+    client.liquidate_position(&first, &liquidation(&trader, 0, None));
+    let result = client.try_liquidate_position(&second, &liquidation(&trader, 0, None));
+    assert_eq!(result, Err(Ok(ContractError::PositionNotOpen.into())));
+    assert_eq!(token.balance(&first), 9_i128);
+    assert_eq!(token.balance(&second), 0_i128);
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #7)")]
+fn test_liquidation_bid_window_is_exclusive() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = open_liquidatable_long(&env, &client_id, &token_id, &token);
+    let keeper1 = Address::generate(&env);
+    let keeper2 = Address::generate(&env);
+
+    client.commit_liquidation_bid(&keeper1, &trader, &2000_u32);
+    client.liquidate_position(&keeper2, &liquidation(&trader, 0, None));
+}
+
+#[test]
+fn test_liquidation_bid_window_expires() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = open_liquidatable_long(&env, &client_id, &token_id, &token);
+    let keeper1 = Address::generate(&env);
+    let keeper2 = Address::generate(&env);
+
+    client.commit_liquidation_bid(&keeper1, &trader, &2000_u32);
+    client.commit_liquidation_bid(&keeper2, &trader, &5000_u32);
+
+    // The best bidder pays at least its committed tip
+    client.liquidate_position(&keeper2, &liquidation(&trader, 0, None));
+    assert_eq!(token.balance(&keeper2), 4_i128);
+    assert_eq!(client.insurance_balance(), 5_i128);
+
+    // Once the window passes anyone may liquidate at their own tip
+    let trader = open_liquidatable_long(&env, &client_id, &token_id, &token);
+    client.commit_liquidation_bid(&keeper1, &trader, &2000_u32);
+    env.ledger().with_mut(|li| li.sequence_number += 5);
+    client.liquidate_position(&keeper2, &liquidation(&trader, 0, None));
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #8)")]
+fn test_liquidation_bid_must_improve() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = open_liquidatable_long(&env, &client_id, &token_id, &token);
+    let keeper1 = Address::generate(&env);
+    let keeper2 = Address::generate(&env);
+
+    client.commit_liquidation_bid(&keeper1, &trader, &2000_u32);
+    client.commit_liquidation_bid(&keeper2, &trader, &2000_u32);
+}
+
+#[test]
+fn test_liquidation_bid_needs_liquidatable_position_and_capped_tip() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = open_liquidatable_long(&env, &client_id, &token_id, &token);
+    let keeper = Address::generate(&env);
+    assert_eq!(
+        client.try_commit_liquidation_bid(&keeper, &trader, &5001_u32),
+        Err(Ok(ContractError::InvalidTip.into()))
+    );
+    assert_eq!(
+        client.try_liquidate_position(&keeper, &liquidation(&trader, 10000, None)),
+        Err(Ok(ContractError::InvalidTip.into()))
+    );
+
+    // No reservation can be taken on a healthy position
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &50000_i128);
+    });
+    assert_eq!(
+        client.try_commit_liquidation_bid(&keeper, &trader, &2000_u32),
+        Err(Ok(ContractError::AboveMargin.into()))
+    );
+}
+
+#[test]
+fn test_close_trade_to_recipient() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader = Address::generate(&env);
+    let cold_wallet = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    client.close_trade(&trader, &Some(cold_wallet.clone()), &None);
+
+    // Only the position owner authorizes the payout route
+    let auths = env.auths();
+    assert_eq!(auths.len(), 1);
+    assert_eq!(auths[0].0, trader);
+
+    assert_eq!(token.balance(&cold_wallet), 990_i128);
+    assert_eq!(token.balance(&trader), 0_i128);
+}
+
+#[test]
+fn test_liquidation_reward_to_treasury() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = open_liquidatable_long(&env, &client_id, &token_id, &token);
+    let hot_key = Address::generate(&env);
+    let treasury = Address::generate(&env);
+
+    client.liquidate_position(&hot_key, &liquidation(&trader, 0, Some(treasury.clone())));
+    assert_eq!(token.balance(&treasury), 9_i128);
+    assert_eq!(token.balance(&hot_key), 0_i128);
+}
+
+#[test]
+fn test_position_packing() {
+    let position = Position::new(1000_i128, 50000_u64, 7_u32, 10_u32, true);
+    assert!(position.is_long());
+    assert_eq!(position.flags, 1);
+    assert_eq!(position.open_price(), 50000_i128);
+    assert_eq!(position.close_price(), 0_i128);
+    assert_eq!(position.opened_at_ledger, 7_u32);
+    assert_eq!(position.notional(), 10000_i128);
+    assert_eq!(position.size_base, 2_000_000_i128);
+    assert!(!Position::new(1000_i128, 50000_u64, 7_u32, 10_u32, false).is_long());
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #9)")]
+fn test_place_trade_without_price() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    env.as_contract(&client_id, || {
+        env.storage().instance().remove(&PRICE);
+    });
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+}
+
+#[test]
+fn test_fresh_price_required_before_close() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.ledger().with_mut(|li| li.sequence_number = 100);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    client.set_require_fresh_close(&true);
+    token.approve(&trader, &client_id, &1000_i128, &200_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    env.as_contract(&client_id, || {
+        let positions: Map<Address, Position> = env.storage().persistent().get(&POSITIONS).unwrap();
+        assert_eq!(positions.get(trader.clone()).unwrap().opened_at_ledger, 100_u32);
+    });
+
+    // Same price that the position opened on: close is refused
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE_LEDGER, &100_u32);
+    });
+    assert_eq!(
+        client.try_close_trade(&trader, &None, &None),
+        Err(Ok(ContractError::PriceNotUpdated.into()))
+    );
+
+    // A later price update unlocks the close
+    env.ledger().with_mut(|li| li.sequence_number = 101);
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE_LEDGER, &101_u32);
+    });
+    client.close_trade(&trader, &None, &None);
+    assert_eq!(token.balance(&trader), 990_i128);
+}
+
+#[test]
+fn test_force_close_at_settlement_price() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    mint_tokens(&env, &token_id, &client_id, 10000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // Freeze the settlement price at 51000
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &51000_i128);
+    });
+    client.start_settlement();
+    assert_eq!(client.get_settlement_price(), Some(51000_i128));
+
+    // Later oracle moves do not change the settlement value
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &40000_i128);
+    });
+
+    // Nothing settles until the dispute window has passed
+    assert_eq!(
+        client.try_force_close(&trader, &symbol_short!("DELIST")),
+        Err(Ok(ContractError::TimelockActive.into()))
+    );
+    env.ledger().with_mut(|li| li.timestamp += SETTLEMENT_DISPUTE_WINDOW);
+    client.force_close(&trader, &symbol_short!("DELIST"));
+
+    // 990 + 1000 * 10 * 990 / 50000 = 1188
+    assert_eq!(token.balance(&trader), 1188_i128);
+    env.as_contract(&client_id, || {
+        let history: Vec<Position> = env.storage().persistent().get(&(TRADE_HISTORY, trader.clone())).unwrap();
+        assert_eq!(history.get_unchecked(0).close_price(), 51000_i128);
+    });
+
+    // New trades are refused while settling
+    let late = Address::generate(&env);
+    assert_eq!(
+        client.try_place_trade(&late, &open_request(1000_i128, true)),
+        Err(Ok(ContractError::SettlementActive.into()))
+    );
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #12)")]
+fn test_force_close_requires_settlement() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    client.force_close(&trader, &symbol_short!("INCIDENT"));
+}
+
+#[test]
+fn test_asset_rename_timelock() {
+    let env = Env::default();
+    // Keep contract storage alive across the timelock
+    env.ledger().with_mut(|li| {
+        li.min_persistent_entry_ttl = 100_000;
+        li.max_entry_ttl = 100_000;
+    });
+    let (_, client, _, _) = setup(&env);
+    env.mock_all_auths();
+
+    let new_name = String::from_str(&env, "XBT");
+    client.propose_asset(&new_name);
+
+    // Too early
+    assert_eq!(client.try_apply_asset(), Err(Ok(ContractError::TimelockActive.into())));
+    assert_eq!(client.get_asset(), String::from_str(&env, "BTC"));
+
+    env.ledger().with_mut(|li| li.sequence_number += 17280);
+    client.apply_asset();
+    assert_eq!(client.get_asset(), new_name);
+
+    // Nothing left to apply
+    assert_eq!(client.try_apply_asset(), Err(Ok(ContractError::NoPendingChange.into())));
+}
+
+#[test]
+fn test_contract_address_book() {
+    let env = Env::default();
+    // Keep contract storage alive across the timelock
+    env.ledger().with_mut(|li| {
+        li.min_persistent_entry_ttl = 100_000;
+        li.max_entry_ttl = 100_000;
+    });
+    let (client_id, client, token_id, _) = setup(&env);
+    env.mock_all_auths();
+
+    let book = client.get_contracts();
+    assert_eq!(book.collateral, token_id);
+    assert_eq!(book.oracles, Vec::from_array(&env, [oracle(&env, &client_id)]));
+    assert_eq!(book.swap_router, None);
+    assert_eq!(book.linked.len(), 0);
+
+    // Linked contracts wait out the timelock
+    let treasury = Address::generate(&env);
+    client.propose_contract(&symbol_short!("TREASURY"), &Some(treasury.clone()));
+    assert_eq!(client.try_apply_contract(), Err(Ok(ContractError::TimelockActive.into())));
+    env.ledger().with_mut(|li| li.sequence_number += 17280);
+    client.apply_contract();
+    assert_eq!(client.get_contracts().linked.get(symbol_short!("TREASURY")), Some(treasury));
+    assert_eq!(client.try_apply_contract(), Err(Ok(ContractError::NoPendingChange.into())));
+
+    client.propose_contract(&symbol_short!("TREASURY"), &None);
+    env.ledger().with_mut(|li| li.sequence_number += 17280);
+    client.apply_contract();
+    assert_eq!(client.get_contracts().linked.len(), 0);
+}
+
+#[test]
+fn test_fee_modes_margin_vs_notional() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    // Margin mode: 1% of 1000 margin
+    assert_eq!(client.calculate_fee(&1000_i128, &true), 10_i128);
+
+    // Notional mode: 1% of 1000 * 10x notional
+    env.mock_all_auths();
+    client.set_fee_on_notional(&true);
+    assert_eq!(client.calculate_fee(&1000_i128, &true), 100_i128);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    let details = client.get_position_details(&trader);
+    assert_eq!(details.position.value, 900_i128);
+
+    // Rebalancing trades stay free in either mode
+    assert_eq!(client.calculate_fee(&900_i128, &false), 0_i128);
+}
+
+#[test]
+fn test_place_trade_units() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+
+    // 0.1 BTC at 50000 is 5000 notional, 1000 margin at 5x
+    let margin = client.place_trade_units(&trader, &units_order(&env, 1_000_000, true, 5));
+    assert_eq!(margin, 1000_i128);
+    assert_eq!(token.balance(&trader), 0_i128);
+
+    let details = client.get_position_details(&trader);
+    assert_eq!(details.position.value, 990_i128);
+    assert_eq!(details.position.leverage, 5_u32);
+
+    // PnL follows the position's own leverage, not the default
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &55000_i128);
+    });
+    assert_eq!(client.calculate_position(&trader), 1485_i128);
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #15)")]
+fn test_place_trade_units_above_max_leverage() {
+    let env = Env::default();
+    let (_, client, _, _) = setup(&env);
+
+    let trader = Address::generate(&env);
+    env.mock_all_auths();
+    client.place_trade_units(&trader, &units_order(&env, 1_000_000, true, 20));
+}
+
+#[test]
+fn test_small_position_pnl_uses_base_size() {
+    // 30 notional at 3,000,000 is 0.00001 units of the base asset
+    let position = Position::new(3_i128, 3_000_000_u64, 0_u32, 10_u32, true);
+    assert_eq!(position.size_base, 100_i128);
+
+    // A 10% move is worth 10% of the notional
+    assert_eq!(position_value_at(&position, 3_300_000_i128), 6_i128);
+    assert_eq!(position_value_at(&position, 2_700_000_i128), 0_i128);
+}
+
+#[test]
+fn test_place_trade_size_rounding_boundary() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    // At this price one base unit costs 10000 notional, i.e. 1000 margin at 10x
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &100_000_000_000_i128);
+    });
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 2021_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &2021_i128, &0_u32);
+
+    // 1010 less an 11 fee leaves 999 margin: too small for a single unit
+    assert_eq!(
+        client.try_place_trade(&trader, &open_request(1010_i128, true)),
+        Err(Ok(ContractError::PositionTooSmallForPrice.into()))
+    );
+
+    // 1011 less an 11 fee leaves exactly 1000
+    client.place_trade(&trader, &open_request(1011_i128, true));
+    let details = client.get_position_details(&trader);
+    assert_eq!(details.position.size_base, 1_i128);
+}
+
+#[test]
+fn test_get_skew() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+
+    // Empty market: both directions would skew it
+    let skew = client.get_skew();
+    assert_eq!(skew.imbalance_bps, 0);
+    assert_eq!(skew.long_fee_bps, 100);
+    assert_eq!(skew.short_fee_bps, 100);
+
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&LONG_POS, &6000_i128);
+        env.storage().instance().set(&SHORT_POS, &2000_i128);
+    });
+    let skew = client.get_skew();
+    assert_eq!(skew.long_oi, 6000);
+    assert_eq!(skew.short_oi, 2000);
+    assert_eq!(skew.imbalance_bps, 5000);
+    assert_eq!(skew.long_fee_bps, 100);
+    assert_eq!(skew.short_fee_bps, 0);
+    assert!(!skew.fee_on_notional);
+}
+
+#[cfg(feature = "invariants")]
+#[test]
+#[should_panic(expected = "Error(Contract, #17)")]
+fn test_invariants_catch_desynced_totals() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    // Corrupt the open interest counter behind the contract's back
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&LONG_POS, &1_i128);
+    });
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+}
+
+#[test]
+fn test_flag_margin_call_cooldown() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.ledger().with_mut(|li| li.timestamp = 1_000);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // Healthy position: nothing to flag
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &60000_i128);
+    });
+    assert!(!client.flag_margin_call(&trader));
+    assert_eq!(client.get_margin_call(&trader), None);
+
+    // Inside the warning band: flagged once per cooldown
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &47000_i128);
+    });
+    assert!(client.flag_margin_call(&trader));
+    assert_eq!(client.get_margin_call(&trader), Some(1_000));
+    assert!(!client.flag_margin_call(&trader));
+
+    env.ledger().with_mut(|li| li.timestamp = 1_000 + 3600);
+    assert!(client.flag_margin_call(&trader));
+    assert_eq!(client.get_margin_call(&trader), Some(4_600));
+
+    // Closing clears the flag
+    client.close_trade(&trader, &None, &None);
+    assert_eq!(client.get_margin_call(&trader), None);
+}
+
+#[test]
+fn test_units_order_auth_is_structured() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+
+    let order = units_order(&env, 1_000_000, true, 5);
+    client.place_trade_units(&trader, &order);
+
+    // The signed invocation carries the named order struct
+    let auths = env.auths();
+    let (signer, invocation) = auths.first().unwrap();
+    assert_eq!(signer, &trader);
+    match &invocation.function {
+        soroban_sdk::testutils::AuthorizedFunction::Contract((contract, name, args)) => {
+            assert_eq!(contract, &client_id);
+            assert_eq!(name, &Symbol::new(&env, "place_trade_units"));
+            let signed: UnitsOrder = args.get_unchecked(1).into_val(&env);
+            assert_eq!(signed.asset, String::from_str(&env, "BTC"));
+            assert_eq!(signed.base_units, 1_000_000);
+            assert!(signed.long);
+            assert_eq!(signed.leverage, 5);
+        }
+        _ => panic!("expected a contract invocation"),
+    }
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #18)")]
+fn test_units_order_wrong_asset() {
+    let env = Env::default();
+    let (_, client, _, _) = setup(&env);
+
+    let trader = Address::generate(&env);
+    env.mock_all_auths();
+    let mut order = units_order(&env, 1_000_000, true, 5);
+    order.asset = String::from_str(&env, "ETH");
+    client.place_trade_units(&trader, &order);
+}
+
+#[test]
+#[cfg(feature = "rewards")]
+fn test_incentive_rebate_decays_and_is_capped() {
+    let env = Env::default();
+    // Keep contract storage alive across an incentive epoch
+    env.ledger().with_mut(|li| {
+        li.min_persistent_entry_ttl = 100_000;
+        li.max_entry_ttl = 100_000;
+    });
+    let (client_id, client, token_id, token) = setup(&env);
+    let admin: Address = env.as_contract(&client_id, || env.storage().instance().get(&ADMIN).unwrap());
+    mint_tokens(&env, &token_id, &admin, 250_i128);
+    client.fund_incentives(&250_i128);
+    assert_eq!(client.get_incentives().unwrap().remaining, 250_i128);
+
+    let traders = [Address::generate(&env), Address::generate(&env), Address::generate(&env)];
+    for trader in traders.iter() {
+        mint_tokens(&env, &token_id, trader, 100_000_i128);
+        token.approve(trader, &client_id, &100_000_i128, &50_000_u32);
+    }
+
+    // Opening the skew earns nothing; closing it earns 0.5% of the balancing part
+    client.place_trade(&traders[0], &open_request(100_000_i128, true));
+    assert_eq!(client.get_incentives().unwrap().remaining, 250_i128);
+
+    // A round trip inside the holding period earns nothing
+    client.place_trade(&traders[1], &open_request(40_000_i128, false));
+    client.close_trade(&traders[1], &None, &None);
+    assert_eq!(token.balance(&traders[1]), 100_000_i128);
+    assert_eq!(client.get_incentives().unwrap().remaining, 250_i128);
+
+    // Held for the period, the 200 rebate is paid at close
+    client.place_trade(&traders[1], &open_request(40_000_i128, false));
+    env.ledger().with_mut(|li| li.sequence_number += INCENTIVE_EPOCH_LEDGERS);
+    client.place_trade(&traders[2], &open_request(40_000_i128, false));
+    client.close_trade(&traders[1], &None, &None);
+    assert_eq!(token.balance(&traders[1]), 100_200_i128);
+
+    // One epoch later the rebate has halved to 100, capped by the 50 left
+    env.ledger().with_mut(|li| li.sequence_number += INCENTIVE_HOLD_LEDGERS);
+    client.close_trade(&traders[2], &None, &None);
+    assert_eq!(token.balance(&traders[2]), 100_050_i128);
+    assert_eq!(client.get_incentives().unwrap().remaining, 0_i128);
+}
+
+#[test]
+fn test_set_price() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.ledger().with_mut(|li| {
+        li.sequence_number = 42;
+        li.timestamp = 1_700_000_000;
+    });
+
+    env.mock_all_auths();
+    client.set_price(&oracle, &51000_i128, &env.ledger().timestamp());
+
+    // Only the oracle signs the update
+    let auths = env.auths();
+    assert_eq!(auths.len(), 1);
+    assert_eq!(auths[0].0, oracle);
+    let (contract, topics, data) = env.events().all().last_unchecked();
+    assert_eq!(contract, client_id);
+    assert_eq!(topics, (PRICE, oracle.clone()).into_val(&env));
+    let (price, timestamp): (i128, u64) = data.into_val(&env);
+    assert_eq!((price, timestamp), (51000_i128, 1_700_000_000_u64));
+    env.as_contract(&client_id, || {
+        let price: i128 = env.storage().instance().get(&PRICE).unwrap();
+        let timestamp: u64 = env.storage().instance().get(&PRICE_TIME).unwrap();
+        let ledger: u32 = env.storage().instance().get(&PRICE_LEDGER).unwrap();
+        assert_eq!((price, timestamp, ledger), (51000_i128, 1_700_000_000_u64, 42_u32));
+    });
+
+    // Unknown oracles, non-positive prices and replays are rejected
+    let stranger = Address::generate(&env);
+    assert_eq!(
+        client.try_set_price(&stranger, &52000_i128, &env.ledger().timestamp()),
+        Err(Ok(ContractError::NotOracle.into()))
+    );
+    assert_eq!(
+        client.try_set_price(&oracle, &0_i128, &env.ledger().timestamp()),
+        Err(Ok(ContractError::PriceOutOfBounds.into()))
+    );
+    env.ledger().with_mut(|li| li.sequence_number += 1);
+    assert_eq!(
+        client.try_set_price(&oracle, &49000_i128, &1_699_999_999_u64),
+        Err(Ok(ContractError::OutdatedPrice.into()))
+    );
+}
+
+#[test]
+fn test_reconcile_sweeps_surplus_to_insurance() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // The 10 fee is in the fee pool; only a stray direct transfer is untracked
+    mint_tokens(&env, &token_id, &client_id, 5_i128);
+    assert_eq!(client.reconcile(), 5_i128);
+    assert_eq!(client.insurance_balance(), 5_i128);
+    assert_eq!(client.get_fee_pool(), 10_i128);
+
+    // Traders' unrealized losses are not swept, since the price can recover
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &49500_i128);
+    });
+    assert_eq!(client.reconcile(), 0_i128);
+    assert_eq!(client.insurance_balance(), 5_i128);
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &50000_i128);
+    });
+    client.close_trade(&trader, &None, &None);
+    assert_eq!(token.balance(&trader), 990_i128);
+}
+
+#[test]
+fn test_prices_from_sep40_feed() {
+    let env = Env::default();
+    env.mock_all_auths();
+    let feed_id = env.register(MockOracle, ());
+    let feed = MockOracleClient::new(&env, &feed_id);
+    let (token_id, token) = create_token_contract(&env);
+    let client_id = env.register(PerpContract, ());
+    let client = PerpContractClient::new(&env, &client_id);
+    client.initialize(
+        &Address::generate(&env),
+        &"BTC".into_val(&env),
+        &10_i128,
+        &token_id,
+        &Address::generate(&env),
+        &Some(feed_id.clone()),
+        &7,
+        &0,
+        &PriceBounds { min: 1, max: i128::MAX },
+    );
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    mint_tokens(&env, &token_id, &client_id, 1000_i128);
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+
+    // No feed price yet
+    assert_eq!(
+        client.try_place_trade(&trader, &open_request(1000_i128, true)),
+        Err(Ok(ContractError::PriceNotUpdated.into()))
+    );
+
+    let btc = FeedAsset::Other(Symbol::new(&env, "BTC"));
+    feed.set_price(&btc, &50000_i128);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // The position tracks the feed without any push to the perp
+    feed.set_price(&btc, &50500_i128);
+    assert_eq!(client.calculate_position(&trader), 1089_i128);
+    client.close_trade(&trader, &None, &None);
+    assert_eq!(token.balance(&trader), 1089_i128);
+}
+
+#[test]
+fn test_median_price_across_oracles() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    env.mock_all_auths();
+    env.ledger().with_mut(|li| li.timestamp = 1_000);
+    let first = oracle(&env, &client_id);
+    let second = Address::generate(&env);
+    let third = Address::generate(&env);
+    client.add_oracle(&second);
+    assert_eq!(
+        client.try_set_oracle_quorum(&3),
+        Err(Ok(ContractError::InvalidQuorum.into()))
+    );
+    client.add_oracle(&third);
+    client.set_oracle_quorum(&2);
+    assert_eq!(client.get_oracle_quorum(), 2);
+
+    let price = || -> i128 { env.as_contract(&client_id, || env.storage().instance().get(&PRICE).unwrap()) };
+
+    // A single report is buffered until the quorum is met
+    client.set_price(&first, &51000_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 50000_i128);
+    client.set_price(&second, &52000_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 51500_i128);
+
+    // An outlier cannot move the median of three
+    client.set_price(&third, &90000_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 52000_i128);
+
+    // Stale reports drop out of the window
+    env.ledger().with_mut(|li| {
+        li.timestamp = 1_000 + REPORT_WINDOW + 1;
+        li.sequence_number += 1;
+    });
+    client.set_price(&third, &53000_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 52000_i128);
+    client.set_price(&first, &53100_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 53050_i128);
+
+    // Removed oracles can no longer report
+    client.remove_oracle(&third);
+    assert_eq!(
+        client.try_set_price(&third, &53000_i128, &env.ledger().timestamp()),
+        Err(Ok(ContractError::NotOracle.into()))
+    );
+
+    // The quorum shrinks with the oracle set rather than freezing the price
+    client.remove_oracle(&second);
+    assert_eq!(client.get_oracle_quorum(), 1);
+    env.ledger().with_mut(|li| {
+        li.timestamp += 5;
+        li.sequence_number += 1;
+    });
+    client.set_price(&first, &53200_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 53200_i128);
+}
+
+#[test]
+fn test_quote_worst_case_matches_fill() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+
+    let quote = client.quote_worst_case(&1000_i128, &true, &10_u32);
+    assert_eq!(quote.price, 50000_i128);
+    assert_eq!(quote.fee, 10_i128);
+    assert_eq!(quote.size_base, 1_980_000_i128);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    let details = client.get_position_details(&trader);
+    assert_eq!(details.position.open_price(), quote.price);
+    assert_eq!(details.position.size_base, quote.size_base);
+    assert_eq!(details.liquidation_price, quote.liquidation_price);
+
+    assert_eq!(
+        client.try_quote_worst_case(&1000_i128, &true, &11_u32).err(),
+        Some(Ok(ContractError::InvalidLeverage.into()))
+    );
+}
+
+#[test]
+fn test_quote_worst_case_allows_for_jump_and_confidence() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    let first = oracle(&env, &client_id);
+    let second = Address::generate(&env);
+    env.mock_all_auths();
+    client.add_oracle(&second);
+    env.ledger().with_mut(|li| li.timestamp = 1_000);
+
+    // A 2% circuit breaker lets the next update move the fill by 1000
+    client.set_max_price_jump(&200_i128);
+    assert_eq!(client.quote_worst_case(&1000_i128, &true, &10_u32).price, 51000_i128);
+    assert_eq!(client.quote_worst_case(&1000_i128, &false, &10_u32).price, 49000_i128);
+
+    // The second oracle's wide interval still counts after its report
+    // drops out of the median
+    client.set_price_with_confidence(&second, &50000_i128, &300_i128, &1_000);
+    env.ledger().with_mut(|li| {
+        li.sequence_number += 1;
+        li.timestamp = 1_400;
+    });
+    client.set_price_with_confidence(&first, &50000_i128, &100_i128, &1_400);
+    assert_eq!(client.get_confidence(), 100_i128);
+    assert_eq!(client.quote_worst_case(&1000_i128, &true, &10_u32).price, 51300_i128);
+    assert_eq!(client.quote_worst_case(&1000_i128, &false, &10_u32).price, 48700_i128);
+}
+
+#[test]
+fn test_stale_price_blocks_execution() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.mock_all_auths();
+    env.ledger().with_mut(|li| li.timestamp = 10_000);
+    client.set_max_price_age(&60_u64);
+    client.set_price(&oracle, &50000_i128, &env.ledger().timestamp());
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // A minute later the price is still usable, a second after that it is not
+    env.ledger().with_mut(|li| {
+        li.timestamp = 10_061;
+        li.sequence_number += 1;
+    });
+    assert_eq!(
+        client.try_close_trade(&trader, &None, &None),
+        Err(Ok(ContractError::StalePrice.into()))
+    );
+    let keeper = Address::generate(&env);
+    assert_eq!(
+        client.try_liquidate_position(&keeper, &liquidation(&trader, 0, None)),
+        Err(Ok(ContractError::StalePrice.into()))
+    );
+
+    assert_eq!(
+        client.try_emergency_close(&trader),
+        Err(Ok(ContractError::StalePrice.into()))
+    );
+
+    // Views keep working and a fresh update unblocks the close
+    assert_eq!(client.calculate_position(&trader), 990_i128);
+    client.set_price(&oracle, &50000_i128, &env.ledger().timestamp());
+    client.close_trade(&trader, &None, &None);
+    assert_eq!(token.balance(&trader), 990_i128);
+}
+
+#[test]
+fn test_liquidation_grace_after_price_outage() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.mock_all_auths();
+    env.ledger().with_mut(|li| li.timestamp = 10_000);
+    client.set_max_price_age(&60_u64);
+    client.set_liquidation_grace(&2_u32);
+    client.set_price(&oracle, &50000_i128, &env.ledger().timestamp());
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // The first price after the outage already puts the long under water
+    env.ledger().with_mut(|li| {
+        li.timestamp = 10_500;
+        li.sequence_number += 1;
+    });
+    client.set_price(&oracle, &45140_i128, &env.ledger().timestamp());
+    assert_eq!(client.get_liquidation_grace(), 2_u32);
+    let keeper = Address::generate(&env);
+    assert_eq!(
+        client.try_liquidate_position(&keeper, &liquidation(&trader, 0, None)),
+        Err(Ok(ContractError::LiquidationGrace.into()))
+    );
+    // Nor can the backstop delay start running during the grace period
+    assert_eq!(
+        client.try_mark_liquidatable(&trader),
+        Err(Ok(ContractError::LiquidationGrace.into()))
+    );
+
+    // Liquidations resume once two more prices have been accepted
+    for _ in 0..2 {
+        env.ledger().with_mut(|li| {
+            li.timestamp += 5;
+            li.sequence_number += 1;
+        });
+        client.set_price(&oracle, &45140_i128, &env.ledger().timestamp());
+    }
+    assert_eq!(client.get_liquidation_grace(), 0_u32);
+    client.liquidate_position(&keeper, &liquidation(&trader, 0, None));
+    assert_eq!(token.balance(&keeper), 9_i128);
+}
+
+#[test]
+fn test_one_price_update_per_oracle_per_ledger() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.mock_all_auths();
+
+    client.set_price(&oracle, &51000_i128, &env.ledger().timestamp());
+    assert_eq!(
+        client.try_set_price(&oracle, &49000_i128, &env.ledger().timestamp()),
+        Err(Ok(ContractError::AlreadyUpdated.into()))
+    );
+
+    env.ledger().with_mut(|li| {
+        li.sequence_number += 1;
+        li.timestamp += 5;
+    });
+    client.set_price(&oracle, &49000_i128, &env.ledger().timestamp());
+    env.as_contract(&client_id, || {
+        let price: i128 = env.storage().instance().get(&PRICE).unwrap();
+        assert_eq!(price, 49000_i128);
+    });
+}
+
+#[test]
+fn test_twap_over_window() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.mock_all_auths();
+
+    let push = |timestamp: u64, price: i128| {
+        env.ledger().with_mut(|li| {
+            li.timestamp = timestamp;
+            li.sequence_number += 1;
+        });
+        client.set_price(&oracle, &price, &env.ledger().timestamp());
+    };
+    push(1_000, 50000);
+    assert_eq!(
+        client.try_get_twap(&60_u64),
+        Err(Ok(ContractError::PriceNotUpdated.into()))
+    );
+    push(1_100, 51000);
+    push(1_150, 53000);
+    env.ledger().with_mut(|li| li.timestamp = 1_200);
+
+    // 50 s at 51000 and 50 s at 53000
+    assert_eq!(client.get_twap(&100_u64), 52000_i128);
+    // 100 s at 50000, 50 s at 51000 and 50 s at 53000
+    assert_eq!(client.get_twap(&200_u64), 51000_i128);
+    // Longer than the recorded history
+    assert_eq!(
+        client.try_get_twap(&201_u64),
+        Err(Ok(ContractError::PriceNotUpdated.into()))
+    );
+}
+
+#[test]
+fn test_price_history() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.mock_all_auths();
+    assert_eq!(client.get_price_history(&5_u32).len(), 0);
+
+    for (timestamp, price) in [(1_000_u64, 50000_i128), (1_100, 51000), (1_200, 52000)] {
+        env.ledger().with_mut(|li| {
+            li.timestamp = timestamp;
+            li.sequence_number += 1;
+        });
+        client.set_price(&oracle, &price, &timestamp);
+    }
+
+    let history = client.get_price_history(&2_u32);
+    assert_eq!(history.len(), 2);
+    assert_eq!(history.get_unchecked(0), PriceData { price: 51000, timestamp: 1_100 });
+    assert_eq!(history.get_unchecked(1), PriceData { price: 52000, timestamp: 1_200 });
+    assert_eq!(client.get_price_history(&10_u32).len(), 3);
+}
+
+#[test]
+fn test_price_jump_needs_confirmation() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    let first = oracle(&env, &client_id);
+    let second = Address::generate(&env);
+    env.mock_all_auths();
+    client.add_oracle(&second);
+    client.set_max_price_jump(&1000_i128);
+    let price = || -> i128 { env.as_contract(&client_id, || env.storage().instance().get(&PRICE).unwrap()) };
+
+    // Within 10% of 50000 a single oracle moves the price
+    client.set_price(&first, &54000_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 54000_i128);
+
+    // A fat-finger print is held back
+    env.ledger().with_mut(|li| {
+        li.sequence_number += 1;
+        li.timestamp += 5;
+    });
+    client.set_price(&first, &5400_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 54000_i128);
+
+    // A genuine crash goes through once a second oracle agrees
+    env.ledger().with_mut(|li| {
+        li.sequence_number += 1;
+        li.timestamp += 5;
+    });
+    client.set_price(&first, &40000_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 54000_i128);
+    client.set_price(&second, &40100_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 40050_i128);
+}
+
+#[test]
+fn test_single_oracle_confirms_a_jump() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.mock_all_auths();
+    client.set_max_price_jump(&1000_i128);
+    let price = || -> i128 { env.as_contract(&client_id, || env.storage().instance().get(&PRICE).unwrap()) };
+    let next_ledger = || {
+        env.ledger().with_mut(|li| {
+            li.sequence_number += 1;
+            li.timestamp += 5;
+        })
+    };
+
+    // A one-off print is held back and a different one does not confirm it
+    next_ledger();
+    client.set_price(&oracle, &5400_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 50000_i128);
+    next_ledger();
+    client.set_price(&oracle, &40000_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 50000_i128);
+
+    // Reported again in a later ledger, the move goes through
+    next_ledger();
+    client.set_price(&oracle, &40100_i128, &env.ledger().timestamp());
+    assert_eq!(price(), 40100_i128);
+}
+
+#[test]
+fn test_add_and_remove_oracles() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    let original = oracle(&env, &client_id);
+    let backup = Address::generate(&env);
+    env.mock_all_auths();
+
+    client.add_oracle(&backup);
+    let oracles = client.get_oracles();
+    assert_eq!(oracles.len(), 2);
+    assert!(oracles.contains(&original) && oracles.contains(&backup));
+
+    // Rotating out the original leaves only the backup able to report
+    client.remove_oracle(&original);
+    assert_eq!(client.get_oracles(), Vec::from_array(&env, [backup.clone()]));
+    assert_eq!(
+        client.try_set_price(&original, &51000_i128, &env.ledger().timestamp()),
+        Err(Ok(ContractError::NotOracle.into()))
+    );
+    client.set_price(&backup, &51000_i128, &env.ledger().timestamp());
+}
+
+#[test]
+#[should_panic(expected = "Error(Auth, InvalidAction)")]
+fn test_add_oracle_requires_admin() {
+    let env = Env::default();
+    let (_, client, _, _) = setup(&env);
+    client.add_oracle(&Address::generate(&env));
+}
+
+#[test]
+fn test_place_trade_request_limits_and_referral() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    let referrer = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+
+    // Price is 50000: a long capped below it and a 20x request are refused
+    let mut request = OpenRequest {
+        value: 1000_i128,
+        long: true,
+        leverage: 5,
+        limit_price: Some(49990_i128),
+        referrer: Some(referrer.clone()),
+        valid_until_ledger: None,
+        reduce_only: false,
+    };
+    assert_eq!(
+        client.try_place_trade(&trader, &request),
+        Err(Ok(ContractError::SlippageExceeded.into()))
+    );
+    request.leverage = 20;
+    request.limit_price = Some(50000_i128);
+    assert_eq!(
+        client.try_place_trade(&trader, &request),
+        Err(Ok(ContractError::InvalidLeverage.into()))
+    );
+
+    request.leverage = 5;
+    client.place_trade(&trader, &request);
+    let (_, topics, data) = env.events().all().last_unchecked();
+    assert_eq!(topics, (symbol_short!("REFER"), referrer).into_val(&env));
+    let (referred, value, _): (Address, i128, u64) = data.into_val(&env);
+    assert_eq!((referred, value), (trader.clone(), 1000_i128));
+    assert_eq!(client.get_position_details(&trader).position.leverage, 5_u32);
+}
+
+#[test]
+fn test_reduce_only_request_closes_the_opposite_position() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+
+    // Nothing to reduce yet, and a same-side request would add exposure
+    let reduce_short = OpenRequest { reduce_only: true, ..open_request(1000_i128, false) };
+    assert_eq!(
+        client.try_place_trade(&trader, &reduce_short),
+        Err(Ok(ContractError::ReduceOnly.into()))
+    );
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    assert_eq!(
+        client.try_place_trade(&trader, &OpenRequest { reduce_only: true, ..open_request(1000_i128, true) }),
+        Err(Ok(ContractError::ReduceOnly.into()))
+    );
+
+    // The short closes the long at its limit and opens nothing
+    let limited = OpenRequest { limit_price: Some(50001_i128), ..reduce_short.clone() };
+    assert_eq!(
+        client.try_place_trade(&trader, &limited),
+        Err(Ok(ContractError::SlippageExceeded.into()))
+    );
+    client.place_trade(&trader, &reduce_short);
+    assert_eq!(token.balance(&trader), 990_i128);
+    assert_eq!(client.calculate_position(&trader), 0_i128);
+    assert_eq!(client.get_skew().short_oi, 0_i128);
+}
+
+#[test]
+fn test_get_liquidity() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    mint_tokens(&env, &token_id, &client_id, 500_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    let liquidity = client.get_liquidity();
+    assert_eq!(liquidity.balance, 1500_i128);
+    assert_eq!(liquidity.reserved, 990_i128);
+    assert_eq!(liquidity.insurance, 0_i128);
+    assert_eq!(liquidity.free, 510_i128);
+
+    // A 1% move at 10x adds 99 to what the trader would be paid
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &50500_i128);
+    });
+    let liquidity = client.get_liquidity();
+    assert_eq!(liquidity.reserved, 1089_i128);
+    assert_eq!(liquidity.free, 411_i128);
+}
+
+#[test]
+fn test_submit_signed_price() {
+    use ed25519_dalek::{Signer, SigningKey};
+    use soroban_sdk::{xdr::ToXdr, BytesN};
+
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.ledger().with_mut(|li| li.timestamp = 1_000);
+    let signer = SigningKey::from_bytes(&[7u8; 32]);
+    let pubkey = BytesN::from_array(&env, &signer.verifying_key().to_bytes());
+    env.mock_all_auths();
+    client.add_oracle_key(&oracle, &pubkey);
+
+    let sign_for = |contract: &Address, payload: &PricePayload| -> BytesN<64> {
+        let message = (env.ledger().network_id(), contract.clone(), payload.clone()).to_xdr(&env);
+        let message: std::vec::Vec<u8> = message.iter().collect();
+        BytesN::from_array(&env, &signer.sign(&message).to_bytes())
+    };
+    let sign = |payload: &PricePayload| sign_for(&client_id, payload);
+    let payload = PricePayload { asset: String::from_str(&env, "BTC"), price: 51000_i128, confidence: 0, timestamp: 990 };
+    let signature = sign(&payload);
+
+    // Relayed by anyone, no oracle auth needed
+    env.set_auths(&[]);
+    client.submit_signed_price(&payload, &signature, &pubkey);
+    env.as_contract(&client_id, || {
+        let price: i128 = env.storage().instance().get(&PRICE).unwrap();
+        assert_eq!(price, 51000_i128);
+    });
+
+    // Replays are outdated and tampered payloads fail verification
+    env.ledger().with_mut(|li| li.sequence_number += 1);
+    assert_eq!(
+        client.try_submit_signed_price(&payload, &signature, &pubkey),
+        Err(Ok(ContractError::OutdatedPrice.into()))
+    );
+    let tampered = PricePayload { price: 10_i128, timestamp: 995, ..payload.clone() };
+    assert!(client.try_submit_signed_price(&tampered, &signature, &pubkey).is_err());
+
+    // Unknown keys are rejected
+    let other = SigningKey::from_bytes(&[8u8; 32]);
+    let other_key = BytesN::from_array(&env, &other.verifying_key().to_bytes());
+    assert_eq!(
+        client.try_submit_signed_price(&tampered, &signature, &other_key),
+        Err(Ok(ContractError::NotOracle.into()))
+    );
+
+    // A payload signed for another market on the same key cannot be replayed here
+    let (other_market, _, _, _) = setup(&env);
+    let fresh = PricePayload { price: 52000_i128, timestamp: 995, ..payload.clone() };
+    assert!(client.try_submit_signed_price(&fresh, &sign_for(&other_market, &fresh), &pubkey).is_err());
+    client.submit_signed_price(&fresh, &sign(&fresh), &pubkey);
+}
+
+#[test]
+fn test_oracle_scoring_and_suspension() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    let good = oracle(&env, &client_id);
+    let also_good = Address::generate(&env);
+    let bad = Address::generate(&env);
+    env.mock_all_auths();
+    client.add_oracle(&also_good);
+    client.add_oracle(&bad);
+    client.set_oracle_quorum(&3);
+    client.set_oracle_threshold(&500_i128);
+
+    // Epoch 1: the bad oracle reports 20% high while the median holds
+    env.ledger().with_mut(|li| li.timestamp = ORACLE_EPOCH + 100);
+    client.set_price(&good, &50000_i128, &(ORACLE_EPOCH + 100));
+    client.set_price(&also_good, &50100_i128, &(ORACLE_EPOCH + 100));
+    client.set_price(&bad, &60000_i128, &(ORACLE_EPOCH + 100));
+    let stats = client.get_oracle_stats(&bad, &1_u64);
+    assert_eq!(stats, OracleStats { updates: 1, total_deviation_bps: 1977, stale_reports: 0 });
+    assert_eq!(client.get_oracle_stats(&also_good, &1_u64).total_deviation_bps, 0_i128);
+
+    // Epochs can only be reviewed once they are over
+    assert_eq!(
+        client.try_review_oracle(&bad, &1_u64),
+        Err(Ok(ContractError::EpochNotFinished.into()))
+    );
+    env.ledger().with_mut(|li| li.timestamp = 2 * ORACLE_EPOCH);
+    assert!(!client.review_oracle(&good, &1_u64));
+    assert!(client.review_oracle(&bad, &1_u64));
+
+    assert_eq!(client.get_oracles().len(), 2);
+    assert_eq!(
+        client.try_set_price(&bad, &50000_i128, &(2 * ORACLE_EPOCH)),
+        Err(Ok(ContractError::NotOracle.into()))
+    );
+}
+
+#[test]
+fn test_backup_oracle_failover() {
+    let env = Env::default();
+    let (client_id, client, _, _) = setup(&env);
+    let primary = oracle(&env, &client_id);
+    let backup = Address::generate(&env);
+    env.mock_all_auths();
+
+    // The backup has to be a registered oracle
+    assert_eq!(
+        client.try_set_failover(&primary, &backup, &120_u64),
+        Err(Ok(ContractError::NotOracle.into()))
+    );
+    client.add_oracle(&backup);
+    client.set_failover(&primary, &backup, &120_u64);
+    let price = || -> i128 { env.as_contract(&client_id, || env.storage().instance().get(&PRICE).unwrap()) };
+
+    env.ledger().with_mut(|li| li.timestamp = 1_000);
+    client.set_price(&primary, &51000_i128, &1_000);
+    assert_eq!(client.get_last_report(&primary).unwrap().timestamp, 1_000);
+
+    // While the primary keeps its heartbeat the backup is refused
+    env.ledger().with_mut(|li| {
+        li.timestamp = 1_120;
+        li.sequence_number += 1;
+    });
+    assert_eq!(
+        client.try_set_price(&backup, &52000_i128, &1_120),
+        Err(Ok(ContractError::NotOracle.into()))
+    );
+
+    // After a missed heartbeat the backup takes over
+    env.ledger().with_mut(|li| {
+        li.timestamp = 1_121;
+        li.sequence_number += 1;
+    });
+    client.set_price(&backup, &52000_i128, &1_121);
+    let failover = env.events().all().iter().any(|(_, topics, _)| {
+        topics == (Symbol::new(&env, "ORACLE_FAILOVER"), primary.clone(), backup.clone()).into_val(&env)
+    });
+    assert!(failover);
+    assert_eq!(price(), 52000_i128);
+
+    // Once the primary is back the backup is refused again
+    env.ledger().with_mut(|li| {
+        li.timestamp = 1_130;
+        li.sequence_number += 1;
+    });
+    client.set_price(&primary, &52500_i128, &1_130);
+    env.ledger().with_mut(|li| {
+        li.timestamp = 1_140;
+        li.sequence_number += 1;
+    });
+    assert_eq!(
+        client.try_set_price(&backup, &53000_i128, &1_140),
+        Err(Ok(ContractError::NotOracle.into()))
+    );
+
+    // A backup removed since cannot stand in any more
+    client.remove_oracle(&backup);
+    env.ledger().with_mut(|li| {
+        li.timestamp = 1_300;
+        li.sequence_number += 1;
+    });
+    assert_eq!(
+        client.try_set_price(&backup, &53000_i128, &1_300),
+        Err(Ok(ContractError::NotOracle.into()))
+    );
+}
+
+#[test]
+fn test_skew_fee_changes_are_smoothed() {
+    let env = Env::default();
+    let (_, client, _, _) = setup(&env);
+    env.mock_all_auths();
+    env.ledger().with_mut(|li| li.timestamp = 1_000);
+
+    // At most 25% of the current 100 bps per change
+    assert_eq!(
+        client.try_set_skew_fee(&130_i128),
+        Err(Ok(ContractError::ParameterChangeTooLarge.into()))
+    );
+    client.set_skew_fee(&125_i128);
+    assert_eq!(client.get_skew().long_fee_bps, 125_i128);
+
+    // And no more than once a day
+    assert_eq!(
+        client.try_set_skew_fee(&100_i128),
+        Err(Ok(ContractError::TimelockActive.into()))
+    );
+    env.ledger().with_mut(|li| li.timestamp = 1_000 + FEE_CHANGE_INTERVAL);
+    client.set_skew_fee(&94_i128);
+    assert_eq!(client.calculate_fee(&10000_i128, &true), 94_i128);
+}
+
+#[test]
+fn test_oracle_decimals_are_normalized() {
+    let env = Env::default();
+    env.mock_all_auths();
+    let token_id = env.register(MockTokenWASM, ());
+    let token = MockTokenClient::new(&env, &token_id);
+    token.initialize(
+        &Address::generate(&env),
+        &6,
+        &String::from_str(&env, "Name"),
+        &String::from_str(&env, "Symbol"),
+    );
+    let client_id = env.register(PerpContract, ());
+    let client = PerpContractClient::new(&env, &client_id);
+    let oracle = Address::generate(&env);
+    client.initialize(
+        &Address::generate(&env),
+        &"BTC".into_val(&env),
+        &10_i128,
+        &token_id,
+        &oracle,
+        &None,
+        &8,
+        &0,
+        &PriceBounds { min: 1, max: i128::MAX },
+    );
+
+    // 50,000 with 8 decimals is stored with the token's 6
+    client.set_price(&oracle, &5_000_000_000_000_i128, &env.ledger().timestamp());
+    env.as_contract(&client_id, || {
+        let price: i128 = env.storage().instance().get(&PRICE).unwrap();
+        assert_eq!(price, 50_000_000_000_i128);
+    });
+
+    // 0.1 BTC at 10x takes 500 pUSD of margin, in 6 decimals
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1_000_000_000_i128);
+    token.approve(&trader, &client_id, &1_000_000_000_i128, &0_u32);
+    let margin = client.place_trade_units(&trader, &units_order(&env, 1_000_000, true, 10));
+    assert_eq!(margin, 500_000_000_i128);
+    let details = client.get_position_details(&trader);
+    assert_eq!(details.position.size_base, 990_000_i128); // 0.099 BTC after the 1% fee
+
+    // Precision beyond 18 decimals is refused
+    let other = PerpContractClient::new(&env, &env.register(PerpContract, ()));
+    assert_eq!(
+        other.try_initialize(
+            &Address::generate(&env),
+            &"BTC".into_val(&env),
+            &10_i128,
+            &token_id,
+            &oracle,
+            &None,
+            &19,
+            &0,
+            &PriceBounds { min: 1, max: i128::MAX },
+        ),
+        Err(Ok(ContractError::InvalidDecimals.into()))
+    );
+}
+
+#[test]
+fn test_mark_price_values_positions_but_not_fills() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.mock_all_auths();
+
+    assert_eq!(
+        client.try_set_mark_premium(&(MAX_MARK_PREMIUM_BPS + 1)),
+        Err(Ok(ContractError::InvalidPrice.into()))
+    );
+    // Changes move 1 bps from zero, then a quarter of the current premium a day
+    assert_eq!(
+        client.try_set_mark_premium(&100_i128),
+        Err(Ok(ContractError::ParameterChangeTooLarge.into()))
+    );
+    client.set_mark_premium(&1_i128);
+    assert_eq!(
+        client.try_set_mark_premium(&2_i128),
+        Err(Ok(ContractError::TimelockActive.into()))
+    );
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&MARK_PREMIUM, &100_i128);
+    });
+    assert_eq!(client.get_mark_price(), 50000_i128); // No open interest, no premium
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // All open interest is long, so the full 1% premium applies
+    assert_eq!(client.get_mark_price(), 50500_i128);
+    // Size 1_980_000 base units gains 500 × 0.198 = 99 at the mark
+    assert_eq!(client.calculate_position(&trader), 1089_i128);
+
+    // The close fills at the index
+    client.close_trade(&trader, &None, &None);
+    assert_eq!(token.balance(&trader), 990_i128);
+}
+
+// Router swapping at a fixed 2:1 rate out of its own balance. It ignores
+// `min_out`, so the perp's own check is what the test exercises.
+#[contract]
+pub struct MockRouter;
+
+#[contractimpl]
+impl MockRouter {
+    pub fn swap_exact_in(
+        env: Env,
+        from: Address,
+        token_in: Address,
+        token_out: Address,
+        amount_in: i128,
+        _min_out: i128,
+        to: Address,
+    ) -> i128 {
+        let router = env.current_contract_address();
+        TokenClient::new(&env, &token_in).transfer_from(&router, &from, &router, &amount_in);
+        TokenClient::new(&env, &token_out).transfer(&router, &to, &(amount_in * 2));
+        amount_in * 2
+    }
+}
+
+#[test]
+fn test_close_trade_for_asset() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.mock_all_auths();
+    let (xlm_id, xlm) = create_token_contract(&env);
+    let router = env.register(MockRouter, ());
+    mint_tokens(&env, &xlm_id, &router, 10000_i128);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 2000_i128);
+    token.approve(&trader, &client_id, &2000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // Not whitelisted yet
+    client.set_swap_router(&router);
+    assert_eq!(
+        client.try_close_trade_for_asset(&trader, &xlm_id, &1_i128),
+        Err(Ok(ContractError::AssetNotAllowed.into()))
+    );
+    client.set_payout_asset(&xlm_id, &true);
+
+    // 990 pUSD swaps to 1980 XLM, short of the trader's minimum
+    assert_eq!(
+        client.try_close_trade_for_asset(&trader, &xlm_id, &1981_i128),
+        Err(Ok(ContractError::SlippageExceeded.into()))
+    );
+    assert_eq!(client.close_trade_for_asset(&trader, &xlm_id, &1980_i128), 1980_i128);
+    assert_eq!(xlm.balance(&trader), 1980_i128);
+    assert_eq!(token.balance(&trader), 1000_i128);
+    assert_eq!(token.balance(&router), 990_i128);
+}
+
+#[test]
+fn test_suspended_account_can_only_exit() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.mock_all_auths();
+    env.ledger().with_mut(|li| li.timestamp = 1_000);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 2000_i128);
+    token.approve(&trader, &client_id, &2000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    client.suspend_account(&trader, &true, &3600_u64);
+    assert_eq!(client.get_suspension(&trader), Some(Suspension { open_only: true, until: 4_600 }));
+    assert_eq!(
+        client.try_place_trade_units(&trader, &units_order(&env, 1_000_000, true, 10)),
+        Err(Ok(ContractError::AccountSuspended.into()))
+    );
+
+    // Exits are never blocked
+    client.close_trade(&trader, &None, &None);
+    assert_eq!(token.balance(&trader), 1990_i128);
+
+    // A full suspension also keeps the account from liquidating
+    client.suspend_account(&trader, &false, &3600_u64);
+    assert_eq!(
+        client.try_liquidate_position(&trader, &liquidation(&Address::generate(&env), 0, None)),
+        Err(Ok(ContractError::AccountSuspended.into()))
+    );
+
+    // And lapses on its own
+    env.ledger().with_mut(|li| li.timestamp = 4_600);
+    assert_eq!(client.get_suspension(&trader), None);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+}
+
+#[test]
+fn test_fills_widen_by_confidence() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.mock_all_auths();
+
+    assert_eq!(
+        client.try_set_price_with_confidence(&oracle, &50000_i128, &50000_i128, &0),
+        Err(Ok(ContractError::InvalidPrice.into()))
+    );
+    client.set_price_with_confidence(&oracle, &50000_i128, &500_i128, &0);
+    assert_eq!(client.get_confidence(), 500_i128);
+    assert_eq!(client.quote_worst_case(&1000_i128, &true, &10_u32).price, 50500_i128);
+    assert_eq!(client.quote_worst_case(&1000_i128, &false, &10_u32).price, 49500_i128);
+
+    // The long buys at 50500 and sells back at 49500
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    assert_eq!(client.get_position_details(&trader).position.open_price(), 50500_i128);
+    client.close_trade(&trader, &None, &None);
+    // Size 1_960_396 base units loses 1000 × 0.1960396 = 197 (floored)
+    assert_eq!(token.balance(&trader), 793_i128);
+}
+
+#[test]
+fn test_recompute_totals_repairs_drift() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.mock_all_auths();
+    let long_trader = Address::generate(&env);
+    let short_trader = Address::generate(&env);
+    for trader in [&long_trader, &short_trader] {
+        mint_tokens(&env, &token_id, trader, 1000_i128);
+        token.approve(trader, &client_id, &1000_i128, &0_u32);
+    }
+    client.place_trade(&long_trader, &open_request(1000_i128, true));
+    client.place_trade(&short_trader, &open_request(500_i128, false));
+
+    // Corrupt the open interest counter behind the contract's back
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&LONG_POS, &7_i128);
+    });
+
+    // Passes start at page 0
+    assert_eq!(
+        client.try_recompute_totals(&1_u32),
+        Err(Ok(ContractError::RecomputeStale.into()))
+    );
+    assert!(client.recompute_totals(&0_u32));
+    let skew = client.get_skew();
+    assert_eq!((skew.long_oi, skew.short_oi), (990_i128, 500_i128));
+    let (_, topics, data) = env.events().all().last_unchecked();
+    assert_eq!(topics, (symbol_short!("RECOMPUTE"),).into_val(&env));
+    let deltas: (i128, i128) = data.into_val(&env);
+    assert_eq!(deltas, (983_i128, 0_i128));
+}
+
+#[test]
+fn test_spread_on_entry_and_exit() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.mock_all_auths();
+
+    assert_eq!(
+        client.try_set_spread(&(MAX_SPREAD_BPS + 1)),
+        Err(Ok(ContractError::InvalidPrice.into()))
+    );
+    // Open positions pay the exit spread, so it moves as gradually as the skew fee
+    assert_eq!(
+        client.try_set_spread(&100_i128),
+        Err(Ok(ContractError::ParameterChangeTooLarge.into()))
+    );
+    client.set_spread(&1_i128);
+    assert_eq!(client.try_set_spread(&2_i128), Err(Ok(ContractError::TimelockActive.into())));
+    env.ledger().with_mut(|li| li.timestamp += FEE_CHANGE_INTERVAL);
+    client.set_spread(&2_i128);
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&SPREAD, &100_i128);
+    });
+    assert_eq!(client.quote_worst_case(&1000_i128, &true, &10_u32).price, 50500_i128);
+    assert_eq!(client.quote_worst_case(&1000_i128, &false, &10_u32).price, 49500_i128);
+
+    // An immediate round trip loses twice the spread, kept by the contract
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    client.close_trade(&trader, &None, &None);
+    assert_eq!(token.balance(&trader), 793_i128);
+    assert_eq!(client.get_liquidity().free, 207_i128);
+    // The fee pool holds the 10 skew fee and 98 of spread on each leg
+    assert_eq!(client.get_fee_pool(), 206_i128);
+}
+
+#[test]
+fn test_skew_scales_down_max_leverage() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.mock_all_auths();
+    assert_eq!(
+        client.try_set_skew_leverage(&11_u32),
+        Err(Ok(ContractError::InvalidLeverage.into()))
+    );
+    client.set_skew_leverage(&2_u32);
+
+    let traders = [Address::generate(&env), Address::generate(&env), Address::generate(&env)];
+    for trader in traders.iter() {
+        mint_tokens(&env, &token_id, trader, 1000_i128);
+        token.approve(trader, &client_id, &1000_i128, &0_u32);
+    }
+    client.place_trade(&traders[0], &open_request(1000_i128, true));
+
+    // All open interest is long: longs drop to the floor, shorts keep 10x
+    assert_eq!(client.get_max_leverage(&true), 2_i128);
+    assert_eq!(client.get_max_leverage(&false), 10_i128);
+
+    // Longs at twice the shorts get half the leverage
+    client.place_trade(&traders[1], &open_request(495_i128, false));
+    assert_eq!(client.get_max_leverage(&true), 5_i128);
+    let mut request = open_request(100_i128, true);
+    request.leverage = 6;
+    assert_eq!(
+        client.try_place_trade(&traders[2], &request),
+        Err(Ok(ContractError::InvalidLeverage.into()))
+    );
+    request.leverage = 5;
+    client.place_trade(&traders[2], &request);
+}
+
+#[test]
+fn test_oracles_can_dispute_settlement_price() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.mock_all_auths();
+    let first = oracle(&env, &client_id);
+    let second = Address::generate(&env);
+    client.add_oracle(&second);
+    client.set_oracle_quorum(&2);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    mint_tokens(&env, &token_id, &client_id, 10000_i128);
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    // A bad final print is proposed
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &30000_i128);
+    });
+    client.start_settlement();
+
+    // One vote short of the quorum leaves it in place
+    assert_eq!(
+        client.try_dispute_settlement(&Address::generate(&env), &51000_i128),
+        Err(Ok(ContractError::NotOracle.into()))
+    );
+    client.dispute_settlement(&first, &51000_i128);
+    assert_eq!(client.get_settlement_price(), Some(30000_i128));
+    client.dispute_settlement(&second, &51200_i128);
+    assert_eq!(client.get_settlement_price(), Some(51100_i128));
+
+    // The window closes for good
+    env.ledger().with_mut(|li| li.timestamp += SETTLEMENT_DISPUTE_WINDOW);
+    assert_eq!(
+        client.try_dispute_settlement(&first, &20000_i128),
+        Err(Ok(ContractError::DisputeWindowClosed.into()))
+    );
+    client.force_close(&trader, &symbol_short!("DELIST"));
+    // 990 + 1100 × 0.198 = 1207.8, floored
+    assert_eq!(token.balance(&trader), 1207_i128);
+}
+
+#[test]
+fn test_relayed_prices_earn_keeper_rebate() {
+    use ed25519_dalek::{Signer, SigningKey};
+    use soroban_sdk::{xdr::ToXdr, BytesN};
+
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.mock_all_auths();
+    env.ledger().with_mut(|li| li.timestamp = 1_000);
+    let signer = SigningKey::from_bytes(&[7u8; 32]);
+    let pubkey = BytesN::from_array(&env, &signer.verifying_key().to_bytes());
+    client.add_oracle_key(&oracle, &pubkey);
+    client.set_keeper_rebate(&4_i128, &60_u64);
+
+    // The 1% fee on a 1000 trade funds the pool
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    assert_eq!(client.get_fee_pool(), 10_i128);
+
+    let keeper = Address::generate(&env);
+    let relay = |timestamp: u64| -> i128 {
+        env.ledger().with_mut(|li| {
+            li.timestamp = timestamp;
+            li.sequence_number += 1;
+        });
+        let payload = PricePayload { asset: String::from_str(&env, "BTC"), price: 50000_i128, confidence: 0, timestamp };
+        let message = (env.ledger().network_id(), client_id.clone(), payload.clone()).to_xdr(&env);
+        let message: std::vec::Vec<u8> = message.iter().collect();
+        let signature = BytesN::from_array(&env, &signer.sign(&message).to_bytes());
+        client.relay_price(&keeper, &payload, &signature, &pubkey)
+    };
+    assert_eq!(relay(1_010), 4_i128);
+    // At most one rebate per interval
+    assert_eq!(relay(1_020), 0_i128);
+    assert_eq!(relay(1_070), 4_i128);
+    assert_eq!(token.balance(&keeper), 8_i128);
+    assert_eq!(client.get_fee_pool(), 2_i128);
+
+    // Reconcile leaves the fee pool to fund later rebates
+    assert_eq!(client.reconcile(), 0_i128);
+    assert_eq!(client.get_fee_pool(), 2_i128);
+
+    // A report buffered short of the quorum publishes no price and earns nothing
+    client.add_oracle(&Address::generate(&env));
+    client.set_oracle_quorum(&2);
+    assert_eq!(relay(1_140), 0_i128);
+    assert_eq!(client.get_fee_pool(), 2_i128);
+}
+
+#[test]
+fn test_ema_smoothed_index() {
+    let env = Env::default();
+    env.mock_all_auths();
+    let (token_id, _) = create_token_contract(&env);
+    let client_id = env.register(PerpContract, ());
+    let client = PerpContractClient::new(&env, &client_id);
+    let oracle = Address::generate(&env);
+    client.initialize(
+        &Address::generate(&env),
+        &"BTC".into_val(&env),
+        &10_i128,
+        &token_id,
+        &oracle,
+        &None,
+        &7,
+        &2500,
+        &PriceBounds { min: 1, max: i128::MAX },
+    );
+    let price = || -> i128 { env.as_contract(&client_id, || env.storage().instance().get(&PRICE).unwrap()) };
+
+    // The first price is taken as is, later ones move it a quarter of the way
+    for (timestamp, report, expected) in [(1_000_u64, 50000_i128, 50000_i128), (1_010, 54000, 51000), (1_020, 55000, 52000)] {
+        env.ledger().with_mut(|li| {
+            li.timestamp = timestamp;
+            li.sequence_number += 1;
+        });
+        client.set_price(&oracle, &report, &timestamp);
+        assert_eq!(price(), expected);
+    }
+}
+
+
+#[test]
+fn test_price_bounds_reject_corrupt_prices() {
+    let env = Env::default();
+    let (client_id, client, _token_id, _token) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.mock_all_auths();
+
+    assert_eq!(
+        client.try_set_price_bounds(&PriceBounds { min: 0, max: 100000 }),
+        Err(Ok(ContractError::InvalidPrice.into()))
+    );
+    assert_eq!(
+        client.try_set_price_bounds(&PriceBounds { min: 100000, max: 10000 }),
+        Err(Ok(ContractError::InvalidPrice.into()))
+    );
+    client.set_price_bounds(&PriceBounds { min: 10000, max: 100000 });
+    assert_eq!(client.get_price_bounds(), PriceBounds { min: 10000, max: 100000 });
+
+    // A 1000x print and a negative print are both refused
+    let now = env.ledger().timestamp();
+    assert_eq!(
+        client.try_set_price(&oracle, &50_000_000_i128, &now),
+        Err(Ok(ContractError::PriceOutOfBounds.into()))
+    );
+    assert_eq!(
+        client.try_set_price(&oracle, &-50000_i128, &now),
+        Err(Ok(ContractError::PriceOutOfBounds.into()))
+    );
+    client.set_price(&oracle, &52000_i128, &now);
+    env.as_contract(&client_id, || {
+        let price: i128 = env.storage().instance().get(&PRICE).unwrap();
+        assert_eq!(price, 52000);
+    });
+}
+
+// Yield source holding deposits as its own token balance, so minting to
+// it simulates accrued yield
+#[contract]
+pub struct MockYieldSource;
+
+#[contractimpl]
+impl MockYieldSource {
+    pub fn init(env: Env, token: Address) {
+        env.storage().instance().set(&symbol_short!("TOKEN"), &token);
+    }
+
+    pub fn deposit(env: Env, from: Address, amount: i128) {
+        let token: Address = env.storage().instance().get(&symbol_short!("TOKEN")).unwrap();
+        let source = env.current_contract_address();
+        TokenClient::new(&env, &token).transfer_from(&source, &from, &source, &amount);
+    }
+
+    pub fn withdraw(env: Env, to: Address, amount: i128) {
+        let token: Address = env.storage().instance().get(&symbol_short!("TOKEN")).unwrap();
+        TokenClient::new(&env, &token).transfer(&env.current_contract_address(), &to, &amount);
+    }
+
+    pub fn balance(env: Env, _owner: Address) -> i128 {
+        let token: Address = env.storage().instance().get(&symbol_short!("TOKEN")).unwrap();
+        TokenClient::new(&env, &token).balance(&env.current_contract_address())
+    }
+}
+
+#[test]
+fn test_insurance_yield_is_recalled_on_shortfall() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.mock_all_auths();
+    mint_tokens(&env, &token_id, &client_id, 1000_i128);
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&INSURANCE, &1000_i128);
+    });
+    let source = env.register(MockYieldSource, ());
+    MockYieldSourceClient::new(&env, &source).init(&token_id);
+    client.set_yield_source(&source);
+
+    assert_eq!(
+        client.try_deploy_insurance(&1001_i128),
+        Err(Ok(ContractError::InsufficientInsurance.into()))
+    );
+    client.deploy_insurance(&950_i128);
+    assert_eq!(token.balance(&client_id), 50_i128);
+    assert_eq!(client.get_liquidity().deployed, 950_i128);
+    assert_eq!(client.get_liquidity().free, 0_i128);
+
+    // Accrued yield shows up in the fund straight away
+    mint_tokens(&env, &token_id, &source, 40_i128);
+    assert_eq!(client.insurance_balance(), 1040_i128);
+
+    // A 1089 payout against 1050 on hand recalls the missing 39, and the
+    // 89 not covered by the trader's own margin comes out of the fund
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &50500_i128);
+    });
+    client.close_trade(&trader, &None, &None);
+    assert_eq!(token.balance(&trader), 1089_i128);
+    assert_eq!(token.balance(&source), 951_i128);
+    assert_eq!(client.get_insurance_deployed(), 951_i128);
+    assert_eq!(client.insurance_balance(), 951_i128);
+
+    assert_eq!(
+        client.try_recall_insurance(&952_i128),
+        Err(Ok(ContractError::InsufficientInsurance.into()))
+    );
+    client.recall_insurance(&951_i128);
+    assert_eq!(token.balance(&client_id), 951_i128);
+    assert_eq!(client.get_insurance_deployed(), 0_i128);
+}
+
+#[test]
+fn test_oracle_routes() {
+    let env = Env::default();
+    // Keep contract storage alive across the rename timelock
+    env.ledger().with_mut(|li| {
+        li.min_persistent_entry_ttl = 100_000;
+        li.max_entry_ttl = 100_000;
+    });
+    let (client_id, client, _token_id, _token) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    env.mock_all_auths();
+    let backup = Address::generate(&env);
+    client.add_oracle(&backup);
+
+    let route = client.get_oracle_route(&Symbol::new(&env, "BTC")).unwrap();
+    assert!(route.oracles.get(oracle).unwrap());
+    assert!(route.oracles.get(backup).unwrap());
+    assert_eq!(route.feed, None);
+    assert_eq!(route.decimals, 7);
+    assert_eq!(client.get_oracle_routes().len(), 1);
+    assert_eq!(client.get_oracle_route(&Symbol::new(&env, "ETH")), None);
+
+    // Renaming the market keeps its route
+    client.propose_asset(&String::from_str(&env, "XBT"));
+    env.ledger().with_mut(|li| li.sequence_number += TIMELOCK_LEDGERS);
+    client.apply_asset();
+    assert_eq!(client.get_oracle_route(&Symbol::new(&env, "BTC")).unwrap(), route);
+}
+
+#[test]
+fn test_pair_name_maps_to_base_symbol() {
+    let env = Env::default();
+    env.mock_all_auths();
+    let feed_id = env.register(MockOracle, ());
+    let feed = MockOracleClient::new(&env, &feed_id);
+    let (token_id, _) = create_token_contract(&env);
+    let client = PerpContractClient::new(&env, &env.register(PerpContract, ()));
+    client.initialize(
+        &Address::generate(&env),
+        &"BTC/USD".into_val(&env),
+        &10_i128,
+        &token_id,
+        &Address::generate(&env),
+        &Some(feed_id),
+        &7,
+        &0,
+        &PriceBounds { min: 1, max: i128::MAX },
+    );
+
+    // The route and the feed lookups use the base asset
+    assert!(client.get_oracle_route(&Symbol::new(&env, "BTC")).is_some());
+    feed.set_price(&FeedAsset::Other(Symbol::new(&env, "BTC")), &50000_i128);
+    assert_eq!(client.get_mark_price(), 50000_i128);
+    assert_eq!(client.get_asset(), String::from_str(&env, "BTC/USD"));
+}
+
+#[test]
+fn test_expired_intents_are_refused() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    env.ledger().with_mut(|li| li.sequence_number = 100);
+
+    let mut request = open_request(1000_i128, true);
+    request.valid_until_ledger = Some(99);
+    assert_eq!(
+        client.try_place_trade(&trader, &request),
+        Err(Ok(ContractError::IntentExpired.into()))
+    );
+    // The last valid ledger is inclusive
+    request.valid_until_ledger = Some(100);
+    client.place_trade(&trader, &request);
+
+    env.ledger().with_mut(|li| li.sequence_number = 102);
+    assert_eq!(
+        client.try_close_trade(&trader, &None, &Some(101)),
+        Err(Ok(ContractError::IntentExpired.into()))
+    );
+    client.close_trade(&trader, &None, &Some(102));
+    assert_eq!(token.balance(&trader), 990_i128);
+}
+
+#[test]
+fn test_account_snapshot() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 2000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1500_i128, &100_u32);
+
+    let account = client.get_account(&trader);
+    assert_eq!((account.balance, account.allowance), (2000_i128, 1500_i128));
+    assert!(account.position.is_none() && account.margin_call.is_none() && account.suspension.is_none());
+
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    client.suspend_account(&trader, &true, &3600);
+    let account = client.get_account(&trader);
+    assert_eq!((account.balance, account.allowance), (1000_i128, 500_i128));
+    assert_eq!(account.position.unwrap().current_value, 990_i128);
+    assert!(account.suspension.unwrap().open_only);
+}
+
+#[test]
+fn test_add_margin() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1500_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1500_i128, &0_u32);
+
+    assert_eq!(
+        client.try_add_margin(&trader, &500_i128),
+        Err(Ok(ContractError::PositionNotOpen.into()))
+    );
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    let before = client.get_position_details(&trader);
+    assert_eq!(
+        client.try_add_margin(&trader, &0_i128),
+        Err(Ok(ContractError::ZeroValue.into()))
+    );
+
+    assert_eq!(client.add_margin(&trader, &500_i128), 1490_i128);
+    let after = client.get_position_details(&trader);
+    assert_eq!(after.position.open_price(), before.position.open_price());
+    assert_eq!(after.position.size_base, before.position.size_base);
+    assert_eq!(after.position.notional(), before.position.notional());
+    assert!(after.liquidation_price < before.liquidation_price);
+    assert_eq!(client.get_skew().long_oi, 1490_i128);
+    assert_eq!(token.balance(&trader), 0_i128);
+
+    // The extra margin comes back on close
+    client.close_trade(&trader, &None, &None);
+    assert_eq!(token.balance(&trader), 1490_i128);
+}
+
+#[test]
+fn test_positions_get_sequential_ids() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.mock_all_auths();
+    let alice = Address::generate(&env);
+    let bob = Address::generate(&env);
+    for trader in [&alice, &bob] {
+        mint_tokens(&env, &token_id, trader, 2000_i128);
+        token.approve(trader, &client_id, &2000_i128, &0_u32);
+    }
+
+    client.place_trade(&alice, &open_request(1000_i128, true));
+    let (_, topics, data) = env.events().all().last_unchecked();
+    assert_eq!(topics, (symbol_short!("PLACE"),).into_val(&env));
+    let (_, _, _, id, _): (Address, i128, bool, u64, u64) = data.into_val(&env);
+    assert_eq!(id, 1);
+    client.place_trade(&bob, &open_request(500_i128, false));
+    assert_eq!(client.get_position_details(&bob).position.id, 2);
+
+    // Ids are never reused, and closed positions keep theirs
+    client.close_trade(&alice, &None, &None);
+    client.place_trade(&alice, &open_request(500_i128, true));
+    assert_eq!(client.get_position_details(&alice).position.id, 3);
+    env.as_contract(&client_id, || {
+        let history: Vec<Position> = env.storage().persistent().get(&(TRADE_HISTORY, alice.clone())).unwrap();
+        assert_eq!(history.get_unchecked(0).id, 1);
+    });
+}
+
+#[test]
+fn test_internal_precision_conversions() {
+    let env = Env::default();
+    let (_, client, _, _) = setup(&env);
+    env.mock_all_auths();
+    let token_with = |decimals: u32| -> Address {
+        let token_id = env.register(MockTokenWASM, ());
+        MockTokenClient::new(&env, &token_id).initialize(
+            &Address::generate(&env),
+            &decimals,
+            &String::from_str(&env, "Name"),
+            &String::from_str(&env, "Symbol"),
+        );
+        token_id
+    };
+
+    // Internal precision is the 7 decimals of pUSD; scaling down truncates
+    let wide = token_with(18);
+    assert_eq!(client.to_internal(&1_234_567_899_999_999_999_i128, &wide), 12_345_678_i128);
+    assert_eq!(client.from_internal(&12_345_678_i128, &wide), 1_234_567_800_000_000_000_i128);
+    let narrow = token_with(2);
+    assert_eq!(client.to_internal(&150_i128, &narrow), 15_000_000_i128);
+    assert_eq!(client.from_internal(&15_099_999_i128, &narrow), 150_i128);
+    assert_eq!(
+        client.try_to_internal(&1_i128, &token_with(19)),
+        Err(Ok(ContractError::InvalidDecimals.into()))
+    );
+}
+
+#[test]
+fn test_increase_position_averages_entry() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 2000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &2000_i128, &0_u32);
+
+    assert_eq!(
+        client.try_increase_position(&trader, &1000_i128),
+        Err(Ok(ContractError::PositionNotOpen.into()))
+    );
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &60000_i128);
+    });
+
+    // 1,980,000 units at 50000 plus 1,650,000 at 60000 average 54545.45,
+    // rounded up against the long
+    client.increase_position(&trader, &1000_i128);
+    let position = client.get_position_details(&trader).position;
+    assert_eq!(position.value, 1980_i128);
+    assert_eq!(position.size_base, 3_630_000_i128);
+    assert_eq!(position.open_price(), 54546_i128);
+    assert_eq!(client.get_skew().long_oi, 1980_i128);
+
+    // Worth the two legs held separately (2970 + 990), less the rounding
+    assert_eq!(client.calculate_position(&trader), 3959_i128);
+}
+
+#[test]
+fn test_margin_tier_changes_grandfather_open_positions() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 2000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &2000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    let before = client.get_position_details(&trader).liquidation_price;
+
+    // A 9% requirement from 5000 notional applies to new trades only
+    client.set_margin_tiers(&Vec::from_array(&env, [MarginTier { min_notional: 5000, margin_bps: 900 }]));
+    assert_eq!(client.get_margin_version(), 1);
+    assert_eq!(client.get_margin_req(&9900_i128), 900_i128);
+    let details = client.get_position_details(&trader);
+    assert_eq!(details.position.margin_version, 0);
+    assert_eq!(details.liquidation_price, before);
+
+    // Increasing the position moves it onto the stricter curve
+    client.increase_position(&trader, &100_i128);
+    let details = client.get_position_details(&trader);
+    assert_eq!(details.position.margin_version, 1);
+    assert!(details.liquidation_price > before);
+}
+
+#[test]
+fn test_stop_loss_executed_by_keeper() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    let keeper = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 10000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &10000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(10000_i128, true));
+
+    // A long's stop must sit below the current price
+    assert_eq!(
+        client.try_set_stop_loss(&trader, &Some(50000_i128)),
+        Err(Ok(ContractError::InvalidPrice.into()))
+    );
+    let id = client.set_stop_loss(&trader, &Some(48000_i128));
+    assert_eq!(client.get_stop_loss(&id).unwrap().trigger_price, 48000_i128);
+    assert_eq!(
+        client.try_execute_stop(&keeper, &id),
+        Err(Ok(ContractError::TriggerNotReached.into()))
+    );
+
+    // At 48000 the 9900 margin is worth 5940, of which 0.1% goes to the
+    // keeper, plus a flat rebate from the fee pool
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &48000_i128);
+    });
+    client.set_keeper_rebate(&4_i128, &60_u64);
+    assert_eq!(client.execute_stop(&keeper, &id), 9_i128);
+    assert_eq!(token.balance(&keeper), 9_i128);
+    assert_eq!(client.get_fee_pool(), 96_i128);
+    assert_eq!(token.balance(&trader), 5935_i128);
+    assert_eq!(client.get_stop_loss(&id), None);
+    assert_eq!(
+        client.try_execute_stop(&keeper, &id),
+        Err(Ok(ContractError::PositionNotOpen.into()))
+    );
+}
+
+#[test]
+fn test_take_profit_splits_fee() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    let keeper = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 10000_i128);
+    mint_tokens(&env, &token_id, &client_id, 10000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &10000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(10000_i128, true));
+
+    // A long's take-profit must sit above the current price
+    assert_eq!(
+        client.try_set_take_profit(&trader, &Some(49000_i128)),
+        Err(Ok(ContractError::InvalidPrice.into()))
+    );
+    let id = client.set_take_profit(&trader, &Some(52000_i128));
+    assert_eq!(client.get_take_profit(&id).unwrap().trigger_price, 52000_i128);
+    assert_eq!(
+        client.try_execute_take_profit(&keeper, &id),
+        Err(Ok(ContractError::TriggerNotReached.into()))
+    );
+
+    // At 52000 the 9900 margin is worth 13860; the 27 fee splits 13 / 14
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &52000_i128);
+    });
+    let insurance = client.insurance_balance();
+    client.set_keeper_rebate(&4_i128, &60_u64);
+    assert_eq!(client.execute_take_profit(&keeper, &id), 17_i128);
+    assert_eq!(token.balance(&keeper), 17_i128);
+    assert_eq!(token.balance(&trader), 13833_i128);
+    assert_eq!(client.insurance_balance(), insurance + 14);
+    assert_eq!(client.get_take_profit(&id), None);
+}
+
+#[test]
+fn test_execute_triggers_in_range() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let keeper = Address::generate(&env);
+    mint_tokens(&env, &token_id, &client_id, 10000_i128);
+    env.mock_all_auths();
+    let traders = [Address::generate(&env), Address::generate(&env), Address::generate(&env)];
+    for (trader, long) in traders.iter().zip([true, true, false]) {
+        mint_tokens(&env, &token_id, trader, 1000_i128);
+        token.approve(trader, &client_id, &1000_i128, &0_u32);
+        client.place_trade(trader, &open_request(1000_i128, long));
+    }
+    let first = client.set_stop_loss(&traders[0], &Some(48000_i128));
+    let moved = client.set_stop_loss(&traders[1], &Some(49500_i128));
+    client.set_stop_loss(&traders[1], &Some(47000_i128));
+    let short = client.set_take_profit(&traders[2], &Some(48500_i128));
+
+    // Nothing has triggered yet, so nothing is paid
+    assert_eq!(client.execute_triggers_in_range(&keeper, &47000_i128, &49500_i128, &10), 0_i128);
+    assert_eq!(
+        client.try_execute_triggers_in_range(&keeper, &49500_i128, &47000_i128, &10),
+        Err(Ok(ContractError::InvalidPrice.into()))
+    );
+    assert_eq!(
+        client.try_execute_triggers_in_range(&keeper, &1000_i128, &49500_i128, &10),
+        Err(Ok(ContractError::InvalidPrice.into()))
+    );
+
+    // At 47900 the first long's stop and the short's take-profit have
+    // triggered. The second long's stop was moved below the range.
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &47900_i128);
+    });
+    // A limit of one reads one order per call
+    client.execute_triggers_in_range(&keeper, &47500_i128, &49500_i128, &1);
+    assert_eq!(client.get_stop_loss(&first), None);
+    assert!(client.get_take_profit(&short).is_some());
+    assert!(client.execute_triggers_in_range(&keeper, &47500_i128, &49500_i128, &10) > 0);
+    assert_eq!(client.get_take_profit(&short), None);
+    assert_eq!(client.get_stop_loss(&moved).unwrap().trigger_price, 47000_i128);
+    assert!(token.balance(&traders[0]) > 0);
+    assert!(token.balance(&traders[2]) > 0);
+    assert_eq!(client.execute_triggers_in_range(&keeper, &46000_i128, &49500_i128, &10), 0_i128);
+}
+
+#[test]
+fn test_volatility_mode_widens_liquidation_buffer() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let oracle = oracle(&env, &client_id);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+    assert_eq!(client.get_position_details(&trader).liquidation_price, 45152_i128);
+
+    // A 10% move between checkpoints is 1000 bps of realized volatility
+    client.set_volatility_guard(&500_i128, &200_i128);
+    for (timestamp, price) in [(1_000_u64, 50000_i128), (1_010, 55000)] {
+        env.ledger().with_mut(|li| {
+            li.timestamp = timestamp;
+            li.sequence_number += 1;
+        });
+        client.set_price(&oracle, &price, &timestamp);
+    }
+    let announced = env.events().all().iter().any(|(_, topics, _)| topics == (symbol_short!("VOLMODE"),).into_val(&env));
+    assert!(announced);
+    assert_eq!(client.get_volatility(), VolatilityState { volatility_bps: 1000, active: true });
+
+    // The 3% maintenance margin drops to 1% while the mode lasts
+    assert_eq!(client.get_position_details(&trader).liquidation_price, 45051_i128);
+    client.set_volatility_guard(&0_i128, &200_i128);
+    assert!(!client.get_volatility().active);
+    assert_eq!(client.get_position_details(&trader).liquidation_price, 45152_i128);
+}
+
+#[test]
+fn test_entrypoint_metrics() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 10000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &10000_i128, &0_u32);
+    assert_eq!(client.get_metrics().len(), 0);
+
+    // At 2x the liquidation price is far enough away to be safe
+    client.place_trade(&trader, &OpenRequest { leverage: 2, ..open_request(10000_i128, true) });
+    // A safe position is not flagged, which counts as a failed call
+    assert!(!client.flag_margin_call(&trader));
+    client.close_trade(&trader, &None, &None);
+
+    let metrics = client.get_metrics();
+    assert_eq!(
+        metrics.get(symbol_short!("place")).unwrap(),
+        EntrypointMetrics { calls: 1, failures: 0, volume: 10000 }
+    );
+    assert_eq!(
+        metrics.get(symbol_short!("mcall")).unwrap(),
+        EntrypointMetrics { calls: 1, failures: 1, volume: 0 }
+    );
+    assert_eq!(
+        metrics.get(symbol_short!("close")).unwrap(),
+        EntrypointMetrics { calls: 1, failures: 0, volume: 9900 }
+    );
+}
+
+#[test]
+fn test_close_abandoned_position() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    let keeper = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 10000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &10000_i128, &0_u32);
+    env.ledger().with_mut(|li| li.sequence_number = 100);
+    client.place_trade(&trader, &open_request(10000_i128, true));
+
+    // Without a lifetime a position never expires
+    assert_eq!(client.get_position_expiry(&trader), None);
+    assert_eq!(
+        client.try_close_abandoned(&keeper, &trader),
+        Err(Ok(ContractError::LifetimeNotReached.into()))
+    );
+
+    // The shorter of the trader's lifetime and the market default applies
+    client.set_max_lifetime(&1000_u32);
+    assert_eq!(client.get_position_expiry(&trader), Some(1100));
+    client.set_position_lifetime(&trader, &Some(50_u32));
+    assert_eq!(client.get_position_expiry(&trader), Some(150));
+
+    env.ledger().with_mut(|li| li.sequence_number = 149);
+    assert_eq!(
+        client.try_close_abandoned(&keeper, &trader),
+        Err(Ok(ContractError::LifetimeNotReached.into()))
+    );
+    env.ledger().with_mut(|li| li.sequence_number = 150);
+    assert_eq!(client.close_abandoned(&keeper, &trader), 0_i128);
+    assert_eq!(token.balance(&trader), 9900_i128);
+    assert_eq!(
+        client.try_get_position_expiry(&trader),
+        Err(Ok(ContractError::PositionNotOpen.into()))
+    );
+}
+
+#[test]
+#[cfg(feature = "orders")]
+fn test_order_registry_and_cancellation() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    let keeper = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 30000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &30000_i128, &0_u32);
+
+    // Orders need a limit price
+    assert_eq!(
+        client.try_place_order(&trader, &open_request(10000_i128, true)),
+        Err(Ok(ContractError::InvalidPrice.into()))
+    );
+    let limit = OpenRequest { limit_price: Some(49000), ..open_request(10000_i128, true) };
+    let cancelled = client.place_order(&trader, &limit);
+    assert_eq!(token.balance(&trader), 20000_i128);
+    assert_eq!(client.get_liquidity().escrowed, 10000_i128);
+
+    // Only the owner can cancel, and the escrow is refunded
+    assert_eq!(
+        client.try_cancel_order(&keeper, &cancelled),
+        Err(Ok(ContractError::OrderNotOpen.into()))
+    );
+    client.cancel_order(&trader, &cancelled);
+    assert_eq!(token.balance(&trader), 30000_i128);
+    assert_eq!(client.get_order(&cancelled).unwrap().status, OrderStatus::Cancelled);
+    assert_eq!(
+        client.try_cancel_order(&trader, &cancelled),
+        Err(Ok(ContractError::OrderNotOpen.into()))
+    );
+
+    // An expired order is marked as such when cancelled
+    env.ledger().with_mut(|li| li.sequence_number = 10);
+    let expiring = client.place_order(&trader, &OpenRequest { valid_until_ledger: Some(20), ..limit.clone() });
+    env.ledger().with_mut(|li| li.sequence_number = 21);
+    client.cancel_order(&trader, &expiring);
+    assert_eq!(client.get_order(&expiring).unwrap().status, OrderStatus::Expired);
+
+    // Keepers fill once the price reaches the limit
+    let filled = client.place_order(&trader, &limit);
+    assert_eq!(
+        client.try_fill_order(&keeper, &filled),
+        Err(Ok(ContractError::TriggerNotReached.into()))
+    );
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &49000_i128);
+    });
+    client.fill_order(&keeper, &filled);
+    assert_eq!(client.get_order(&filled).unwrap().status, OrderStatus::Filled);
+    assert_eq!(client.get_position_details(&trader).position.value, 9900_i128);
+    assert_eq!(client.get_liquidity().escrowed, 0_i128);
+    assert_eq!(token.balance(&trader), 20000_i128);
+    assert_eq!(client.get_user_orders(&trader), Vec::from_array(&env, [cancelled, expiring, filled]));
+}
+
+#[test]
+fn test_transfer_position() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let seller = Address::generate(&env);
+    let buyer = Address::generate(&env);
+    let holder = Address::generate(&env);
+    mint_tokens(&env, &token_id, &seller, 10000_i128);
+    mint_tokens(&env, &token_id, &holder, 10000_i128);
+    env.mock_all_auths();
+    token.approve(&seller, &client_id, &10000_i128, &0_u32);
+    token.approve(&holder, &client_id, &10000_i128, &0_u32);
+    client.place_trade(&seller, &open_request(10000_i128, true));
+    client.place_trade(&holder, &open_request(10000_i128, false));
+    let id = client.set_stop_loss(&seller, &Some(48000_i128));
+    assert_eq!(client.get_position_owner(&id), Some(seller.clone()));
+
+    // The receiver must not already hold a position
+    assert_eq!(
+        client.try_transfer_position(&seller, &holder),
+        Err(Ok(ContractError::PositionOpen.into()))
+    );
+    assert_eq!(client.transfer_position(&seller, &buyer), id);
+    // The receiver signs to accept the position
+    assert!(env.auths().iter().any(|(address, _)| *address == buyer));
+    assert_eq!(client.get_position_owner(&id), Some(buyer.clone()));
+    assert_eq!(client.get_stop_loss(&id), None);
+    assert_eq!(
+        client.try_close_trade(&seller, &None, &None),
+        Err(Ok(ContractError::PositionNotOpen.into()))
+    );
+
+    // The new owner closes and collects the payout
+    client.close_trade(&buyer, &None, &None);
+    assert_eq!(token.balance(&buyer), 9900_i128);
+    assert_eq!(client.get_position_owner(&id), None);
+}
+
+#[test]
+fn test_transfer_position_by_operator() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let seller = Address::generate(&env);
+    let buyer = Address::generate(&env);
+    let operator = Address::generate(&env);
+    mint_tokens(&env, &token_id, &seller, 10000_i128);
+    env.mock_all_auths();
+    token.approve(&seller, &client_id, &10000_i128, &0_u32);
+    assert_eq!(
+        client.try_approve_position(&seller, &Some(operator.clone())),
+        Err(Ok(ContractError::PositionNotOpen.into()))
+    );
+    client.place_trade(&seller, &open_request(10000_i128, true));
+
+    // Only the approved operator may move the position
+    assert_eq!(
+        client.try_transfer_position_from(&operator, &seller, &buyer),
+        Err(Ok(ContractError::NotApproved.into()))
+    );
+    let id = client.approve_position(&seller, &Some(operator.clone()));
+    assert_eq!(client.get_position_approval(&id), Some(operator.clone()));
+    assert_eq!(
+        client.try_transfer_position_from(&Address::generate(&env), &seller, &buyer),
+        Err(Ok(ContractError::NotApproved.into()))
+    );
+
+    // The approval lapses with the transfer
+    assert_eq!(client.transfer_position_from(&operator, &seller, &buyer), id);
+    assert_eq!(client.get_position_owner(&id), Some(buyer.clone()));
+    assert_eq!(client.get_position_approval(&id), None);
+    assert_eq!(
+        client.try_transfer_position_from(&operator, &buyer, &seller),
+        Err(Ok(ContractError::NotApproved.into()))
+    );
+
+    // A revoked operator can no longer move it
+    client.approve_position(&buyer, &Some(operator.clone()));
+    client.approve_position(&buyer, &None);
+    assert_eq!(
+        client.try_transfer_position_from(&operator, &buyer, &seller),
+        Err(Ok(ContractError::NotApproved.into()))
+    );
+}
+
+#[test]
+fn test_flip_position() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 10000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &10000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(10000_i128, true));
+
+    // The 9900 payout funds the 5000 short and the rest goes back
+    assert_eq!(client.flip_position(&trader, &5000_i128), 9900_i128);
+    assert_eq!(token.balance(&trader), 4900_i128);
+    let position = client.get_position_details(&trader).position;
+    assert!(!position.is_long());
+    assert_eq!(position.value, 4950_i128);
+    assert_eq!(position.leverage, 10);
+
+    // A larger flip pulls only the shortfall from the trader
+    token.approve(&trader, &client_id, &1050_i128, &0_u32);
+    assert_eq!(client.flip_position(&trader, &6000_i128), 4950_i128);
+    assert_eq!(token.balance(&trader), 3850_i128);
+    assert!(client.get_position_details(&trader).position.is_long());
+    assert_eq!(client.get_position_details(&trader).position.value, 5940_i128);
+}
+
+#[test]
+fn test_place_trade_with_asset() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.mock_all_auths();
+    let (xlm_id, xlm) = create_token_contract(&env);
+    let router = env.register(MockRouter, ());
+    mint_tokens(&env, &token_id, &router, 10000_i128);
+    client.set_swap_router(&router);
+
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &xlm_id, &trader, 500_i128);
+    xlm.approve(&trader, &client_id, &500_i128, &0_u32);
+    let request = open_request(0_i128, true);
+
+    // Not whitelisted yet
+    assert_eq!(
+        client.try_place_trade_with_asset(&trader, &xlm_id, &500_i128, &1_i128, &request),
+        Err(Ok(ContractError::AssetNotAllowed.into()))
+    );
+    client.set_collateral_asset(&xlm_id, &true);
+
+    // 500 XLM swaps to 1000 pUSD of margin, short of the trader's minimum
+    assert_eq!(
+        client.try_place_trade_with_asset(&trader, &xlm_id, &500_i128, &1001_i128, &request),
+        Err(Ok(ContractError::SlippageExceeded.into()))
+    );
+    assert_eq!(client.place_trade_with_asset(&trader, &xlm_id, &500_i128, &1000_i128, &request), 1000_i128);
+    assert_eq!(xlm.balance(&trader), 0_i128);
+    assert_eq!(xlm.balance(&router), 500_i128);
+    assert_eq!(token.balance(&client_id), 1000_i128);
+    assert_eq!(client.get_position_details(&trader).position.value, 990_i128);
+}
+
+#[test]
+fn test_dated_market_settles_after_expiry() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 2000_i128);
+    mint_tokens(&env, &token_id, &client_id, 10000_i128);
+    env.mock_all_auths();
+    env.ledger().with_mut(|li| li.timestamp = 1_000);
+    token.approve(&trader, &client_id, &2000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(1000_i128, true));
+
+    assert_eq!(client.try_set_expiry(&Some(1_000)), Err(Ok(ContractError::InvalidExpiry.into())));
+    client.set_expiry(&Some(5_000));
+    assert_eq!(client.get_expiry(), Some(5_000));
+    assert_eq!(client.try_expire_market(), Err(Ok(ContractError::NotExpired.into())));
+
+    // Past expiry only closes are allowed, even before settlement starts
+    env.ledger().with_mut(|li| li.timestamp = 5_000);
+    assert_eq!(
+        client.try_place_trade(&Address::generate(&env), &open_request(1000_i128, true)),
+        Err(Ok(ContractError::SettlementActive.into()))
+    );
+    assert_eq!(client.try_set_expiry(&None), Err(Ok(ContractError::InvalidExpiry.into())));
+    assert_eq!(client.try_settle_expired(&trader), Err(Ok(ContractError::NotSettling.into())));
+
+    // Anyone captures the price, then settles once the dispute window passes
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &51000_i128);
+    });
+    client.expire_market();
+    assert_eq!(client.get_settlement_price(), Some(51000_i128));
+    assert_eq!(client.try_settle_expired(&trader), Err(Ok(ContractError::TimelockActive.into())));
+    env.ledger().with_mut(|li| li.timestamp += SETTLEMENT_DISPUTE_WINDOW);
+
+    // 990 + 1000 * 10 * 990 / 50000 = 1188
+    assert_eq!(client.settle_expired(&trader), 1188_i128);
+    assert_eq!(token.balance(&trader), 2188_i128);
+}
+
+// Backstop recording the last position it took over
+#[contract]
+pub struct MockBackstop;
+
+#[contractimpl]
+impl MockBackstop {
+    pub fn take_over(env: Env, _user: Address, position_id: u64) {
+        env.storage().instance().set(&symbol_short!("TAKEN"), &position_id);
+    }
+
+    pub fn taken(env: Env) -> Option<u64> {
+        env.storage().instance().get(&symbol_short!("TAKEN"))
+    }
+}
+
+#[test]
+fn test_backstop_takes_over_unliquidated_position() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 10000_i128);
+    env.mock_all_auths();
+    env.ledger().with_mut(|li| li.sequence_number = 100);
+    token.approve(&trader, &client_id, &10000_i128, &0_u32);
+    client.place_trade(&trader, &open_request(10000_i128, true));
+
+    let backstop = env.register(MockBackstop, ());
+    assert_eq!(client.try_backstop_take_over(&trader), Err(Ok(ContractError::NoBackstop.into())));
+    client.set_backstop(&Some(BackstopConfig { backstop: backstop.clone(), delay_ledgers: 10 }));
+
+    // A healthy position cannot be marked
+    assert_eq!(client.mark_liquidatable(&trader), None);
+    env.as_contract(&client_id, || {
+        env.storage().instance().set(&PRICE, &45100_i128);
+    });
+    assert_eq!(client.try_backstop_take_over(&trader), Err(Ok(ContractError::TimelockActive.into())));
+    assert_eq!(client.mark_liquidatable(&trader), Some(100));
+
+    // Keepers get `delay_ledgers` to liquidate first
+    env.ledger().with_mut(|li| li.sequence_number = 105);
+    assert_eq!(client.mark_liquidatable(&trader), Some(100));
+    assert_eq!(client.try_backstop_take_over(&trader), Err(Ok(ContractError::TimelockActive.into())));
+    env.ledger().with_mut(|li| li.sequence_number = 110);
+    client.backstop_take_over(&trader);
+
+    let id = client.get_position_details(&backstop).position.id;
+    assert_eq!(client.get_position_owner(&id), Some(backstop.clone()));
+    assert_eq!(MockBackstopClient::new(&env, &backstop).taken(), Some(id));
+    assert!(client.try_get_position_details(&trader).is_err());
+}
+
+#[test]
+fn test_backstop_merges_positions_it_takes_over() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    env.mock_all_auths();
+    env.ledger().with_mut(|li| li.sequence_number = 100);
+    let alice = Address::generate(&env);
+    let bob = Address::generate(&env);
+    let carol = Address::generate(&env);
+    for trader in [&alice, &bob, &carol] {
+        mint_tokens(&env, &token_id, trader, 10000_i128);
+        token.approve(trader, &client_id, &10000_i128, &0_u32);
+    }
+    mint_tokens(&env, &token_id, &client_id, 10000_i128);
+    client.place_trade(&alice, &open_request(10000_i128, true));
+    client.place_trade(&bob, &open_request(5000_i128, true));
+    client.place_trade(&carol, &open_request(2000_i128, false));
+    let backstop = env.register(MockBackstop, ());
+    client.set_backstop(&Some(BackstopConfig { backstop: backstop.clone(), delay_ledgers: 0 }));
+    let set_price = |price: i128| {
+        env.as_contract(&client_id, || env.storage().instance().set(&PRICE, &price));
+    };
+
+    // The first take-over hands alice's position to the backstop
+    set_price(45100_i128);
+    let first = client.get_position_details(&alice).position;
+    let second = client.get_position_details(&bob).position;
+    client.mark_liquidatable(&alice);
+    client.backstop_take_over(&alice);
+    assert_eq!(client.get_position_details(&backstop).position.id, first.id);
+
+    // A second one on the same side adds to the backstop's position
+    client.mark_liquidatable(&bob);
+    client.backstop_take_over(&bob);
+    let book = client.get_position_details(&backstop).position;
+    assert_eq!(book.id, first.id);
+    assert_eq!(book.value, first.value + second.value);
+    assert_eq!(book.size_base, first.size_base + second.size_base);
+    assert_eq!(client.get_position_owner(&second.id), None);
+    assert_eq!(MockBackstopClient::new(&env, &backstop).taken(), Some(first.id));
+    assert_eq!(client.get_skew().long_oi, first.value + second.value);
+
+    // One on the other side is netted against it
+    set_price(54900_i128);
+    let short = client.get_position_details(&carol).position;
+    client.mark_liquidatable(&carol);
+    client.backstop_take_over(&carol);
+    let netted = client.get_position_details(&backstop).position;
+    assert!(netted.is_long());
+    assert_eq!(netted.size_base, book.size_base - short.size_base);
+    assert_eq!(client.get_skew().short_oi, 0_i128);
+    assert_eq!(client.get_skew().long_oi, netted.value);
+    assert!(netted.value > book.value);
+}
+
+#[test]
+fn test_min_position_value() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 1000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &1000_i128, &0_u32);
+
+    client.set_min_position_value(&100_i128);
+    assert_eq!(client.get_min_position_value(), 100_i128);
+    assert_eq!(
+        client.try_place_trade(&trader, &open_request(99_i128, true)),
+        Err(Ok(ContractError::BelowMinimum.into()))
+    );
+    client.place_trade(&trader, &open_request(100_i128, true));
+    assert_eq!(token.balance(&trader), 900_i128);
+}
+
+#[test]
+fn test_event_nonce_per_user() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let alice = Address::generate(&env);
+    let bob = Address::generate(&env);
+    env.mock_all_auths();
+    for trader in [&alice, &bob] {
+        mint_tokens(&env, &token_id, trader, 2000_i128);
+        token.approve(trader, &client_id, &2000_i128, &0_u32);
+    }
+    assert_eq!(client.get_event_nonce(&alice), 0);
+
+    client.place_trade(&alice, &open_request(1000_i128, true));
+    let (_, _, data) = env.events().all().last_unchecked();
+    let (_, _, _, _, nonce): (Address, i128, bool, u64, u64) = data.into_val(&env);
+    assert_eq!(nonce, 1);
+
+    // Each user counts separately
+    client.place_trade(&bob, &open_request(500_i128, false));
+    assert_eq!(client.get_event_nonce(&bob), 1);
+    client.add_margin(&alice, &100_i128);
+    let (_, topics, data) = env.events().all().last_unchecked();
+    assert_eq!(topics, (symbol_short!("ADDMARGIN"), alice.clone()).into_val(&env));
+    let (_, _, _, nonce): (i128, i128, u64, u64) = data.into_val(&env);
+    assert_eq!(nonce, 2);
+    assert_eq!(client.get_event_nonce(&alice), 2);
+}
+
+#[test]
+fn test_max_position_notional() {
+    let env = Env::default();
+    let (client_id, client, token_id, token) = setup(&env);
+    let trader = Address::generate(&env);
+    mint_tokens(&env, &token_id, &trader, 2000_i128);
+    env.mock_all_auths();
+    token.approve(&trader, &client_id, &2000_i128, &0_u32);
+
+    // 10x leverage caps margin at 1000
+    client.set_max_position_notional(&10000_i128);
+    assert_eq!(client.get_max_position_notional(), 10000_i128);
+    assert_eq!(
+        client.try_place_trade(&trader, &open_request(1001_i128, true)),
+        Err(Ok(ContractError::PositionTooLarge.into()))
+    );
+    client.place_trade(&trader, &open_request(900_i128, true));
+
+    // The 891 left after fees plus 110 more would exceed the cap
+    assert_eq!(
+        client.try_increase_position(&trader, &110_i128),
+        Err(Ok(ContractError::PositionTooLarge.into()))
+    );
+    client.increase_position(&trader, &100_i128);
+
+    // Added margin is not exposure, so a position at the cap can still be topped up
+    let notional = client.get_position_details(&trader).position.notional();
+    client.add_margin(&trader, &500_i128);
+    assert_eq!(client.get_position_details(&trader).position.notional(), notional);
+    assert_eq!(
+        client.try_increase_position(&trader, &20_i128),
+        Err(Ok(ContractError::PositionTooLarge.into()))
+    );
+}
@@ -0,0 +1,42 @@
+#![cfg(any(test, feature = "testutils"))]
+//! Test helpers for integrators, enabled with the `testutils` feature.
+//!
+//! `MockOracle` is a minimal SEP-40 feed that serves whatever price the
+//! test sets. Pass its address as `price_feed` to `initialize` to drive the
+//! perp's price without writing into its storage:
+//!
+//! ```ignore
+//! let feed = MockOracleClient::new(&env, &env.register(MockOracle, ()));
+//! perp.initialize(
+//!     &admin,
+//!     &asset,
+//!     &10,
+//!     &p_usd,
+//!     &oracle,
+//!     &Some(feed.address.clone()),
+//!     &7,
+//!     &0,
+//!     &PriceBounds { min: 1, max: i128::MAX },
+//! );
+//! feed.set_price(&FeedAsset::Other(Symbol::new(&env, "BTC")), &50000);
+//! ```
+use soroban_sdk::{contract, contractimpl, Env};
+
+use crate::{FeedAsset, PriceData};
+
+#[contract]
+pub struct MockOracle;
+
+#[contractimpl]
+impl MockOracle {
+    /// Serve `price` for `asset`, timestamped with the current ledger time
+    pub fn set_price(env: Env, asset: FeedAsset, price: i128) {
+        let data = PriceData { price, timestamp: env.ledger().timestamp() };
+        env.storage().instance().set(&asset, &data);
+    }
+
+    /// Latest price set for `asset`, if any
+    pub fn lastprice(env: Env, asset: FeedAsset) -> Option<PriceData> {
+        env.storage().instance().get(&asset)
+    }
+}
@@ -0,0 +1,110 @@
+#![no_std]
+//! Example strategy contract that trades against the perp through
+//! cross-contract calls. It holds pUSD on behalf of an owner and walks a
+//! position through its lifecycle, doubling as living documentation of the
+//! perp's public API.
+use soroban_sdk::{
+    contract, contractclient, contractimpl, contracttype, symbol_short, Address, Env, Symbol,
+};
+use sep_41_token::TokenClient;
+
+// Storage keys
+const OWNER: Symbol = symbol_short!("OWNER");
+const PERP: Symbol = symbol_short!("PERP");
+const PUSD: Symbol = symbol_short!("PUSD");
+
+// Default leverage the strategy trades at
+const LEVERAGE: u32 = 10;
+
+/// Mirror of the perp's `OpenRequest`; field names and types must match
+#[derive(Clone)]
+#[contracttype]
+pub struct OpenRequest {
+    pub value: i128,
+    pub long: bool,
+    pub leverage: u32,
+    pub limit_price: Option<i128>,
+    pub referrer: Option<Address>,
+    pub valid_until_ledger: Option<u32>,
+}
+
+/// The subset of the perp interface this strategy relies on. Argument
+/// types must stay in step with `sorobanperps::PerpContract`.
+#[contractclient(name = "PerpClient")]
+pub trait PerpInterface {
+    fn place_trade(env: Env, trader: Address, request: OpenRequest);
+    fn close_trade(env: Env, trader: Address, recipient: Option<Address>, valid_until_ledger: Option<u32>);
+    fn calculate_position(env: Env, user: Address) -> i128;
+}
+
+#[contract]
+pub struct StrategyContract;
+
+#[contractimpl]
+impl StrategyContract {
+
+    /// Initialize the strategy with its owner, the perp and the pUSD token
+    pub fn initialize(env: Env, owner: Address, perp: Address, p_usd: Address) {
+        env.storage().instance().set(&OWNER, &owner);
+        env.storage().instance().set(&PERP, &perp);
+        env.storage().instance().set(&PUSD, &p_usd);
+    }
+
+    /// Move pUSD from the owner into the strategy
+    pub fn deposit(env: Env, amount: i128) {
+        let owner: Address = env.storage().instance().get(&OWNER).unwrap();
+        owner.require_auth();
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).transfer(&owner, &env.current_contract_address(), &amount);
+    }
+
+    /// Open a position on the perp with `value` of the strategy's pUSD
+    pub fn open(env: Env, value: i128, long: bool) {
+        let owner: Address = env.storage().instance().get(&OWNER).unwrap();
+        owner.require_auth();
+        open_position(&env, value, long);
+    }
+
+    /// Close the current position and reopen at a new size and direction
+    pub fn rebalance(env: Env, value: i128, long: bool) {
+        let owner: Address = env.storage().instance().get(&OWNER).unwrap();
+        owner.require_auth();
+        let perp: Address = env.storage().instance().get(&PERP).unwrap();
+        PerpClient::new(&env, &perp).close_trade(&env.current_contract_address(), &None, &None);
+        open_position(&env, value, long);
+    }
+
+    /// Close the position, leaving the proceeds in the strategy
+    pub fn close(env: Env) {
+        let owner: Address = env.storage().instance().get(&OWNER).unwrap();
+        owner.require_auth();
+        let perp: Address = env.storage().instance().get(&PERP).unwrap();
+        PerpClient::new(&env, &perp).close_trade(&env.current_contract_address(), &None, &None);
+    }
+
+    /// Send pUSD held by the strategy back to the owner
+    pub fn withdraw(env: Env, amount: i128) {
+        let owner: Address = env.storage().instance().get(&OWNER).unwrap();
+        owner.require_auth();
+        let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+        TokenClient::new(&env, &p_usd).transfer(&env.current_contract_address(), &owner, &amount);
+    }
+
+    /// Current value of the strategy's perp position
+    pub fn position_value(env: Env) -> i128 {
+        let perp: Address = env.storage().instance().get(&PERP).unwrap();
+        PerpClient::new(&env, &perp).calculate_position(&env.current_contract_address())
+    }
+}
+
+/// Approve the perp to pull `value` and open the trade
+fn open_position(env: &Env, value: i128, long: bool) {
+    let perp: Address = env.storage().instance().get(&PERP).unwrap();
+    let p_usd: Address = env.storage().instance().get(&PUSD).unwrap();
+    let this = env.current_contract_address();
+    TokenClient::new(env, &p_usd).approve(&this, &perp, &value, &env.ledger().sequence());
+    let request = OpenRequest { value, long, leverage: LEVERAGE, limit_price: None, referrer: None, valid_until_ledger: None };
+    PerpClient::new(env, &perp).place_trade(&this, &request);
+}
+
+mod test;
@@ -0,0 +1,71 @@
+#![cfg(test)]
+
+use super::*;
+use soroban_sdk::{testutils::{Address as _, Ledger}, IntoVal, String};
+use sep_41_token::testutils::{MockTokenClient, MockTokenWASM};
+use sorobanperps::{PerpContract, PerpContractClient, PriceBounds};
+
+// Test helper to deploy the perp, pUSD and the strategy with a funded owner
+fn setup<'a>(e: &'a Env) -> (PerpContractClient<'a>, Address, StrategyContractClient<'a>, MockTokenClient<'a>, Address) {
+    e.mock_all_auths();
+    let admin = Address::generate(e);
+    let token_id = e.register(MockTokenWASM, ());
+    let token = MockTokenClient::new(e, &token_id);
+    token.initialize(
+        &admin,
+        &7,
+        &String::from_str(e, "Name"),
+        &String::from_str(e, "Symbol"),
+    );
+
+    let perp_id = e.register(PerpContract, ());
+    let perp = PerpContractClient::new(e, &perp_id);
+    let oracle = Address::generate(e);
+    perp.initialize(&admin, &"BTC".into_val(e), &10_i128, &token_id, &oracle, &None, &7, &0, &PriceBounds { min: 1, max: i128::MAX });
+    perp.set_price(&oracle, &50000, &e.ledger().timestamp());
+
+    let strategy_id = e.register(StrategyContract, ());
+    let strategy = StrategyContractClient::new(e, &strategy_id);
+    let owner = Address::generate(e);
+    strategy.initialize(&owner, &perp_id, &token_id);
+
+    token.mint(&owner, &10000);
+    token.mint(&perp_id, &100000);
+    (perp, oracle, strategy, token, owner)
+}
+
+#[test]
+fn test_strategy_lifecycle() {
+    let env = Env::default();
+    let (perp, oracle, strategy, token, owner) = setup(&env);
+
+    strategy.deposit(&5000);
+    assert_eq!(token.balance(&strategy.address), 5000);
+
+    // Opening into an empty market pays the 1% skew fee
+    strategy.open(&1000, &true);
+    assert_eq!(token.balance(&strategy.address), 4000);
+    assert_eq!(strategy.position_value(), 990);
+
+    // A 1% move at 10x is worth 10% of the position
+    env.ledger().with_mut(|li| {
+        li.sequence_number += 1;
+        li.timestamp += 5;
+    });
+    perp.set_price(&oracle, &50500, &env.ledger().timestamp());
+    assert_eq!(strategy.position_value(), 1089);
+
+    // Flip to a short, realising the long's profit
+    strategy.rebalance(&2000, &false);
+    assert_eq!(token.balance(&strategy.address), 4000 + 1089 - 2000);
+    assert_eq!(strategy.position_value(), 1980);
+
+    strategy.close();
+    assert_eq!(strategy.position_value(), 0);
+    assert_eq!(token.balance(&strategy.address), 4000 + 1089 - 2000 + 1980);
+
+    let held = token.balance(&strategy.address);
+    strategy.withdraw(&held);
+    assert_eq!(token.balance(&strategy.address), 0);
+    assert_eq!(token.balance(&owner), 5000 + held);
+}